@@ -0,0 +1,281 @@
+// Transforms which change during the exposure, for motion blur of any object
+//
+// A motion is a list of keyframes, each a time with a scale, rotation, and translation. Between two keyframes the
+// scale and translation are interpolated linearly and the rotation by spherical linear interpolation (slerp) of unit
+// quaternions, which turns at a constant rate about a fixed axis; before the first keyframe and after the last the
+// object stays where it is. Slerp takes the shorter way round, so a rotation of more than half a turn (e.g. a spinning
+// wheel) needs a keyframe at least every half turn. Times are in the same units as the camera's exposure_length, rays
+// being cast at times from 0 to exposure_length.
+
+use crate::{my_vec3::MyVec3, my_matrix4::MyMatrix4, world_instance::Transform, bounding_box::WEBoundingBox};
+
+// Unit quaternion representing a rotation: w = cos(angle / 2), v = sin(angle / 2) * axis
+#[derive(Debug, Copy, Clone)]
+pub struct Quaternion {
+    pub w: f64,
+    pub v: MyVec3
+}
+
+impl Quaternion {
+    pub fn from_axis_angle(axis: MyVec3, angle: f64) -> Quaternion
+    {
+        Quaternion { w: f64::cos(0.5 * angle), v: (f64::sin(0.5 * angle) / axis.length()) * axis }
+    }
+
+    // Angles in radians about the x, then y, then z axis (as for a static instance)
+    pub fn from_euler(angles: MyVec3) -> Quaternion
+    {
+        Quaternion::from_axis_angle(MyVec3{x: 0.0, y: 0.0, z: 1.0}, angles.z)
+            * Quaternion::from_axis_angle(MyVec3{x: 0.0, y: 1.0, z: 0.0}, angles.y)
+            * Quaternion::from_axis_angle(MyVec3{x: 1.0, y: 0.0, z: 0.0}, angles.x)
+    }
+
+    fn dot(&self, rhs: Quaternion) -> f64
+    {
+        self.w * rhs.w + self.v.dot(rhs.v)
+    }
+
+    fn normalized(&self) -> Quaternion
+    {
+        let recip_length = 1.0 / f64::sqrt(self.dot(*self));
+
+        Quaternion { w: recip_length * self.w, v: recip_length * self.v }
+    }
+
+    // Angle (radians) of the rotation from self to rhs, the shorter way round
+    pub fn angle_to(&self, rhs: Quaternion) -> f64
+    {
+        2.0 * f64::acos(f64::min(f64::abs(self.dot(rhs)), 1.0))
+    }
+
+    // Rotation a fraction t of the way from self to rhs, at a constant rate, the shorter way round
+    pub fn slerp(&self, rhs: Quaternion, t: f64) -> Quaternion
+    {
+        // q and -q are the same rotation; choose the sign which is closer to self
+        let (rhs, cos_theta) = if self.dot(rhs) < 0.0 {(Quaternion { w: -rhs.w, v: -1.0 * rhs.v }, -self.dot(rhs))} else {(rhs, self.dot(rhs))};
+
+        // Nearly the same rotation: sin(theta) is too small to divide by, and linear interpolation is as good
+        let (a, b) = if cos_theta > 0.9995
+        {
+            (1.0 - t, t)
+        }
+        else
+        {
+            let theta = f64::acos(cos_theta);
+
+            (f64::sin((1.0 - t) * theta) / f64::sin(theta), f64::sin(t * theta) / f64::sin(theta))
+        };
+
+        Quaternion { w: a * self.w + b * rhs.w, v: a * self.v + b * rhs.v }.normalized()
+    }
+
+    pub fn to_matrix(self) -> MyMatrix4
+    {
+        let (w, x, y, z) = (self.w, self.v.x, self.v.y, self.v.z);
+
+        MyMatrix4 { m: [[1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z),       2.0 * (x * z + w * y),       0.0],
+                        [2.0 * (x * y + w * z),       1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x),       0.0],
+                        [2.0 * (x * z - w * y),       2.0 * (y * z + w * x),       1.0 - 2.0 * (x * x + y * y), 0.0],
+                        [0.0,                         0.0,                         0.0,                         1.0]] }
+    }
+}
+
+impl std::ops::Mul for Quaternion
+{
+    type Output = Self;
+
+    // Rotation by rhs followed by rotation by self
+    fn mul(self, rhs: Self) -> Self
+    {
+        Quaternion { w: self.w * rhs.w - self.v.dot(rhs.v), v: self.w * rhs.v + rhs.w * self.v + self.v.cross(rhs.v) }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Keyframe {
+    pub time:      f64,
+    pub scale:     MyVec3,
+    pub rotation:  Quaternion,
+    pub translate: MyVec3
+}
+
+pub struct AnimatedTransform {
+    keyframes: Vec<Keyframe>   // In order of time
+}
+
+// Swept bounds are found from the bounds at this many times between each pair of keyframes
+const BOUNDS_STEPS: usize = 64;
+
+impl AnimatedTransform {
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Result<AnimatedTransform, String>
+    {
+        if keyframes.is_empty()
+        {
+            return Err("At least one keyframe is needed".to_string());
+        }
+
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        for pair in keyframes.windows(2)
+        {
+            if pair[0].time == pair[1].time
+            {
+                return Err(format!("Two keyframes have the same time ({})", pair[0].time));
+            }
+        }
+
+        // A scale which changes sign between keyframes would pass through zero, flattening the object
+        let positive = |s: MyVec3| [s.x > 0.0, s.y > 0.0, s.z > 0.0];
+
+        if keyframes.iter().any(|k| k.scale.x == 0.0 || k.scale.y == 0.0 || k.scale.z == 0.0 || positive(k.scale) != positive(keyframes[0].scale))
+        {
+            return Err("Keyframe scales must not be zero, or change sign between keyframes".to_string());
+        }
+
+        Ok(AnimatedTransform { keyframes })
+    }
+
+    fn interpolate(&self, time: f64) -> (MyVec3, Quaternion, MyVec3)
+    {
+        let keyframes = &self.keyframes;
+        let next      = keyframes.partition_point(|k| k.time <= time);
+
+        if next == 0 || next == keyframes.len()
+        {
+            let k = keyframes[usize::min(next, keyframes.len() - 1)];
+
+            return (k.scale, k.rotation, k.translate);
+        }
+
+        let (k0, k1) = (keyframes[next - 1], keyframes[next]);
+        let t        = (time - k0.time) / (k1.time - k0.time);
+
+        ((1.0 - t) * k0.scale + t * k1.scale, k0.rotation.slerp(k1.rotation, t), (1.0 - t) * k0.translate + t * k1.translate)
+    }
+
+    // Scale, then rotate, then translate; the inverse is built from the parts rather than by inverting the matrix
+    pub fn at(&self, time: f64) -> Transform
+    {
+        let (scale, rotation, translate) = self.interpolate(time);
+        let rotation_matrix              = rotation.to_matrix();
+
+        let to_world  = MyMatrix4::translation(translate) * rotation_matrix * MyMatrix4::scaling(scale);
+        let to_object = MyMatrix4::scaling(MyVec3{x: 1.0 / scale.x, y: 1.0 / scale.y, z: 1.0 / scale.z})
+                      * rotation_matrix.transpose()
+                      * MyMatrix4::translation(-1.0 * translate);
+
+        Transform::from_matrices(to_world, to_object)
+    }
+
+    // Box enclosing the object's box over every time in [time0, time1]
+    // The transformed box is found at a number of times in each keyframe interval. Between those times the corners
+    // move on arcs, which may bulge out of the boxes found, by at most 1 - cos(half the angle turned) of their distance
+    // from the centre of rotation, so the box is grown by that much
+    pub fn swept_bounds(&self, object_box: WEBoundingBox, time0: f64, time1: f64) -> WEBoundingBox
+    {
+        if object_box.is_empty()
+        {
+            return object_box;
+        }
+
+        let mut times: Vec<f64> = vec![time0, time1];
+
+        for pair in self.keyframes.windows(2)
+        {
+            let (start, end) = (f64::max(pair[0].time, time0), f64::min(pair[1].time, time1));
+
+            if start < end
+            {
+                times.extend((0..=BOUNDS_STEPS).map(|i| start + (end - start) * i as f64 / BOUNDS_STEPS as f64));
+            }
+        }
+
+        times.sort_by(f64::total_cmp);
+
+        let mut bounds        = WEBoundingBox::empty();
+        let mut max_angle     = 0.0;
+        let mut max_reach     = 0.0;
+        let mut last_rotation = None;
+
+        for &time in &times
+        {
+            let (_, rotation, translate) = self.interpolate(time);
+            let transform                = self.at(time);
+
+            for corner in object_box.corners()
+            {
+                let p = transform.to_world.transform_point(corner);
+
+                bounds    = bounds.union_point(p);
+                max_reach = f64::max(max_reach, (p - translate).length());
+            }
+
+            if let Some(last_rotation) = last_rotation
+            {
+                max_angle = f64::max(max_angle, rotation.angle_to(last_rotation));
+            }
+
+            last_rotation = Some(rotation);
+        }
+
+        let padding = max_reach * (1.0 - f64::cos(0.5 * max_angle)) + 1e-9 * max_reach;
+
+        WEBoundingBox { x0: bounds.x0 - padding, x1: bounds.x1 + padding,
+                        y0: bounds.y0 - padding, y1: bounds.y1 + padding,
+                        z0: bounds.z0 - padding, z1: bounds.z1 + padding }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn spin() -> AnimatedTransform
+    {
+        let keyframe = |time: f64, angle: f64| Keyframe { time, scale: MyVec3{x: 1.0, y: 2.0, z: 1.0},
+                                                          rotation:  Quaternion::from_euler(MyVec3{x: 0.0, y: 0.0, z: angle.to_radians()}),
+                                                          translate: MyVec3{x: 3.0 * time, y: 0.0, z: 0.0} };
+
+        AnimatedTransform::new(vec![keyframe(1.0, 170.0), keyframe(0.0, 0.0), keyframe(2.0, 340.0)]).unwrap()
+    }
+
+    #[test]
+    fn rotation_is_interpolated_at_constant_rate()
+    {
+        let motion = spin();
+        let p      = MyVec3{x: 1.0, y: 0.0, z: 0.0};
+
+        for (time, angle) in [(0.5, 85.0), (1.5, 255.0), (3.0, 340.0)]
+        {
+            let expected = MyMatrix4::translation(MyVec3{x: 3.0 * f64::min(time, 2.0), y: 0.0, z: 0.0})
+                         * MyMatrix4::rotation(MyVec3{x: 0.0, y: 0.0, z: 1.0}, f64::to_radians(angle));
+
+            assert!((motion.at(time).to_world.transform_point(p) - expected.transform_point(p)).length() < 1e-9);
+            assert!((motion.at(time).to_object.transform_point(motion.at(time).to_world.transform_point(p)) - p).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn swept_bounds_contain_object_throughout()
+    {
+        let motion     = spin();
+        let object_box = WEBoundingBox { x0: -1.0, x1: 1.0, y0: -0.5, y1: 0.5, z0: -0.1, z1: 0.1 };
+        let bounds     = motion.swept_bounds(object_box, 0.2, 1.7);
+
+        for i in 0..=1000
+        {
+            let time = 0.2 + 1.5 * i as f64 / 1000.0;
+
+            for corner in object_box.corners()
+            {
+                let p = motion.at(time).to_world.transform_point(corner);
+
+                assert!(p.x >= bounds.x0 && p.x <= bounds.x1 && p.y >= bounds.y0 && p.y <= bounds.y1 && p.z >= bounds.z0 && p.z <= bounds.z1);
+            }
+        }
+
+        // The object reaches x = 3 * 1.7 + 2 at the latest time, but no further than the padding allows
+        assert!(bounds.x1 < 3.0 * 1.7 + 2.0 + 0.01);
+    }
+}
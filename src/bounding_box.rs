@@ -9,9 +9,10 @@
 // A further advantage is that intersection with axis-aligned planes is 
 // computationally (and conceptually) very simple
 
-use crate::{world_element::Intersect, ray::Ray, rayinfo::RayInfo, common::order_pair};
+use crate::{world_element::Intersect, ray::Ray, rayinfo::RayInfo, my_vec3::MyVec3, common::order_pair};
 
-struct WEBoundingBox {
+#[derive(Debug, Copy, Clone)]
+pub struct WEBoundingBox {
 
     pub x0: f64,
     pub x1: f64,
@@ -22,8 +23,83 @@ struct WEBoundingBox {
 
 }
 
-impl Intersect for WEBoundingBox {
-    fn intersect(&self, ray: &Ray, _min_scale: f64, _max_scale: f64, _cast_time: f64) -> (bool, RayInfo)
+impl WEBoundingBox
+{
+    // Box spanning the two corners (the corners need not be ordered)
+    pub fn new(a: MyVec3, b: MyVec3) -> WEBoundingBox
+    {
+        let (x0, x1) = order_pair(a.x, b.x);
+        let (y0, y1) = order_pair(a.y, b.y);
+        let (z0, z1) = order_pair(a.z, b.z);
+
+        WEBoundingBox { x0, x1, y0, y1, z0, z1 }
+    }
+
+    // A box containing nothing; the union of an empty box with any other box is the other box
+    pub fn empty() -> WEBoundingBox
+    {
+        WEBoundingBox { x0: f64::INFINITY, x1: f64::NEG_INFINITY, y0: f64::INFINITY, y1: f64::NEG_INFINITY, z0: f64::INFINITY, z1: f64::NEG_INFINITY }
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.x0 > self.x1 || self.y0 > self.y1 || self.z0 > self.z1
+    }
+
+    pub fn union(&self, rhs: &WEBoundingBox) -> WEBoundingBox
+    {
+        WEBoundingBox { x0: f64::min(self.x0, rhs.x0),
+                        x1: f64::max(self.x1, rhs.x1),
+                        y0: f64::min(self.y0, rhs.y0),
+                        y1: f64::max(self.y1, rhs.y1),
+                        z0: f64::min(self.z0, rhs.z0),
+                        z1: f64::max(self.z1, rhs.z1) }
+    }
+
+    pub fn union_point(&self, p: MyVec3) -> WEBoundingBox
+    {
+        self.union(&WEBoundingBox { x0: p.x, x1: p.x, y0: p.y, y1: p.y, z0: p.z, z1: p.z })
+    }
+
+    pub fn min_corner(&self) -> MyVec3
+    {
+        MyVec3 { x: self.x0, y: self.y0, z: self.z0 }
+    }
+
+    pub fn max_corner(&self) -> MyVec3
+    {
+        MyVec3 { x: self.x1, y: self.y1, z: self.z1 }
+    }
+
+    pub fn corners(&self) -> [MyVec3; 8]
+    {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|corner| MyVec3 { x: if corner & 1 == 0 {self.x0} else {self.x1},
+                                                       y: if corner & 2 == 0 {self.y0} else {self.y1},
+                                                       z: if corner & 4 == 0 {self.z0} else {self.z1} })
+    }
+
+    pub fn centroid(&self) -> MyVec3
+    {
+        0.5 * (self.min_corner() + self.max_corner())
+    }
+
+    // Used by the surface area heuristic: the probability of a random ray hitting a box is proportional to its surface area
+    pub fn surface_area(&self) -> f64
+    {
+        if self.is_empty()
+        {
+            return 0.0;
+        }
+
+        let dx = self.x1 - self.x0;
+        let dy = self.y1 - self.y0;
+        let dz = self.z1 - self.z0;
+
+        2.0 * (dx * dy + dy * dz + dz * dx)
+    }
+
+    // Slab test, restricted to the range of ray scaling factors [min_scale, max_scale]
+    pub fn hit(&self, ray: &Ray, min_scale: f64, max_scale: f64) -> bool
     {
         // Any non-axis aligned ray will intersect every plane
         // The intersect for each is calculated as the range of ray distances which are between two parallel planes
@@ -38,7 +114,7 @@ impl Intersect for WEBoundingBox {
             // Declare a hit since bounding boxes are only used to determine whether or not there is  
             // potentially an object within the box (thus it is a small overhead to return a hit, as
             // the ray is then tested individually against objects within the box)
-            return(true, RayInfo::default());
+            return true;
         }
 
         // x0 = p.x + k * direction.x for some k, k is the ray scaling factor
@@ -60,16 +136,35 @@ impl Intersect for WEBoundingBox {
         let (a2, b2) = order_pair(k_z0, k_z1);
 
         // Determine the upper and lower bounds on k based on the lowest and highest values in each interval
-        let lower_bound = f64::max(f64::max(a0, a1), a2);
-        let upper_bound = f64::min(f64::min(b0, b1), b2);
+        // The permitted range of the ray is also an interval, so it is included in the same way
+        let lower_bound = f64::max(f64::max(f64::max(a0, a1), a2), min_scale);
+        let upper_bound = f64::min(f64::min(f64::min(b0, b1), b2), max_scale);
 
-        if lower_bound < upper_bound
-        {
-            return(true, RayInfo::default());
-        }        
-        else
-        {
-            return(false, RayInfo::default());
-        }
+        // Flat boxes (e.g. around an axis-aligned triangle) have a single valid value of k, so equality is a hit
+        lower_bound <= upper_bound
+    }
+
+    // The ray scaling factor at which the ray leaves the box for the last time (zero if that is behind the ray's origin)
+    pub fn exit_scale(&self, ray: &Ray) -> f64
+    {
+        // Along an axis which the ray is parallel to the factors are infinite, so that axis does not limit the exit
+        let exit = |a: f64, b: f64, p: f64, d: f64| f64::max((a - p) / d, (b - p) / d);
+
+        let k = f64::min(f64::min(exit(self.x0, self.x1, ray.p.x, ray.direction.x), exit(self.y0, self.y1, ray.p.y, ray.direction.y)),
+                         exit(self.z0, self.z1, ray.p.z, ray.direction.z));
+
+        f64::max(k, 0.0)
     }
-}
\ No newline at end of file
+}
+
+impl Intersect for WEBoundingBox {
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, _cast_time: f64) -> (bool, RayInfo<'_>)
+    {
+        (self.hit(ray, min_scale, max_scale), RayInfo::default())
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> WEBoundingBox
+    {
+        *self
+    }
+}
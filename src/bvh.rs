@@ -0,0 +1,374 @@
+// Bounding volume hierarchy
+//
+// A binary tree of bounding boxes; each inner node's box encloses the boxes of its two children,
+// and each leaf holds a small number of objects. A ray which misses a node's box cannot hit anything
+// beneath it, so most of the scene is skipped without testing the objects themselves.
+//
+// The tree only stores indices, so it can be built over any list of objects which can report
+// their bounds (the objects of a WorldElement, or the triangles of a mesh).
+//
+// The split of each node is chosen using the surface area heuristic (SAH): the probability that a
+// ray which hits the parent box also hits a child box is proportional to the ratio of their surface
+// areas, so the expected cost of a split is estimated as
+//     cost = traversal_cost + (area_left * count_left + area_right * count_right) / area_parent
+// Candidate splits are evaluated by binning object centroids along each axis.
+
+use crate::{bounding_box::WEBoundingBox, ray::Ray, rayinfo::RayInfo};
+
+const NUMBER_OF_BINS: usize = 16;
+const MAX_LEAF_SIZE:  usize = 4;
+
+// Relative to the cost of intersecting a single object
+const TRAVERSAL_COST: f64 = 0.125;
+
+#[derive(Debug, Copy, Clone)]
+struct BvhNode
+{
+    bounds: WEBoundingBox,
+
+    // Inner node: index of the second child (the first child immediately follows this node), split axis
+    // Leaf node:  first index into the object index list, number of objects
+    offset: usize,
+    count:  usize,
+    axis:   usize
+}
+
+impl BvhNode
+{
+    fn is_leaf(&self) -> bool
+    {
+        self.count > 0
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Bin
+{
+    bounds: WEBoundingBox,
+    count:  usize
+}
+
+pub struct WEBvh
+{
+    nodes:   Vec<BvhNode>,
+    indices: Vec<usize>
+}
+
+impl WEBvh
+{
+    // Build the hierarchy over a list of object bounds; the BVH refers to objects by their position in this list
+    pub fn build(bounds: &[WEBoundingBox]) -> WEBvh
+    {
+        let mut bvh = WEBvh { nodes: Vec::with_capacity(2 * bounds.len()), indices: (0..bounds.len()).collect() };
+
+        if !bounds.is_empty()
+        {
+            let centroids: Vec<[f64; 3]> = bounds.iter().map(|b| { let c = b.centroid(); [c.x, c.y, c.z] }).collect();
+
+            bvh.build_recursive(bounds, &centroids, 0, bounds.len());
+        }
+
+        bvh
+    }
+
+    fn build_recursive(&mut self, bounds: &[WEBoundingBox], centroids: &[[f64; 3]], begin: usize, end: usize) -> usize
+    {
+        let node_index = self.nodes.len();
+
+        let mut node_bounds     = WEBoundingBox::empty();
+        let mut centroid_bounds = WEBoundingBox::empty();
+
+        for &i in &self.indices[begin..end]
+        {
+            node_bounds     = node_bounds.union(&bounds[i]);
+            centroid_bounds = centroid_bounds.union_point(bounds[i].centroid());
+        }
+
+        self.nodes.push(BvhNode { bounds: node_bounds, offset: begin, count: end - begin, axis: 0 });
+
+        let count = end - begin;
+
+        if count <= 1
+        {
+            return node_index;
+        }
+
+        let centroid_min = centroid_bounds.min_corner();
+        let centroid_max = centroid_bounds.max_corner();
+        let extent       = [centroid_max.x - centroid_min.x, centroid_max.y - centroid_min.y, centroid_max.z - centroid_min.z];
+        let origin       = [centroid_min.x, centroid_min.y, centroid_min.z];
+
+        // Find the cheapest split over all axes and bin boundaries
+        let mut best_cost  = f64::INFINITY;
+        let mut best_axis  = 0;
+        let mut best_split = 0;
+
+        let bin_index = |axis: usize, c: &[f64; 3]| -> usize {
+            let b = (NUMBER_OF_BINS as f64 * (c[axis] - origin[axis]) / extent[axis]) as usize;
+            usize::min(b, NUMBER_OF_BINS - 1)
+        };
+
+        for (axis, &axis_extent) in extent.iter().enumerate()
+        {
+            // All centroids in the same place along this axis, no split possible
+            if axis_extent <= 0.0
+            {
+                continue;
+            }
+
+            let mut bins = [Bin { bounds: WEBoundingBox::empty(), count: 0 }; NUMBER_OF_BINS];
+
+            for &i in &self.indices[begin..end]
+            {
+                let b = bin_index(axis, &centroids[i]);
+
+                bins[b].bounds = bins[b].bounds.union(&bounds[i]);
+                bins[b].count += 1;
+            }
+
+            // Sweep from the right to accumulate the area and count on the right of each split, then from the left to evaluate the cost
+            let mut right_area  = [0.0; NUMBER_OF_BINS];
+            let mut right_count = [0; NUMBER_OF_BINS];
+            let mut accumulated = WEBoundingBox::empty();
+            let mut number      = 0;
+
+            for b in (1..NUMBER_OF_BINS).rev()
+            {
+                accumulated    = accumulated.union(&bins[b].bounds);
+                number        += bins[b].count;
+                right_area[b]  = accumulated.surface_area();
+                right_count[b] = number;
+            }
+
+            accumulated = WEBoundingBox::empty();
+            number      = 0;
+
+            for b in 0..NUMBER_OF_BINS - 1
+            {
+                accumulated = accumulated.union(&bins[b].bounds);
+                number     += bins[b].count;
+
+                if number == 0 || right_count[b + 1] == 0
+                {
+                    continue;
+                }
+
+                let cost = accumulated.surface_area() * number as f64 + right_area[b + 1] * right_count[b + 1] as f64;
+
+                if cost < best_cost
+                {
+                    best_cost  = cost;
+                    best_axis  = axis;
+                    best_split = b + 1;
+                }
+            }
+        }
+
+        let parent_area = node_bounds.surface_area();
+        let split_cost  = if parent_area > 0.0 { TRAVERSAL_COST + best_cost / parent_area } else { f64::INFINITY };
+
+        // Small nodes become leaves if no split was found, or splitting is more expensive than testing every object
+        if count <= MAX_LEAF_SIZE && split_cost >= count as f64
+        {
+            return node_index;
+        }
+
+        // Partition the indices into the two children
+        let mid = if best_cost == f64::INFINITY
+        {
+            // Split could not be evaluated (e.g. degenerate boxes); split in the middle of the list instead
+            begin + count / 2
+        }
+        else
+        {
+            let slice = &mut self.indices[begin..end];
+            let mut left = 0;
+
+            for j in 0..slice.len()
+            {
+                if bin_index(best_axis, &centroids[slice[j]]) < best_split
+                {
+                    slice.swap(left, j);
+                    left += 1;
+                }
+            }
+
+            begin + left
+        };
+
+        self.nodes[node_index].count = 0;
+        self.nodes[node_index].axis  = best_axis;
+
+        self.build_recursive(bounds, centroids, begin, mid);
+
+        let second_child = self.build_recursive(bounds, centroids, mid, end);
+
+        self.nodes[node_index].offset = second_child;
+
+        node_index
+    }
+
+    // Find the closest intersection; intersect_object is called with the index of each candidate object and the current range of ray scaling factors
+    pub fn intersect<'a, F>(&self, ray: &Ray, min_scale: f64, max_scale: f64, mut intersect_object: F) -> (bool, RayInfo<'a>)
+        where F: FnMut(usize, f64, f64) -> (bool, RayInfo<'a>)
+    {
+        let mut ray_scale_closest = max_scale;
+        let mut f_any_intersect   = false;
+        let mut info              = RayInfo::default();
+
+        if self.nodes.is_empty()
+        {
+            return (false, info);
+        }
+
+        let direction_negative = [ray.direction.x < 0.0, ray.direction.y < 0.0, ray.direction.z < 0.0];
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop()
+        {
+            let node = &self.nodes[node_index];
+
+            if !node.bounds.hit(ray, min_scale, ray_scale_closest)
+            {
+                continue;
+            }
+
+            if node.is_leaf()
+            {
+                for &object_index in &self.indices[node.offset..node.offset + node.count]
+                {
+                    let (f_intersect, ray_info) = intersect_object(object_index, min_scale, ray_scale_closest);
+
+                    if f_intersect && ray_info.ds < ray_scale_closest
+                    {
+                        f_any_intersect   = true;
+                        ray_scale_closest = ray_info.ds;
+                        info              = ray_info;
+                    }
+                }
+            }
+            else
+            {
+                // Visit the nearer child first so that later boxes can be culled by the closest intersection found so far
+                if direction_negative[node.axis]
+                {
+                    stack.push(node_index + 1);
+                    stack.push(node.offset);
+                }
+                else
+                {
+                    stack.push(node.offset);
+                    stack.push(node_index + 1);
+                }
+            }
+        }
+
+        (f_any_intersect, info)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::{my_vec3::MyVec3, ray::Ray, world_element::WorldElement, material::{Diffuse, SharedMaterial}, common::RandomStream};
+    use std::{ptr, sync::Arc};
+    use rand::Rng;
+
+    // Each object has a material of its own, so that the object hit can be told from the material
+    fn material() -> SharedMaterial
+    {
+        Arc::new(Diffuse { gain: MyVec3 { x: 0.5, y: 0.5, z: 0.5 }, texture: None })
+    }
+
+    fn random_point(rng: &mut RandomStream, size: f64) -> MyVec3
+    {
+        MyVec3 { x: size * (rng.gen::<f64>() - 0.5), y: size * (rng.gen::<f64>() - 0.5), z: size * (rng.gen::<f64>() - 0.5) }
+    }
+
+    // The closest intersection found through the hierarchy is the one found by testing every object
+    #[test]
+    fn matches_linear_scan()
+    {
+        let mut rng = RandomStream::new(0, 0, 0);
+        let mut world_element = WorldElement::new();
+
+        for n in 0..300
+        {
+            let centre = random_point(&mut rng, 20.0);
+            let size   = 0.1 + rng.gen::<f64>();
+
+            match n % 3
+            {
+                0 => world_element.add_sphere(centre.x, centre.y, centre.z, size, material()),
+                1 => world_element.add_moving_sphere(centre.x, centre.y, centre.z, size, material(), 3.0 * rng.gen::<f64>(), random_point(&mut rng, 1.0)),
+                _ => world_element.add_triangle(centre + random_point(&mut rng, 2.0 * size), centre + random_point(&mut rng, 2.0 * size), centre + random_point(&mut rng, 2.0 * size), material()),
+            }
+        }
+
+        world_element.build_bvh(0.0, 1.0);
+
+        let mut hits = 0;
+
+        for _ in 0..10000
+        {
+            let ray             = Ray { p: random_point(&mut rng, 30.0), direction: random_point(&mut rng, 2.0), cast_time: rng.gen::<f64>() };
+            let (hit, bvh_info) = world_element.intersect_all(&ray, 0.001, f64::INFINITY, ray.cast_time);
+
+            let mut closest = None;
+
+            for object in &world_element.objects
+            {
+                let (f_intersect, ray_info) = object.intersect(&ray, 0.001, f64::INFINITY, ray.cast_time);
+
+                if f_intersect && closest.as_ref().is_none_or(|(ds, _)| ray_info.ds < *ds)
+                {
+                    closest = Some((ray_info.ds, ray_info.material));
+                }
+            }
+
+            assert_eq!(hit, closest.is_some());
+
+            if let Some((ds, material)) = closest
+            {
+                assert_eq!(bvh_info.ds, ds);
+                assert!(ptr::addr_eq(bvh_info.material, material));
+                hits += 1;
+            }
+        }
+
+        // Enough of the rays hit something for the comparison to mean something
+        assert!(hits > 1000, "{} hits", hits);
+    }
+
+    // A sphere moving during the exposure is found at either end of its path, and not where it is not
+    #[test]
+    fn moving_sphere_hit_throughout_exposure()
+    {
+        let mut world_element = WorldElement::new();
+
+        world_element.add_moving_sphere(0.0, 0.0, 0.0, 0.5, material(), 10.0, MyVec3 { x: 1.0, y: 0.0, z: 0.0 });
+
+        for n in 0..20
+        {
+            world_element.add_sphere(n as f64 - 10.0, 5.0, 0.0, 0.4, material());
+        }
+
+        world_element.build_bvh(0.0, 1.0);
+
+        let down = |x: f64, cast_time: f64| Ray { p: MyVec3 { x, y: 2.0, z: 0.0 }, direction: MyVec3 { x: 0.0, y: -1.0, z: 0.0 }, cast_time };
+
+        for (x, cast_time, expected) in [(0.0, 0.0, true), (10.0, 1.0, true), (5.0, 0.5, true), (0.0, 1.0, false), (10.0, 0.0, false)]
+        {
+            let (hit, ray_info) = world_element.intersect_all(&down(x, cast_time), 0.001, f64::INFINITY, cast_time);
+
+            assert_eq!(hit, expected, "x {} time {}", x, cast_time);
+
+            if expected
+            {
+                assert!((ray_info.ds - 1.5).abs() < 1e-9);
+            }
+        }
+    }
+}
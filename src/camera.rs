@@ -1,5 +1,6 @@
-use crate::{common::uniform_within_unit_circle, my_vec3::{MyVec3, vec3_normalize}, ray::Ray};
+use crate::{common::unit_disc_from_square, my_vec3::{MyVec3, vec3_normalize}, ray::Ray};
 
+#[allow(dead_code)]
 #[derive(Debug, Copy, Clone)]
 pub struct Viewport
 {
@@ -17,6 +18,8 @@ pub struct Viewport
 
 impl Viewport
 {
+    // The viewport is fully described by its position, size and orientation, all of which the camera chooses
+    #[allow(clippy::too_many_arguments)]
     pub fn new(distance: f64,
                height: f64,
                width: f64,
@@ -32,8 +35,8 @@ impl Viewport
          *    +ve horizonatal is right (as seen by the camera)
          *    +ve vertical    is up    (as seen by the camera)
          */
-        let horizontal_offset = -1.0 * (width as f64 / 2.0) * horizontal_vector;
-        let vertical_offset   = (height as f64 / 2.0) * vertical_vector;
+        let horizontal_offset = -(width / 2.0) * horizontal_vector;
+        let vertical_offset   = (height / 2.0) * vertical_vector;
 
         // This is the upper left corner of the viewport
         let reference_corner  = camera_location + distance * camera_direction + horizontal_offset + vertical_offset;
@@ -48,6 +51,7 @@ impl Viewport
     }
 }
 
+#[allow(dead_code)]
 pub struct Camera
 {
     pub location:  MyVec3,
@@ -64,6 +68,8 @@ pub struct Camera
 
 impl Camera
 {
+    // One argument per camera setting of the scene file, most of them optional
+    #[allow(clippy::too_many_arguments)]
     pub fn new(location: MyVec3,
                camera_direction: Option<MyVec3>,
                camera_target: Option<MyVec3>,
@@ -90,11 +96,7 @@ impl Camera
                                }
         );
 
-        let lens_radius = match aperture
-        {
-            None           => { None }
-            Some(aperture) => { Some(aperture / 2.0) }
-        };
+        let lens_radius = aperture.map(|aperture| aperture / 2.0);
         
 
         // Calculate the horizontal and vertical orientation of the viewport as unit vectors; used to calculate ray target locations on the viewport
@@ -116,7 +118,9 @@ impl Camera
     }
 
 
-    pub fn generate_ray(&self, viewport_coord: MyVec3, cast_time: f64) -> Ray
+    // lens_sample is a point in the unit square, mapped to a point on the lens
+    #[allow(clippy::needless_return)]
+    pub fn generate_ray(&self, viewport_coord: MyVec3, cast_time: f64, lens_sample: (f64, f64)) -> Ray
     {
         match self.lens_radius
         {
@@ -127,7 +131,7 @@ impl Camera
             {
                 // Simple lens
                 let lens_centre     = self.location;
-                let lens_offset     = lens_radius * uniform_within_unit_circle();
+                let lens_offset     = lens_radius * unit_disc_from_square(lens_sample);
                 let lens_ray_origin = lens_centre + lens_offset.x * self.viewport.horizontal_vector + lens_offset * self.viewport.vertical_vector;
 
                 return Ray { p: lens_ray_origin, direction: viewport_coord - lens_ray_origin, cast_time };
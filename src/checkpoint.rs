@@ -0,0 +1,252 @@
+// Checkpoint files, so that a long render can be resumed after it is stopped (or the machine restarts), or a finished
+// render extended to more samples per pixel
+//
+// A checkpoint holds the film (the weighted sum of the samples of each pixel, and their count and statistics) and what
+// the film was rendered from: the resolution, a hash of the scene and the settings which change the image, the seed,
+// and the sampler. The samplers have no state of their own between samples, their numbers depend only on (seed,
+// pixel, sample index, dimension), so the seed and the sample count of each pixel are all that is needed to carry on
+// with exactly the numbers an uninterrupted render would have used.
+//
+// Format (little-endian):
+//     "RTCKPT01"                                   magic and version
+//     width, height                                u32
+//     scene hash, seed                             u64
+//     sampler                                      u32
+//     per pixel, rows from the top of the image:
+//         colour sum (r, g, b), weight sum         f64
+//         sample count                             u32
+//         luminance mean, luminance m2             f64
+
+use std::{fs, path::Path};
+
+use crate::{film::{Film, FilmPixel}, my_vec3::MyVec3, sampler::SamplerType};
+
+const MAGIC: &[u8; 8] = b"RTCKPT01";
+
+const SAMPLERS: [SamplerType; 4] = [SamplerType::Independent, SamplerType::Stratified, SamplerType::Halton, SamplerType::Sobol];
+
+// What a film was rendered from; a checkpoint can only be resumed by a render with the same header
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CheckpointHeader
+{
+    pub width:        u32,
+    pub height:       u32,
+    pub scene_hash:   u64,
+    pub seed:         u64,
+    pub sampler_type: SamplerType
+}
+
+// The file is written under a temporary name and then renamed, so an interruption while writing leaves the previous
+// checkpoint intact
+pub fn save_checkpoint(path: &Path, header: &CheckpointHeader, film: &Film) -> Result<(), String>
+{
+    let mut data = Vec::with_capacity(36 + film.pixels().len() * 52);
+
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&header.width.to_le_bytes());
+    data.extend_from_slice(&header.height.to_le_bytes());
+    data.extend_from_slice(&header.scene_hash.to_le_bytes());
+    data.extend_from_slice(&header.seed.to_le_bytes());
+    data.extend_from_slice(&(SAMPLERS.iter().position(|&s| s == header.sampler_type).unwrap() as u32).to_le_bytes());
+
+    for pixel in film.pixels()
+    {
+        for value in [pixel.colour_sum.x, pixel.colour_sum.y, pixel.colour_sum.z, pixel.weight_sum]
+        {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+
+        data.extend_from_slice(&pixel.statistics.count.to_le_bytes());
+        data.extend_from_slice(&pixel.statistics.luminance_mean.to_le_bytes());
+        data.extend_from_slice(&pixel.statistics.luminance_m2.to_le_bytes());
+    }
+
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+
+    fs::write(&temporary_path, &data).and_then(|_| fs::rename(&temporary_path, path))
+                                     .map_err(|e| format!("{}: Unable to write checkpoint: {}", path.display(), e))
+}
+
+// Load the film from a checkpoint, which must have been made by a render with the given header
+pub fn load_checkpoint(path: &Path, expected: &CheckpointHeader) -> Result<Film, String>
+{
+    let error = |message: String| format!("{}: {}", path.display(), message);
+
+    let data       = fs::read(path).map_err(|e| error(format!("Unable to read checkpoint: {}", e)))?;
+    let mut reader = Reader { data: &data, position: 0 };
+
+    if reader.bytes(MAGIC.len()).map_err(error)? != MAGIC
+    {
+        return Err(error("Not a checkpoint file (or one written by an incompatible version)".to_string()));
+    }
+
+    let width        = reader.u32().map_err(error)?;
+    let height       = reader.u32().map_err(error)?;
+    let scene_hash   = reader.u64().map_err(error)?;
+    let seed         = reader.u64().map_err(error)?;
+    let sampler_type = *SAMPLERS.get(reader.u32().map_err(error)? as usize).ok_or_else(|| error("Unknown sampler".to_string()))?;
+
+    if (width, height) != (expected.width, expected.height)
+    {
+        return Err(error(format!("Checkpoint is {}x{} but the image being rendered is {}x{}", width, height, expected.width, expected.height)));
+    }
+
+    if scene_hash != expected.scene_hash
+    {
+        return Err(error("Checkpoint was made from a different scene (or OBJ files, sky, bounce depth, light sampling, filter, or adaptive sampling settings)".to_string()));
+    }
+
+    if seed != expected.seed
+    {
+        return Err(error(format!("Checkpoint was rendered with seed {} (use --seed {})", seed, seed)));
+    }
+
+    if sampler_type != expected.sampler_type
+    {
+        return Err(error(format!("Checkpoint was rendered with the {:?} sampler", sampler_type)));
+    }
+
+    let mut film = Film::new(width, height);
+
+    for pixel in film.pixels_mut()
+    {
+        let mut values = [0.0; 4];
+
+        for value in values.iter_mut()
+        {
+            *value = reader.f64().map_err(error)?;
+        }
+
+        *pixel = FilmPixel { colour_sum: MyVec3{x: values[0], y: values[1], z: values[2]}, weight_sum: values[3], ..FilmPixel::default() };
+
+        pixel.statistics.count          = reader.u32().map_err(error)?;
+        pixel.statistics.luminance_mean = reader.f64().map_err(error)?;
+        pixel.statistics.luminance_m2   = reader.f64().map_err(error)?;
+    }
+
+    if reader.position != data.len()
+    {
+        return Err(error("Unexpected data at the end of the checkpoint".to_string()));
+    }
+
+    Ok(film)
+}
+
+struct Reader<'a>
+{
+    data:     &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a>
+{
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String>
+    {
+        let bytes = self.data.get(self.position..self.position + length).ok_or_else(|| "Checkpoint is truncated".to_string())?;
+
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String>
+    {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String>
+    {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, String>
+    {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::common::TemporaryPath;
+
+    fn header() -> CheckpointHeader
+    {
+        CheckpointHeader { width: 3, height: 2, scene_hash: 0x1234_5678_9abc_def0, seed: 7, sampler_type: SamplerType::Halton }
+    }
+
+    // A film with different values in every pixel
+    fn film() -> Film
+    {
+        let mut film = Film::new(3, 2);
+
+        for (n, pixel) in film.pixels_mut().iter_mut().enumerate()
+        {
+            let n = n as f64;
+
+            pixel.colour_sum                = MyVec3 { x: n, y: 2.0 * n + 0.5, z: -n };
+            pixel.weight_sum                = 0.25 * n + 1.0;
+            pixel.statistics.count          = 10 + n as u32;
+            pixel.statistics.luminance_mean = n / 3.0;
+            pixel.statistics.luminance_m2   = n * n;
+        }
+
+        film
+    }
+
+    #[test]
+    fn round_trip()
+    {
+        let file = TemporaryPath::new("checkpoint_round_trip");
+
+        save_checkpoint(&file.0, &header(), &film()).unwrap();
+
+        let loaded = load_checkpoint(&file.0, &header()).unwrap();
+
+        assert_eq!((loaded.width, loaded.height), (3, 2));
+
+        for (a, b) in loaded.pixels().iter().zip(film().pixels())
+        {
+            assert_eq!((a.colour_sum.x, a.colour_sum.y, a.colour_sum.z, a.weight_sum), (b.colour_sum.x, b.colour_sum.y, b.colour_sum.z, b.weight_sum));
+            assert_eq!((a.statistics.count, a.statistics.luminance_mean, a.statistics.luminance_m2), (b.statistics.count, b.statistics.luminance_mean, b.statistics.luminance_m2));
+        }
+    }
+
+    // A render which differs from the checkpoint in any of the header's values is refused, saying how
+    #[test]
+    fn different_render_rejected()
+    {
+        let file = TemporaryPath::new("checkpoint_different_render_rejected");
+
+        save_checkpoint(&file.0, &header(), &film()).unwrap();
+
+        for (expected, message) in [(CheckpointHeader { width: 4, ..header() },                         "Checkpoint is 3x2 but the image being rendered is 4x2"),
+                                    (CheckpointHeader { height: 3, ..header() },                        "Checkpoint is 3x2 but the image being rendered is 3x3"),
+                                    (CheckpointHeader { scene_hash: 1, ..header() },                    "Checkpoint was made from a different scene"),
+                                    (CheckpointHeader { seed: 8, ..header() },                          "Checkpoint was rendered with seed 7 (use --seed 7)"),
+                                    (CheckpointHeader { sampler_type: SamplerType::Sobol, ..header() }, "Checkpoint was rendered with the Halton sampler")]
+        {
+            let error = load_checkpoint(&file.0, &expected).err().unwrap();
+
+            assert!(error.starts_with(&format!("{}: {}", file.0.display(), message)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn damaged_file_rejected()
+    {
+        let file = TemporaryPath::new("checkpoint_damaged_file_rejected");
+
+        save_checkpoint(&file.0, &header(), &film()).unwrap();
+
+        let data = fs::read(&file.0).unwrap();
+
+        fs::write(&file.0, &data[..data.len() - 1]).unwrap();
+        assert_eq!(load_checkpoint(&file.0, &header()).err().unwrap(), format!("{}: Checkpoint is truncated", file.0.display()));
+
+        fs::write(&file.0, [b"RTCKPT00".as_slice(), &data[8..]].concat()).unwrap();
+        assert_eq!(load_checkpoint(&file.0, &header()).err().unwrap(), format!("{}: Not a checkpoint file (or one written by an incompatible version)", file.0.display()));
+    }
+}
@@ -1,51 +1,122 @@
 
-use rand::Rng;
+use rand::{Rng, RngCore};
+
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
 
 use crate::my_vec3::MyVec3;
 
-// Uniform in the interval [0, 1)
-pub fn uniform_random() -> f64
+// Deterministic stream of pseudo-random numbers (SplitMix64)
+// Every use of randomness has its own stream derived from the user's seed, e.g. one per (pixel, sample) while
+// rendering, so the image does not depend on the order in which the work happens to be done by the threads
+#[derive(Debug, Clone)]
+pub struct RandomStream
 {
-    rand::thread_rng().gen::<f64>()
+    state: u64
 }
 
-// Uniform in the interval [min, max)
-pub fn random_in_interval(min: f64, max: f64) -> f64
+// Stream used for the random choices made while building the scene (as opposed to while rendering it)
+pub const SCENE_STREAM: u64 = u64::MAX;
+
+impl RandomStream
 {
-    min + (max-min) * uniform_random()
+    // Independent stream number index of the stream family
+    pub fn new(seed: u64, family: u64, index: u64) -> RandomStream
+    {
+        let state = mix64(mix64(mix64(seed) ^ family.wrapping_mul(0xD1B5_4A32_D192_ED03)) ^ index.wrapping_mul(0x8CB9_2BA7_2F3D_8DD7));
+
+        RandomStream { state }
+    }
 }
 
-// Uniform within a circle (excluding on the circumference)
-pub fn uniform_within_unit_circle() -> MyVec3
+// FNV-1a, used to hash the scene files and settings for checkpoints and to derive random streams from names; unlike
+// std's hasher its values are fixed, so checkpoints remain valid across builds
+pub const HASH_START: u64 = 0xcbf29ce484222325;
+
+pub fn hash_bytes(hash: u64, bytes: &[u8]) -> u64
 {
-    let mut rng = rand::thread_rng();
+    bytes.iter().fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
 
-    loop 
+impl RngCore for RandomStream
+{
+    fn next_u64(&mut self) -> u64
     {
-        let p = MyVec3 {x: 2.0 * rng.gen::<f64>() - 1.0, y: 2.0 * rng.gen::<f64>() - 1.0, z: 0.0};
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
 
-        if p.squared_length() < 1.0
+        mix64(self.state)
+    }
+
+    fn next_u32(&mut self) -> u32
+    {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8])
+    {
+        for chunk in dest.chunks_mut(8)
         {
-            return p;
+            let bytes = self.next_u64().to_le_bytes();
+
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
         }
     }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error>
+    {
+        self.fill_bytes(dest);
+
+        Ok(())
+    }
 }
 
-pub fn random_point_in_unit_sphere() -> MyVec3
+// SplitMix64 output function; a bijective mix of all the bits of x
+pub fn mix64(x: u64) -> u64
 {
-    let mut rng = rand::thread_rng();
+    let mut z = x;
 
-    loop 
-    {
-        // Random point in the cube [-1,-1,-1] -> [+1, +1, +1]
-        let p = MyVec3{x: 2.0 * rng.gen::<f64>() - 1.0, y: 2.0 * rng.gen::<f64>() - 1.0, z: 2.0 * rng.gen::<f64>() - 1.0};
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
 
-        // Continue choosing random points in the square until the chosen point also lies in a sphere of radius 1 centred at [0, 0, 0]
-        if p.squared_length() < 1.0
-        {
-            return p;
-        }
+    z ^ (z >> 31)
+}
+
+// Uniform in the interval [0, 1)
+pub fn uniform_random(rng: &mut impl Rng) -> f64
+{
+    rng.gen::<f64>()
+}
+
+// Uniform in the interval [min, max)
+pub fn random_in_interval(rng: &mut impl Rng, min: f64, max: f64) -> f64
+{
+    min + (max-min) * uniform_random(rng)
+}
+
+// Point within the unit circle (z = 0) from a point in the unit square
+// Concentric mapping (Shirley and Chiu 1997): squares map to rings, so well spread points in the square stay well spread
+pub fn unit_disc_from_square(u: (f64, f64)) -> MyVec3
+{
+    let a = 2.0 * u.0 - 1.0;
+    let b = 2.0 * u.1 - 1.0;
+
+    if a == 0.0 && b == 0.0
+    {
+        return MyVec3{x: 0.0, y: 0.0, z: 0.0};
     }
+
+    let (r, theta) = if f64::abs(a) > f64::abs(b) {(a, FRAC_PI_4 * (b / a))} else {(b, FRAC_PI_2 - FRAC_PI_4 * (a / b))};
+
+    MyVec3{x: r * f64::cos(theta), y: r * f64::sin(theta), z: 0.0}
+}
+
+// Point on the surface of the unit sphere (uniformly distributed) from a point in the unit square
+pub fn unit_sphere_surface_from_square(u: (f64, f64)) -> MyVec3
+{
+    let z   = 1.0 - 2.0 * u.0;
+    let r   = f64::sqrt(f64::max(0.0, 1.0 - z * z));
+    let phi = 2.0 * PI * u.1;
+
+    MyVec3{x: r * f64::cos(phi), y: r * f64::sin(phi), z}
 }
 
 
@@ -53,3 +124,25 @@ pub fn order_pair(x: f64, y: f64) -> (f64, f64)
 {
     if x < y { (x, y) } else { (y, x) }
 }
+
+// A file or directory for a test, in the system's temporary directory, removed when dropped (even if the test fails)
+#[cfg(test)]
+pub struct TemporaryPath(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TemporaryPath
+{
+    pub fn new(name: &str) -> TemporaryPath
+    {
+        TemporaryPath(std::env::temp_dir().join(format!("ray_tracer_{}_{}", std::process::id(), name)))
+    }
+}
+
+#[cfg(test)]
+impl Drop for TemporaryPath
+{
+    fn drop(&mut self)
+    {
+        let _ = if self.0.is_dir() {std::fs::remove_dir_all(&self.0)} else {std::fs::remove_file(&self.0)};
+    }
+}
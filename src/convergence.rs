@@ -0,0 +1,57 @@
+// Convergence benchmark for the samplers
+// A reference image is rendered with many samples per pixel, then each sampler renders the same scene at doubling
+// sample counts and the root mean square error of each render against the reference is reported. Halving the error
+// takes four times the samples with independent random numbers; a good low-discrepancy sampler does better
+
+use crate::{renderer::{Renderer, render}, sampler::SamplerType};
+
+const SAMPLERS: [(SamplerType, &str); 4] = [(SamplerType::Independent, "independent"),
+                                            (SamplerType::Stratified,  "stratified"),
+                                            (SamplerType::Halton,      "halton"),
+                                            (SamplerType::Sobol,       "sobol")];
+
+// Every render is compared to the reference over each channel of each pixel; values are clamped to [0, 1] first so
+// that a few very bright pixels (e.g. light sources) do not dominate the error
+fn rmse(image: &[f32], reference: &[f32]) -> f64
+{
+    let sum: f64 = image.iter()
+                        .zip(reference)
+                        .map(|(&a, &b)| (f32::clamp(a, 0.0, 1.0) - f32::clamp(b, 0.0, 1.0)) as f64)
+                        .map(|d| d * d)
+                        .sum();
+
+    f64::sqrt(sum / image.len() as f64)
+}
+
+// Prints a table of RMSE against samples per pixel (1, 2, 4, ... up to reference_samples / 16)
+pub fn convergence_benchmark(renderer: &mut Renderer, number_of_threads: u32, reference_samples: u32, seed: u64)
+{
+    // The reference uses a different seed to the renders being measured so that its error is not correlated with theirs
+    eprintln!("Rendering reference image ({} samples per pixel)", reference_samples);
+
+    renderer.set_sampling(reference_samples, SamplerType::Sobol, seed.wrapping_add(1));
+
+    let reference = render(renderer, number_of_threads).framebuffer();
+
+    let max_samples = u32::max(1, reference_samples / 16);
+
+    println!("{:>8} {}", "samples", SAMPLERS.iter().map(|(_, name)| format!("{:>12}", name)).collect::<Vec<_>>().join(" "));
+
+    let mut samples = 1;
+
+    while samples <= max_samples
+    {
+        let errors: Vec<String> = SAMPLERS.iter()
+                                          .map(|&(sampler_type, _)|
+                                          {
+                                              renderer.set_sampling(samples, sampler_type, seed);
+
+                                              format!("{:>12.6}", rmse(&render(renderer, number_of_threads).framebuffer(), &reference))
+                                          })
+                                          .collect();
+
+        println!("{:>8} {}", samples, errors.join(" "));
+
+        samples *= 2;
+    }
+}
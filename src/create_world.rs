@@ -1,55 +1,104 @@
-use crate::{world_element::WorldElement, material::{Material, self}, common::{uniform_random, random_in_interval}, my_vec3::{MyVec3, random_vec3, random_in_interval_vec3}, scatter::ScatteringType};
+use std::sync::Arc;
 
-pub fn create_world() -> WorldElement
+use rand::Rng;
+
+use crate::{world_element::WorldElement, material::{self, SharedMaterial, Diffuse, Metallic, Emissive, Principled}, common::{uniform_random, random_in_interval}, my_vec3::{MyVec3, random_vec3, random_in_interval_vec3}, microfacet};
+
+#[allow(clippy::needless_return)]
+pub fn create_world(rng: &mut impl Rng) -> WorldElement
 {
-    let mut world_element = WorldElement{objects: vec![]};
+    let mut world_element = WorldElement::new();
+
+    // One glass material shared by all the glass spheres
+    let glass: SharedMaterial = Arc::new(material::GLASS);
 
-    world_element.add_sphere( 0.0, -1000.0, 0.0, 1000.0, material::NEUTRAL_GREY);
+    world_element.add_sphere( 0.0, -1000.0, 0.0, 1000.0, Arc::new(material::NEUTRAL_GREY));
 
     // Small spheres, scattered about the ground
     for m in -11..=11
     {
         for n in -11..=11
         {
-            let material_chooser = uniform_random();
-            let sphere_centre    = MyVec3 {x: m as f64 + 0.9 * uniform_random(), y: 0.2, z: n as f64 + 0.9 * uniform_random()};
+            let material_chooser = uniform_random(rng);
+            let sphere_centre    = MyVec3 {x: m as f64 + 0.9 * uniform_random(rng), y: 0.2, z: n as f64 + 0.9 * uniform_random(rng)};
 
             if (sphere_centre - MyVec3{x:4.0, y:0.2, z:0.0}).length() > 0.9
             {
                 if material_chooser < 0.8
                 {
                     // Choose a diffuse material
-                    let gain                    = random_vec3() * random_vec3();
-                    let random_diffuse_material = Material{surface: ScatteringType::DiffuseScattering, gain, metal_fuzz: None, index_of_refraction: None};
+                    let gain                    = random_vec3(rng) * random_vec3(rng);
+                    let random_diffuse_material = Arc::new(Diffuse{gain, texture: None});
 
                     world_element.add_sphere(sphere_centre.x, sphere_centre.y, sphere_centre.z, 0.2, random_diffuse_material);
                 }
                 else if material_chooser < 0.95
                 {
                     // Choose a metallic material
-                    let gain = random_in_interval_vec3(0.5, 1.0);
-                    let fuzz = random_in_interval     (0.0, 0.5);
+                    let gain = random_in_interval_vec3(rng, 0.5, 1.0);
+                    let fuzz = random_in_interval     (rng, 0.0, 0.5);
 
-                    let random_metallic_material = Material{surface: ScatteringType::MetallicScattering, gain, metal_fuzz: Some(fuzz), index_of_refraction: None};
+                    let random_metallic_material = Arc::new(Metallic{gain, texture: None, roughness: microfacet::roughness_from_fuzz(fuzz), conductor: None});
                     world_element.add_sphere(sphere_centre.x, sphere_centre.y, sphere_centre.z, 0.2, random_metallic_material);
                 }
                 else
                 {
                     // Refractive material (glass)
-                    world_element.add_sphere(sphere_centre.x, sphere_centre.y, sphere_centre.z, 0.2, material::GLASS);
+                    world_element.add_sphere(sphere_centre.x, sphere_centre.y, sphere_centre.z, 0.2, Arc::clone(&glass));
                 }
             }
         }
     }
 
     // Large spheres, centered
-    let diffuse_material_large  = Material{surface: ScatteringType::DiffuseScattering,  gain: MyVec3{x:0.4, y: 0.2, z: 0.1}, metal_fuzz: None,      index_of_refraction: None};
-    let metallic_material_large = Material{surface: ScatteringType::MetallicScattering, gain: MyVec3{x:0.7, y: 0.6, z: 0.5}, metal_fuzz: Some(0.0), index_of_refraction: None};
+    let diffuse_material_large  = Arc::new(Diffuse {gain: MyVec3{x:0.4, y: 0.2, z: 0.1}, texture: None});
+    let metallic_material_large = Arc::new(Metallic{gain: MyVec3{x:0.7, y: 0.6, z: 0.5}, texture: None, roughness: 0.0, conductor: None});
 
-    world_element.add_sphere( 0.0, 1.0, 0.0, 1.0, material::GLASS);
+    world_element.add_sphere( 0.0, 1.0, 0.0, 1.0, glass);
     world_element.add_sphere(-4.0, 1.0, 0.0, 1.0, diffuse_material_large);
     //world_element.add_sphere( 4.0, 1.0, 0.0, 1.0, metallic_material_large);
     world_element.add_moving_sphere( 4.0, 1.0, 0.0, 1.0, metallic_material_large, 0.25, MyVec3{x: 1.0, y: 0.0, z: 0.0});
 
     return world_element;
-}
\ No newline at end of file
+}
+
+// Test chart for the principled material: a row of spheres for each parameter, swept from 0 on the left to 1 on the
+// right, from the top: roughness, metallic, specular, transmission, clearcoat, sheen and anisotropy
+pub fn create_material_sweep() -> WorldElement
+{
+    let mut world_element = WorldElement::new();
+
+    world_element.add_sphere(0.0, -1000.0, 0.0, 1000.0, Arc::new(Diffuse{gain: MyVec3{x: 0.5, y: 0.5, z: 0.5}, texture: None}));
+    world_element.add_sphere(6.0, 12.0, 10.0, 1.5, Arc::new(Emissive{colour: MyVec3{x: 1.0, y: 0.95, z: 0.9}, strength: 60.0}));
+
+    let colour = |x, y, z| MyVec3{x, y, z};
+
+    // Each row's material, and the parameter it sweeps
+    type SetParameter = fn(&mut Principled, f64);
+
+    let rows: [(Principled, SetParameter); 7] = [
+        (Principled{base_colour: colour(0.8, 0.2, 0.2),                                         ..Default::default()}, |m, v| m.roughness    = v),
+        (Principled{base_colour: colour(0.95, 0.65, 0.3), roughness: 0.3,                       ..Default::default()}, |m, v| m.metallic     = v),
+        (Principled{base_colour: colour(0.05, 0.1, 0.4),  roughness: 0.2,                       ..Default::default()}, |m, v| m.specular     = v),
+        (Principled{base_colour: colour(0.9, 0.95, 1.0),  roughness: 0.05,                      ..Default::default()}, |m, v| m.transmission = v),
+        (Principled{base_colour: colour(0.2, 0.5, 0.2),   roughness: 0.7,                       ..Default::default()}, |m, v| m.clearcoat    = v),
+        (Principled{base_colour: colour(0.3, 0.05, 0.2),  roughness: 0.8,                       ..Default::default()}, |m, v| m.sheen        = v),
+        (Principled{base_colour: colour(0.9, 0.9, 0.9),   roughness: 0.4, metallic: 1.0,        ..Default::default()}, |m, v| m.anisotropy   = v),
+    ];
+
+    let columns = 6;
+
+    for (row, (base_material, set_parameter)) in rows.iter().enumerate()
+    {
+        for column in 0..columns
+        {
+            let mut material = base_material.clone();
+
+            set_parameter(&mut material, column as f64 / (columns - 1) as f64);
+
+            world_element.add_sphere(column as f64 - 0.5 * (columns - 1) as f64, 0.6 + (rows.len() - 1 - row) as f64, 0.0, 0.4, Arc::new(material));
+        }
+    }
+
+    world_element
+}
@@ -0,0 +1,162 @@
+// Nested dielectrics (after Schmidt and Budge, "Simple Nested Dielectrics in Ray Traced Images", 2002)
+//
+// A path keeps a list of the dielectric objects (glass, water, ...) it is inside. Touching objects are modelled
+// overlapping slightly, e.g. the water in a glass a little larger than the inside of the glass, so that there is no
+// gap of air between them, and each object has a priority: where objects overlap the one with the highest priority
+// fills the overlap. The surface of an object inside one of higher priority is then a false intersection, which the
+// ray passes straight through, and at a true intersection the refractive indices on either side are those of the
+// highest priority object the ray is inside before and after crossing it (air outside them all). Among objects of
+// equal priority the one most recently entered counts, so without priorities objects simply nest.
+
+use std::ptr;
+
+use crate::material::{Material, Interior};
+
+#[derive(Clone)]
+pub struct DielectricStack<'a>
+{
+    // In the order entered; the materials identify the objects (an object entered twice is listed twice)
+    entries: Vec<(&'a dyn Material, Interior)>
+}
+
+impl<'a> DielectricStack<'a>
+{
+    pub fn new() -> DielectricStack<'a>
+    {
+        DielectricStack { entries: vec![] }
+    }
+
+    // What the ray is travelling through, None outside every dielectric object
+    pub fn current(&self) -> Option<Interior>
+    {
+        self.highest(None)
+    }
+
+    // Whether the ray passes straight through the surface of material, which it hits from outside if is_front
+    pub fn is_false_intersection(&self, material: &dyn Material, interior: &Interior, is_front: bool) -> bool
+    {
+        let others = if is_front {self.current()} else {self.highest(self.position(material))};
+
+        others.is_some_and(|other| other.priority > interior.priority)
+    }
+
+    // At a true intersection with the surface of material, the ratio of the refractive index beyond it to that on the
+    // side the ray arrives from
+    pub fn relative_index(&self, material: &dyn Material, interior: &Interior, is_front: bool) -> f64
+    {
+        let outside = |interior: Option<Interior>| interior.map_or(1.0, |interior| interior.index_of_refraction);
+
+        if is_front
+        {
+            return interior.index_of_refraction / outside(self.current());
+        }
+
+        match self.position(material)
+        {
+            Some(index) => outside(self.highest(Some(index))) / outside(self.current()),
+
+            // Leaving an object the ray was not known to be inside (e.g. the camera is inside it)
+            None        => outside(self.current()) / interior.index_of_refraction,
+        }
+    }
+
+    // Record that the ray has crossed the surface of material, entering the object if is_front, otherwise leaving it
+    // (ignored if the ray was not known to be inside)
+    pub fn cross(&mut self, material: &'a dyn Material, interior: Interior, is_front: bool)
+    {
+        if is_front
+        {
+            self.entries.push((material, interior));
+        }
+        else if let Some(index) = self.position(material)
+        {
+            self.entries.remove(index);
+        }
+    }
+
+    // Most recent entry for material
+    fn position(&self, material: &dyn Material) -> Option<usize>
+    {
+        self.entries.iter().rposition(|(entry, _)| ptr::addr_eq(*entry, material))
+    }
+
+    // The highest priority interior, most recently entered of equal priority, leaving out the entry at index excluded
+    fn highest(&self, excluded: Option<usize>) -> Option<Interior>
+    {
+        self.entries.iter()
+                    .enumerate()
+                    .filter(|(index, _)| Some(*index) != excluded)
+                    .map(|(_, (_, interior))| *interior)
+                    .max_by_key(|interior| interior.priority)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{my_vec3::MyVec3, material::Refractive};
+
+    fn dielectric(index_of_refraction: f64, priority: u32) -> Refractive
+    {
+        Refractive { gain: MyVec3 { x: 1.0, y: 1.0, z: 1.0 }, index_of_refraction, roughness: 0.0, absorption: MyVec3::default(), priority }
+    }
+
+    // A ray passes into a glass (priority 2) holding water (priority 1) which overlaps the inside of the glass, then
+    // through the bottom of the glass into the water, and out of the water into the air
+    #[test]
+    fn glass_of_water()
+    {
+        let glass = dielectric(1.5, 2);
+        let water = dielectric(1.33, 1);
+
+        let glass_interior = glass.interior().unwrap();
+        let water_interior = water.interior().unwrap();
+
+        let mut stack = DielectricStack::new();
+
+        assert!(!stack.is_false_intersection(&glass, &glass_interior, true));
+        assert_eq!(stack.relative_index(&glass, &glass_interior, true), 1.5);
+        stack.cross(&glass, glass_interior, true);
+
+        // The surface of the water inside the glass is not there
+        assert!(stack.is_false_intersection(&water, &water_interior, true));
+        stack.cross(&water, water_interior, true);
+        assert_eq!(stack.current().unwrap().index_of_refraction, 1.5);
+
+        // The inside of the glass is the boundary between glass and water
+        assert!(!stack.is_false_intersection(&glass, &glass_interior, false));
+        assert_eq!(stack.relative_index(&glass, &glass_interior, false), 1.33 / 1.5);
+        stack.cross(&glass, glass_interior, false);
+
+        assert!(!stack.is_false_intersection(&water, &water_interior, false));
+        assert_eq!(stack.relative_index(&water, &water_interior, false), 1.0 / 1.33);
+        stack.cross(&water, water_interior, false);
+
+        assert!(stack.current().is_none());
+    }
+
+    // Air bubbles (index one, higher priority) in water are true intersections whether or not they overlap its surface
+    #[test]
+    fn air_bubble_in_water()
+    {
+        let water  = dielectric(1.33, 1);
+        let bubble = dielectric(1.0, 2);
+
+        let water_interior  = water.interior().unwrap();
+        let bubble_interior = bubble.interior().unwrap();
+
+        let mut stack = DielectricStack::new();
+
+        stack.cross(&water, water_interior, true);
+
+        assert!(!stack.is_false_intersection(&bubble, &bubble_interior, true));
+        assert_eq!(stack.relative_index(&bubble, &bubble_interior, true), 1.0 / 1.33);
+        stack.cross(&bubble, bubble_interior, true);
+
+        // The water's surface inside the bubble is false, and leaving the bubble the ray is in air
+        assert!(stack.is_false_intersection(&water, &water_interior, false));
+        stack.cross(&water, water_interior, false);
+        assert_eq!(stack.relative_index(&bubble, &bubble_interior, false), 1.0);
+    }
+}
@@ -0,0 +1,111 @@
+// The image being rendered: the samples accumulated so far for each pixel
+// Samples are added pass by pass (see renderer::render_pass), so the film can be turned into an image at any time,
+// e.g. for a preview, with each pixel the average of the samples it has received
+
+use crate::my_vec3::MyVec3;
+
+// Pixels whose weights sum to less than this are treated as having no samples. With the negative lobes of the Mitchell
+// and Lanczos filters a few samples can leave a tiny positive sum, and dividing by it would give a huge value
+const MIN_WEIGHT_SUM: f64 = 1e-3;
+
+pub struct Film
+{
+    pub width:  u32,
+    pub height: u32,
+    pixels:     Vec<FilmPixel>      // Rows from the top of the image
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FilmPixel
+{
+    pub colour_sum: MyVec3,             // Weighted sum of the sample values
+    pub weight_sum: f64,
+    pub statistics: PixelStatistics     // Of the samples taken for this pixel
+}
+
+impl Film
+{
+    pub fn new(width: u32, height: u32) -> Film
+    {
+        Film { width, height, pixels: vec![FilmPixel::default(); (width * height) as usize] }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &FilmPixel
+    {
+        &self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut FilmPixel
+    {
+        &mut self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn pixels(&self) -> &[FilmPixel]
+    {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [FilmPixel]
+    {
+        &mut self.pixels
+    }
+
+    // Linear (scene-referred) values, three per pixel; black where a pixel has no samples yet
+    // Negative values, which the negative filter lobes can give next to bright edges, are clamped to zero
+    pub fn framebuffer(&self) -> Vec<f32>
+    {
+        self.pixels.iter()
+                   .flat_map(|p|
+                   {
+                       let c = if p.weight_sum > MIN_WEIGHT_SUM {p.colour_sum / p.weight_sum} else {MyVec3::default()};
+
+                       [f64::max(c.x, 0.0) as f32, f64::max(c.y, 0.0) as f32, f64::max(c.z, 0.0) as f32]
+                   })
+                   .collect()
+    }
+
+    pub fn sample_counts(&self) -> Vec<u32>
+    {
+        self.pixels.iter().map(|p| p.statistics.count).collect()
+    }
+}
+
+// Number of samples of a pixel and the variance of their luminance (Welford's algorithm), used to decide when the pixel
+// has converged
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PixelStatistics
+{
+    pub count:          u32,
+    pub luminance_mean: f64,
+    pub luminance_m2:   f64     // Sum of squared differences from the mean
+}
+
+impl PixelStatistics
+{
+    pub fn add(&mut self, colour: MyVec3)
+    {
+        let luminance = 0.2126 * colour.x + 0.7152 * colour.y + 0.0722 * colour.z;
+
+        self.count += 1;
+
+        let delta = luminance - self.luminance_mean;
+
+        self.luminance_mean += delta / self.count as f64;
+        self.luminance_m2   += delta * (luminance - self.luminance_mean);
+    }
+
+    // Standard error of the mean luminance relative to the mean; the denominator is kept away from zero so that dark
+    // pixels, where any error is invisible, are not sampled without end
+    pub fn relative_error(&self) -> f64
+    {
+        if self.count < 2
+        {
+            return f64::INFINITY;
+        }
+
+        let n        = self.count as f64;
+        let variance = self.luminance_m2 / (n - 1.0);
+
+        f64::sqrt(variance / n) / f64::max(self.luminance_mean, 0.01)
+    }
+}
@@ -0,0 +1,135 @@
+// Reconstruction filters: how much each sample contributes to the pixels around it
+//
+// Every sample is splatted onto each pixel whose centre is within the filter radius of the sample's position, weighted
+// by the filter at the offset between them, and a pixel's value is the weighted sum of its samples divided by the sum
+// of their weights. A filter wider than a pixel blends each sample into the neighbouring pixels, which smooths the
+// stair steps along high-contrast edges that a box of one pixel leaves. All the filters are separable:
+// weight(dx, dy) = f(dx) * f(dy), with offsets in pixels.
+
+use std::f64::consts::PI;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum, Default)]
+pub enum FilterType
+{
+    #[default]
+    Box,        // Equal weight within the radius; radius 0.5 averages the samples of each pixel only. Sharpest, but aliases
+    Tent,       // Weight falls linearly to zero at the radius; mild smoothing
+    Gaussian,   // Gaussian with a standard deviation of a third of the radius, shifted to reach zero at the radius; soft
+    Mitchell,   // Mitchell-Netravali cubic (B = C = 1/3); small negative lobes keep edges sharp with little ringing
+    Lanczos     // Windowed sinc with radius lobes; sharpest of the wide filters, but can ring (halos) around edges
+}
+
+impl FilterType
+{
+    // Radius (in pixels) used when none is given
+    pub fn default_radius(&self) -> f64
+    {
+        match self
+        {
+            FilterType::Box      => 0.5,
+            FilterType::Tent     => 1.0,
+            FilterType::Gaussian => 1.5,
+            FilterType::Mitchell => 2.0,
+            FilterType::Lanczos  => 2.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Filter
+{
+    pub filter_type: FilterType,
+    pub radius:      f64
+}
+
+impl Default for Filter
+{
+    fn default() -> Self
+    {
+        Filter { filter_type: FilterType::Box, radius: 0.5 }
+    }
+}
+
+impl Filter
+{
+    pub fn new(filter_type: FilterType, radius: Option<f64>) -> Result<Filter, String>
+    {
+        let radius = radius.unwrap_or_else(|| filter_type.default_radius());
+
+        if !(radius > 0.0 && radius <= 8.0)
+        {
+            return Err(format!("The filter radius must be greater than 0 and at most 8 pixels (got {})", radius));
+        }
+
+        Ok(Filter { filter_type, radius })
+    }
+
+    // Pixels on each side of a sample's own pixel which it may contribute to (samples lie within their own pixel)
+    pub fn pixel_reach(&self) -> u32
+    {
+        u32::max(1, f64::ceil(self.radius + 0.5) as u32) - 1
+    }
+
+    // Weight of a sample at offset (dx, dy) pixels from a pixel's centre
+    pub fn weight(&self, dx: f64, dy: f64) -> f64
+    {
+        self.evaluate(dx) * self.evaluate(dy)
+    }
+
+    fn evaluate(&self, x: f64) -> f64
+    {
+        let r = self.radius;
+        let x = f64::abs(x);
+
+        if x >= r
+        {
+            return 0.0;
+        }
+
+        match self.filter_type
+        {
+            FilterType::Box      => 1.0,
+            FilterType::Tent     => r - x,
+            FilterType::Gaussian =>
+            {
+                let gaussian = |x: f64| f64::exp(-4.5 * x * x / (r * r));   // Standard deviation r / 3
+
+                gaussian(x) - gaussian(r)
+            }
+            FilterType::Mitchell => mitchell(2.0 * x / r),
+            FilterType::Lanczos  => sinc(x) * sinc(x / r),
+        }
+    }
+}
+
+// Mitchell-Netravali cubic for B = C = 1/3, defined on [0, 2)
+fn mitchell(x: f64) -> f64
+{
+    const B: f64 = 1.0 / 3.0;
+    const C: f64 = 1.0 / 3.0;
+
+    let x2 = x * x;
+    let x3 = x2 * x;
+
+    let value = if x < 1.0
+    {
+        (12.0 - 9.0 * B - 6.0 * C) * x3 + (-18.0 + 12.0 * B + 6.0 * C) * x2 + (6.0 - 2.0 * B)
+    }
+    else
+    {
+        (-B - 6.0 * C) * x3 + (6.0 * B + 30.0 * C) * x2 + (-12.0 * B - 48.0 * C) * x + (8.0 * B + 24.0 * C)
+    };
+
+    value / 6.0
+}
+
+// Normalised sinc, sin(pi x) / (pi x)
+fn sinc(x: f64) -> f64
+{
+    if x < 1e-5
+    {
+        return 1.0;
+    }
+
+    f64::sin(PI * x) / (PI * x)
+}
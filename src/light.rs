@@ -0,0 +1,187 @@
+// Explicitly sampled light sources
+//
+// Every static emissive sphere and triangle is also registered with the world as a light, so that the renderer can
+// choose points on the lights directly (next-event estimation) rather than waiting for scattered rays to hit them.
+// Probability densities are all with respect to solid angle as seen from the point being lit, so that they can be
+// compared with the densities of scattered directions for multiple importance sampling.
+
+use std::{f64::consts::PI, fmt::Debug, sync::Arc};
+
+use crate::my_vec3::{MyVec3, vec3_orthonormal_basis};
+
+pub struct LightSample
+{
+    pub direction: MyVec3,      // Unit vector from the lit point towards the light
+    pub distance:  f64,         // Distance to the sampled point on the light
+    pub radiance:  MyVec3,
+    pub pdf:       f64
+}
+
+pub trait Light: Debug
+{
+    // Choose a direction towards the light from origin, using the two uniform random numbers u1 and u2
+    fn sample(&self, origin: MyVec3, u1: f64, u2: f64) -> Option<LightSample>;
+
+    // The density with which sample would choose the direction from origin to point, zero if point is not on this light
+    fn pdf(&self, origin: MyVec3, point: MyVec3) -> f64;
+}
+
+// Held by the world's list of lights and by the object which is the light, so that a scattered ray which hits the
+// object finds its light without searching the list
+pub type SharedLight = Arc<dyn Light + Send + Sync>;
+
+// Sampled uniformly within the cone of directions which the sphere subtends
+#[derive(Debug)]
+pub struct SphereLight
+{
+    pub c:        MyVec3,
+    pub r:        f64,
+    pub radiance: MyVec3
+}
+
+impl SphereLight
+{
+    // 1 - cos(theta_max), where theta_max is the half-angle of the cone subtended by the sphere
+    // Written so as not to lose precision for small, distant lights; None if origin is inside the sphere
+    fn one_minus_cos_max(&self, origin: MyVec3) -> Option<f64>
+    {
+        let sin2_max = self.r * self.r / (self.c - origin).squared_length();
+
+        if sin2_max >= 1.0
+        {
+            return None;
+        }
+
+        Some(sin2_max / (1.0 + f64::sqrt(1.0 - sin2_max)))
+    }
+}
+
+impl Light for SphereLight
+{
+    fn sample(&self, origin: MyVec3, u1: f64, u2: f64) -> Option<LightSample>
+    {
+        let one_minus_cos_max = self.one_minus_cos_max(origin)?;
+
+        let to_centre       = self.c - origin;
+        let centre_distance = to_centre.length();
+        let w               = to_centre / centre_distance;
+        let (u, v)          = vec3_orthonormal_basis(w);
+
+        let cos_theta = 1.0 - u1 * one_minus_cos_max;
+        let sin_theta = f64::sqrt(f64::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi       = 2.0 * PI * u2;
+
+        let direction = (sin_theta * f64::cos(phi)) * u + (sin_theta * f64::sin(phi)) * v + cos_theta * w;
+
+        // Nearer intersection of the ray with the sphere
+        let half_chord = f64::sqrt(f64::max(0.0, self.r * self.r - centre_distance * centre_distance * sin_theta * sin_theta));
+        let distance   = centre_distance * cos_theta - half_chord;
+
+        Some(LightSample { direction, distance, radiance: self.radiance, pdf: 1.0 / (2.0 * PI * one_minus_cos_max) })
+    }
+
+    fn pdf(&self, origin: MyVec3, point: MyVec3) -> f64
+    {
+        if f64::abs((point - self.c).length() - self.r) > 1e-6 * f64::max(self.r, 1.0)
+        {
+            return 0.0;
+        }
+
+        match self.one_minus_cos_max(origin)
+        {
+            Some(one_minus_cos_max) => 1.0 / (2.0 * PI * one_minus_cos_max),
+            None                    => 0.0,
+        }
+    }
+}
+
+// Sampled uniformly by area, emitting from both sides
+#[derive(Debug)]
+pub struct TriangleLight
+{
+    pub p0:       MyVec3,
+    pub p1:       MyVec3,
+    pub p2:       MyVec3,
+    pub radiance: MyVec3
+}
+
+impl TriangleLight
+{
+    fn area_normal(&self) -> (f64, MyVec3)
+    {
+        let cross = (self.p1 - self.p0).cross(self.p2 - self.p0);
+        let twice_area = cross.length();
+
+        (0.5 * twice_area, cross / twice_area)
+    }
+
+    // Convert the uniform area density to a solid angle density at origin
+    fn solid_angle_pdf(&self, origin: MyVec3, point: MyVec3) -> f64
+    {
+        let (area, normal) = self.area_normal();
+        let to_point       = point - origin;
+        let distance2      = to_point.squared_length();
+        let cos_light      = f64::abs(normal.dot(to_point)) / f64::sqrt(distance2);
+
+        if area <= 0.0 || cos_light < 1e-9
+        {
+            return 0.0;
+        }
+
+        distance2 / (cos_light * area)
+    }
+}
+
+impl Light for TriangleLight
+{
+    fn sample(&self, origin: MyVec3, u1: f64, u2: f64) -> Option<LightSample>
+    {
+        // Uniform barycentric co-ordinates (the square root warps the unit square onto the triangle without folding)
+        let s  = f64::sqrt(u1);
+        let b1 = 1.0 - s;
+        let b2 = u2 * s;
+
+        let point    = self.p0 + b1 * (self.p1 - self.p0) + b2 * (self.p2 - self.p0);
+        let to_point = point - origin;
+        let distance = to_point.length();
+        let pdf      = self.solid_angle_pdf(origin, point);
+
+        if distance <= 0.0 || pdf <= 0.0
+        {
+            return None;
+        }
+
+        Some(LightSample { direction: to_point / distance, distance, radiance: self.radiance, pdf })
+    }
+
+    fn pdf(&self, origin: MyVec3, point: MyVec3) -> f64
+    {
+        let (area, normal) = self.area_normal();
+
+        // The point must lie in the plane of the triangle ...
+        let scale = f64::sqrt(area).max(1.0);
+        if area <= 0.0 || f64::abs(normal.dot(point - self.p0)) > 1e-6 * scale
+        {
+            return 0.0;
+        }
+
+        // ... and inside the triangle (all three edge functions have the same sign as the normal)
+        let inside = |a: MyVec3, b: MyVec3| normal.dot((b - a).cross(point - a)) >= -1e-9 * scale * scale;
+
+        if !(inside(self.p0, self.p1) && inside(self.p1, self.p2) && inside(self.p2, self.p0))
+        {
+            return 0.0;
+        }
+
+        self.solid_angle_pdf(origin, point)
+    }
+}
+
+// Weight for a sample drawn with density pdf_a, when the same direction could also have been drawn with density pdf_b
+pub fn power_heuristic(pdf_a: f64, pdf_b: f64) -> f64
+{
+    let a2 = pdf_a * pdf_a;
+    let b2 = pdf_b * pdf_b;
+
+    if a2 + b2 == 0.0 { 0.0 } else { a2 / (a2 + b2) }
+}
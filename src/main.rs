@@ -1,25 +1,51 @@
-use std::cmp;
-use image::{self, RgbImage, ImageBuffer};
+use std::{cmp, fs, path::PathBuf, process, thread, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
 use clap::Parser;
 
 // Yes, I know there is a Vec3 crate which is probably more suitable but the goal is to learn Rust so I've implemented my own for practice (to be replaced later)
 mod my_vec3;
+mod my_matrix4;
+mod animated_transform;
 mod camera;
 mod rayinfo;
 mod ray;
 mod world_element;
 mod world_sphere;
+mod world_triangle;
+mod world_mesh;
+mod world_instance;
 mod bounding_box;
+mod bvh;
 mod material;
 mod scatter;
 mod common;
 mod create_world;
 mod renderer;
+mod light;
+mod texture;
+mod perlin;
+mod obj_loader;
+mod scene;
+mod output;
+mod tone_mapping;
+mod sampler;
+mod convergence;
+mod film;
+mod checkpoint;
+mod filter;
+mod medium;
+mod voxel_grid;
+mod microfacet;
+mod principled;
+mod dielectric_stack;
 
-use crate::create_world::create_world;
-use crate::my_vec3::MyVec3;
 use crate::camera::Camera;
-use crate::renderer::{Renderer, render};
+use crate::renderer::{Renderer, Sky, TileOrder, AdaptiveSampling, render_pass};
+use crate::film::Film;
+use crate::checkpoint::CheckpointHeader;
+use crate::filter::{Filter, FilterType};
+use crate::tone_mapping::{ToneMapping, ToneMapOperator};
+use crate::my_vec3::MyVec3;
+use crate::sampler::SamplerType;
 
 /*
  * Command-line argument parser
@@ -44,9 +70,119 @@ struct Args {
     #[clap(short, long, default_value_t = 50)]
     ray_bounce_depth: u32,
 
-    /// Number of threads to use (in the range 1 to 32 - out-of-range values will be clamped to this range)
-    #[clap(short, long, default_value_t = 8)]
-    number_of_threads: u32,
+    /// Number of threads to use (defaults to the number of threads the system can run in parallel)
+    #[clap(short, long)]
+    number_of_threads: Option<u32>,
+
+    /// Order in which the tiles of the image are rendered
+    #[clap(long, arg_enum, default_value = "scanline")]
+    tile_order: TileOrder,
+
+    /// Wavefront OBJ file to add to the scene (may be given more than once); materials are read from the MTL files it references
+    #[clap(long)]
+    obj: Vec<PathBuf>,
+
+    /// Scene description file (TOML) defining the camera, materials, and objects
+    #[clap(long, conflicts_with = "preset")]
+    scene: Option<PathBuf>,
+
+    /// Built-in scene to render when no scene file is given (final_scene or material_sweep)
+    #[clap(long, default_value = "final_scene")]
+    preset: String,
+
+    /// Background light for rays which leave the scene (overrides the scene file)
+    #[clap(long, arg_enum)]
+    sky: Option<Sky>,
+
+    /// Disable explicit sampling of light sources (lights are then only found by scattered rays)
+    #[clap(long)]
+    no_light_sampling: bool,
+
+    /// Output image file; the extension selects the format (exr, hdr, pfm are linear floating point; png, jpg are 8-bit)
+    #[clap(short, long, default_value = "test.jpg")]
+    output: PathBuf,
+
+    /// Bits per channel for PNG output (8 or 16)
+    #[clap(long, default_value_t = 8)]
+    png_bit_depth: u32,
+
+    /// Tone mapping operator used to convert the linear image for display (png and jpg output)
+    #[clap(long, arg_enum, default_value = "clamp")]
+    tone_map: ToneMapOperator,
+
+    /// Exposure adjustment in stops (EV) applied before tone mapping
+    #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    exposure: f64,
+
+    /// White balance as red, green, and blue multipliers, e.g. 1.0,0.95,0.85
+    #[clap(long, number_of_values = 3, use_value_delimiter = true, require_value_delimiter = true, default_values = &["1.0", "1.0", "1.0"])]
+    white_balance: Vec<f64>,
+
+    /// Luminance (after exposure) mapped to white by the extended Reinhard operator
+    #[clap(long, default_value_t = 4.0)]
+    white_point: f64,
+
+    /// Re-grade a linear image (exr, hdr, or pfm) previously written by the renderer instead of rendering a scene
+    #[clap(long)]
+    regrade: Option<PathBuf>,
+
+    /// Seed for the random numbers used to build the scene and to render it; the same seed gives the same image
+    #[clap(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Sampler generating the sample positions within each pixel, and the random numbers for each bounce
+    #[clap(long, arg_enum, default_value = "independent")]
+    sampler: SamplerType,
+
+    /// Instead of writing an image, render a reference with --samples-per-pixel samples and print the error of each
+    /// sampler against it at 1, 2, 4, ... samples per pixel
+    #[clap(long)]
+    convergence_benchmark: bool,
+
+    /// Adaptive sampling: each pixel stops taking samples once the estimated relative error of its brightness is below
+    /// this value (e.g. 0.01), taking at most --samples-per-pixel samples
+    #[clap(long)]
+    adaptive_error: Option<f64>,
+
+    /// Adaptive sampling: samples taken for every pixel before its error is first estimated
+    #[clap(long, default_value_t = 16)]
+    min_samples_per_pixel: u32,
+
+    /// Reconstruction filter weighting the samples of the pixels around each pixel: box (a radius of 0.5 averages the
+    /// samples of the pixel alone), tent, gaussian, mitchell (Mitchell-Netravali), or lanczos (sharpest, but may ring)
+    #[clap(long, arg_enum, default_value = "box")]
+    filter: FilterType,
+
+    /// Radius of the reconstruction filter in pixels (defaults: box 0.5, tent 1, gaussian 1.5, mitchell 2, lanczos 2)
+    #[clap(long)]
+    filter_radius: Option<f64>,
+
+    /// Write a heat map (png or jpg) of the number of samples taken for each pixel
+    #[clap(long)]
+    sample_map: Option<PathBuf>,
+
+    /// Progressive rendering: render the whole image in passes of this many samples per pixel, writing the image so far
+    /// to the output file between passes
+    #[clap(long)]
+    progressive: Option<u32>,
+
+    /// Progressive rendering: minimum time in seconds between preview images (0 writes a preview after every pass)
+    #[clap(long, default_value_t = 0.0)]
+    preview_interval: f64,
+
+    /// Save the render to this checkpoint file between passes (of --progressive samples per pixel, or 16 if not given),
+    /// when it is stopped, and when it finishes, so that it can be continued with --resume
+    #[clap(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Minimum time in seconds between checkpoints written between passes
+    #[clap(long, default_value_t = 300.0)]
+    checkpoint_interval: f64,
+
+    /// Continue the render saved in this checkpoint file up to --samples-per-pixel (also used to extend a finished
+    /// render); the scene, resolution, seed, and sampler must match. Checkpoints are saved back to it unless --checkpoint is given
+    #[clap(long)]
+    resume: Option<PathBuf>,
 }
 
 /*
@@ -60,64 +196,312 @@ fn main()
 
     let args = Args::parse();
 
-    const MIN_NUMBER_OF_THREADS: u32 = 1;
-    const MAX_NUMBER_OF_THREADS: u32 = 32;
     const MIN_SAMPLES_PER_PIXEL: u32 = 1;
     const MAX_SAMPLES_PER_PIXEL: u32 = 2000;
 
     // Main code starts here
     let image_width:  u32    = cmp::max(1, args.image_width);
     let image_height: u32    = cmp::max(1, args.image_height);
-    let colour_channels: u32 = 3;
 
-    let aspect_ratio: f64           = image_width as f64 / image_height as f64;
-    let field_of_view_vertical: f64 = (20.0/90.0) * std::f64::consts::PI / 2.0;
+    let aspect_ratio: f64    = image_width as f64 / image_height as f64;
 
     let samples_per_pixel: u32     = u32::clamp(args.samples_per_pixel, MIN_SAMPLES_PER_PIXEL, MAX_SAMPLES_PER_PIXEL);
     let max_ray_bounce_depth: u32  = cmp::max  (1, args.ray_bounce_depth);
-    let number_of_threads: u32     = u32::clamp(args.number_of_threads, MIN_NUMBER_OF_THREADS, MAX_NUMBER_OF_THREADS);
+    let available_threads: u32     = thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1);
+    let number_of_threads: u32     = cmp::max  (1, args.number_of_threads.unwrap_or(available_threads));
+
+    let white_balance = MyVec3{x: args.white_balance[0], y: args.white_balance[1], z: args.white_balance[2]};
+    let tone_mapping  = ToneMapping{operator: args.tone_map, exposure: args.exposure, white_balance, white_point: args.white_point};
+
+    // Check the output file can be written before spending time rendering
+    if let Err(e) = output::OutputFormat::from_path(&args.output)
+    {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+
+    if args.png_bit_depth != 8 && args.png_bit_depth != 16
+    {
+        eprintln!("Error: PNG bit depth must be 8 or 16 (got {})", args.png_bit_depth);
+        process::exit(1);
+    }
+
+    let filter = match Filter::new(args.filter, args.filter_radius)
+    {
+        Ok(filter) => filter,
+        Err(e) =>
+        {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if args.white_point <= 0.0 || args.white_balance.iter().any(|&w| w < 0.0)
+    {
+        eprintln!("Error: The white point must be positive and the white balance multipliers must not be negative");
+        process::exit(1);
+    }
 
     /*
-    * Create the camera and viewport for our render
+    * Re-grade an existing linear image, no rendering is needed
     */
 
-    let camera_location  = MyVec3 { x:  13.0, y: 2.0, z: 3.0};
-    let camera_target    = MyVec3 { x:   0.0, y: 0.0, z: 0.0};
-    let camera_up        = MyVec3 { x:   0.0, y: 1.0, z: 0.0};
+    if let Some(regrade_path) = &args.regrade
+    {
+        let result = output::load_linear_image(regrade_path)
+                            .and_then(|(width, height, framebuffer)| output::save_image(&args.output, width, height, &framebuffer, &tone_mapping, args.png_bit_depth));
+
+        if let Err(e) = result
+        {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
 
-    let focus_distance  = 10.0;
-    let aperture        = 0.1;
-    let exposure_length = 1.0;
+        return;
+    }
 
-    let camera: Camera = Camera::new(camera_location, None, Some(camera_target), Some(camera_up), Some(focus_distance), Some(aperture), Some(exposure_length), aspect_ratio, field_of_view_vertical).unwrap();
+    /*
+    * Load the scene (camera and world), and create the camera and viewport for our render
+    */
+
+    let scene_result = match &args.scene
+    {
+        Some(scene_path) => scene::load_scene(scene_path, args.seed),
+        None             => scene::preset_scene(&args.preset, args.seed),
+    };
+
+    let scene = match scene_result
+    {
+        Ok(scene) => scene,
+        Err(e) =>
+        {
+            eprintln!("Error loading scene: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let exposure_length = scene.camera.exposure_length();
+
+    let camera: Camera = match scene.camera.to_camera(aspect_ratio)
+    {
+        Ok(camera) => camera,
+        Err(e) =>
+        {
+            eprintln!("Error creating camera: {}", e);
+            process::exit(1);
+        }
+    };
 
     /* 
     * Prepare the world
     */
 
-    let world_element = create_world();
+    let mut world_element = scene.world_element;
+
+    for obj_path in &args.obj
+    {
+        match obj_loader::load_obj(obj_path)
+        {
+            Ok(meshes) =>
+            {
+                for mesh in meshes
+                {
+                    world_element.add_mesh(mesh);
+                }
+            }
+            Err(e) =>
+            {
+                eprintln!("Error loading OBJ file: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    world_element.build_bvh(0.0, exposure_length);
 
     /* 
     * Render
     */
-    let renderer = Renderer::new(image_width, image_height, colour_channels, samples_per_pixel, max_ray_bounce_depth, camera, world_element);
+    let renderer_sky = args.sky.unwrap_or(scene.sky);
+
+    let mut renderer = Renderer::new(image_width, image_height, samples_per_pixel, max_ray_bounce_depth, camera, world_element, renderer_sky, !args.no_light_sampling, args.tile_order, args.sampler, args.seed);
+
+    renderer.set_filter(filter);
+    renderer.set_fog(scene.fog);
+
+    if let Some(target_error) = args.adaptive_error
+    {
+        match AdaptiveSampling::new(args.min_samples_per_pixel, samples_per_pixel, target_error)
+        {
+            Ok(adaptive_sampling) => renderer.set_adaptive_sampling(Some(adaptive_sampling)),
+            Err(e) =>
+            {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if args.convergence_benchmark
+    {
+        convergence::convergence_benchmark(&mut renderer, number_of_threads, samples_per_pixel, args.seed);
+        return;
+    }
+
+    /*
+    * Render in passes of pass_samples samples per pixel (a single pass unless progressive), writing a preview of the
+    * image after a pass once preview_interval has elapsed since the last one. On Ctrl-C rendering stops after the
+    * current sample of each thread and the image so far is written; a second Ctrl-C exits immediately
+    */
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = Arc::clone(&stop);
+
+    let handler_result = ctrlc::set_handler(move ||
+    {
+        if handler_stop.swap(true, Ordering::SeqCst)
+        {
+            process::exit(130);
+        }
+
+        eprintln!("Stopping, the image rendered so far will be written (press Ctrl-C again to exit now)");
+    });
+
+    if let Err(e) = handler_result
+    {
+        eprintln!("Warning: Unable to handle Ctrl-C: {}", e);
+    }
+
+    // Without checkpoints the whole render is a single pass; checkpoints are only written between passes
+    const CHECKPOINT_PASS_SAMPLES: u32 = 16;
+
+    let checkpoint_path = args.checkpoint.as_ref().or(args.resume.as_ref());
+    let default_pass    = if checkpoint_path.is_some() {CHECKPOINT_PASS_SAMPLES} else {samples_per_pixel};
+
+    let pass_samples        = cmp::max(1, args.progressive.unwrap_or(default_pass));
+    let preview_interval    = Duration::from_secs_f64(f64::max(0.0, args.preview_interval));
+    let checkpoint_interval = Duration::from_secs_f64(f64::max(0.0, args.checkpoint_interval));
+
+    let write_film = |film: &Film|
+    {
+        let mut result = output::save_image(&args.output, film.width, film.height, &film.framebuffer(), &tone_mapping, args.png_bit_depth);
 
-    let bitmap = render(renderer, number_of_threads);
+        if let (Ok(()), Some(sample_map_path)) = (&result, &args.sample_map)
+        {
+            result = output::save_sample_map(sample_map_path, film.width, film.height, &film.sample_counts(), samples_per_pixel);
+        }
 
-    // Image buffer
-    let mut img: RgbImage = ImageBuffer::new(image_width, image_height);
+        if let Err(e) = result
+        {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let checkpoint_header = match checkpoint_path
+    {
+        Some(_) => match scene_hash(&args, max_ray_bounce_depth, renderer_sky, &filter)
+        {
+            Ok(scene_hash) => CheckpointHeader { width: image_width, height: image_height, scene_hash, seed: args.seed, sampler_type: args.sampler },
+            Err(e) =>
+            {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        },
+        None => CheckpointHeader { width: image_width, height: image_height, scene_hash: 0, seed: args.seed, sampler_type: args.sampler },
+    };
+
+    let write_checkpoint = |film: &Film|
+    {
+        if let Some(path) = checkpoint_path
+        {
+            if let Err(e) = checkpoint::save_checkpoint(path, &checkpoint_header, film)
+            {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    };
+
+    let mut film = match &args.resume
+    {
+        Some(resume_path) => match checkpoint::load_checkpoint(resume_path, &checkpoint_header)
+        {
+            Ok(film) => film,
+            Err(e) =>
+            {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        },
+        None => Film::new(image_width, image_height),
+    };
+
+    // Each pass brings every pixel up to the pass's sample count from however many samples it already has, so a
+    // checkpoint stopped part way through a pass is resumed correctly from the most samples any pixel has
+    let mut samples_done    = film.sample_counts().into_iter().max().unwrap_or(0);
+    let mut last_preview    = Instant::now();
+    let mut last_checkpoint = Instant::now();
+    let     start_time      = Instant::now();
+
+    if args.resume.is_some()
+    {
+        eprintln!("Resuming from {} samples per pixel", samples_done);
+    }
+
+    while samples_done < samples_per_pixel && !stop.load(Ordering::SeqCst)
+    {
+        samples_done = cmp::min(samples_done.saturating_add(pass_samples), samples_per_pixel);
+
+        render_pass(&renderer, &mut film, samples_done, number_of_threads, &stop);
+
+        let more_passes = samples_done < samples_per_pixel && !stop.load(Ordering::SeqCst);
+
+        if args.progressive.is_some() && more_passes && last_preview.elapsed() >= preview_interval
+        {
+            eprintln!("{} of {} samples per pixel ({:.1}s), writing preview", samples_done, samples_per_pixel, start_time.elapsed().as_secs_f64());
+
+            write_film(&film);
+            last_preview = Instant::now();
+        }
+
+        if more_passes && last_checkpoint.elapsed() >= checkpoint_interval
+        {
+            write_checkpoint(&film);
+            last_checkpoint = Instant::now();
+        }
+    }
+
+    // The checkpoint is kept when the render finishes so that it can be extended to more samples later
+    write_checkpoint(&film);
 
-    let mut idx = 0;
     // Write out the final image to a file
-    for (_x, _y, pixel) in img.enumerate_pixels_mut()
+    write_film(&film);
+}
+
+// Hash of the scene and the settings which change the rendered image, apart from the resolution, seed, and sampler
+// which checkpoints store separately. Scene and OBJ files are hashed by content; files they reference (MTL files,
+// textures) are not. --samples-per-pixel is left out, as a resumed render may be extended to more samples, but the
+// adaptive sampling settings which decide where the samples go are included
+fn scene_hash(args: &Args, max_ray_bounce_depth: u32, sky: Sky, filter: &Filter) -> Result<u64, String>
+{
+    let read = |path: &PathBuf| fs::read(path).map_err(|e| format!("{}: {}", path.display(), e));
+
+    let mut hash = match &args.scene
     {
-        let rd = bitmap[idx]; idx += 1;
-        let gn = bitmap[idx]; idx += 1;
-        let bl = bitmap[idx]; idx += 1;
+        Some(scene_path) => common::hash_bytes(common::HASH_START, &read(scene_path)?),
+        None             => common::hash_bytes(common::HASH_START, args.preset.as_bytes()),
+    };
 
-        *pixel = image::Rgb([rd, gn, bl])
+    for obj_path in &args.obj
+    {
+        hash = common::hash_bytes(hash, &read(obj_path)?);
     }
 
-    img.save("test.jpg").unwrap();
-    
+    let adaptive_sampling = args.adaptive_error.map(|target_error| (target_error, args.min_samples_per_pixel));
+
+    let settings = format!("{} {:?} {} {:?} {} {:?}", max_ray_bounce_depth, sky, args.no_light_sampling, filter.filter_type, filter.radius, adaptive_sampling);
+
+    Ok(common::hash_bytes(hash, settings.as_bytes()))
 }
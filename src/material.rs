@@ -1,22 +1,380 @@
 #![allow(dead_code)]
 
-use crate::{my_vec3::MyVec3, scatter::ScatteringType};
+// Materials decide what happens to light where a ray meets a surface: how it is scattered (the BSDF), how much of it
+// the surface absorbs, and what the surface emits. The renderer only uses the Material trait, so a new kind of
+// material is added by implementing it. Materials are shared between objects through Arc, so e.g. a mesh of many
+// triangles holds one material
 
-//
-#[derive(Debug, Copy, Clone, Default)]
+use std::{any::Any, fmt::Debug, sync::Arc};
 
-pub struct Material {
-    pub surface:             ScatteringType,
+use crate::{my_vec3::MyVec3, ray::Ray, rayinfo::RayInfo, sampler::Sampler, texture::SharedTexture, medium::Medium,
+            microfacet::{self, Conductor, MIN_ALPHA}, scatter::{self, ScatterSample}, principled::PrincipledBsdf};
+
+// Any lets the concrete material be recovered from a SharedMaterial (e.g. to check what a loader has made)
+pub trait Material: Debug + Any
+{
+    // Choose the direction of the ray scattered where r meets the surface; None where the light is absorbed
+    fn sample(&self, r: Ray, ray_info: &RayInfo, sampler: &mut dyn Sampler) -> Option<ScatterSample>;
+
+    // For light arriving along the unit vector direction and leaving back along r, the BSDF times the cosine factor,
+    // including the attenuation; specular materials only scatter into the directions they sample, so this is zero
+    fn evaluate(&self, _r: Ray, _ray_info: &RayInfo, _direction: MyVec3) -> MyVec3
+    {
+        MyVec3 { x: 0.0, y: 0.0, z: 0.0 }
+    }
+
+    // Density (with respect to solid angle) with which sample would choose direction
+    fn pdf(&self, _r: Ray, _ray_info: &RayInfo, _direction: MyVec3) -> f64
+    {
+        0.0
+    }
+
+    // Specular materials scatter light into a single direction, which a sample chosen on a light would (almost) never
+    // match, so light sources are not sampled for them
+    fn is_specular(&self) -> bool
+    {
+        true
+    }
+
+    // Radiance emitted by the surface, None for surfaces which do not emit
+    fn emission(&self) -> Option<MyVec3>
+    {
+        None
+    }
+
+    // Colour of the surface at the intersect, the fraction of the light of each colour which is not absorbed
+    fn attenuation(&self, ray_info: &RayInfo) -> MyVec3;
+
+    // The medium inside an invisible surface which only marks where rays enter and leave it; None for visible surfaces
+    fn medium(&self) -> Option<&Medium>
+    {
+        None
+    }
+
+    // For the surface of a closed dielectric object which light passes into, what is inside it; None for surfaces
+    // light does not enter
+    fn interior(&self) -> Option<Interior>
+    {
+        None
+    }
+}
+
+// The inside of a dielectric object
+#[derive(Debug, Copy, Clone)]
+pub struct Interior
+{
+    pub index_of_refraction: f64,
+
+    // Fraction of each colour absorbed per unit distance (Beer-Lambert: after distance d, exp(-absorption * d) remains)
+    pub absorption:          MyVec3,
+
+    // Where objects overlap, the one with the highest priority fills the overlap (see dielectric_stack.rs)
+    pub priority:            u32
+}
+
+pub type SharedMaterial = Arc<dyn Material + Send + Sync>;
+
+// Colour from the texture where there is one, otherwise gain
+fn gain_at(gain: MyVec3, texture: &Option<SharedTexture>, ray_info: &RayInfo) -> MyVec3
+{
+    match texture
+    {
+        Some(texture) => texture.value(ray_info.u, ray_info.v, ray_info.intersect),
+        None          => gain,
+    }
+}
+
+// Lambertian reflection, gain / pi
+#[derive(Debug, Clone)]
+pub struct Diffuse
+{
+    pub gain:    MyVec3,
+    pub texture: Option<SharedTexture>      // Where present, replaces gain as the colour of the surface
+}
+
+impl Material for Diffuse
+{
+    fn sample(&self, r: Ray, ray_info: &RayInfo, sampler: &mut dyn Sampler) -> Option<ScatterSample>
+    {
+        let direction = scatter::diffuse_scatter(r, ray_info.normal, sampler);
+
+        Some(ScatterSample { direction, weight: self.attenuation(ray_info), pdf: Some(scatter::diffuse_pdf(ray_info.normal, direction)) })
+    }
+
+    fn evaluate(&self, _r: Ray, ray_info: &RayInfo, direction: MyVec3) -> MyVec3
+    {
+        f64::max(0.0, ray_info.normal.dot(direction)) / std::f64::consts::PI * self.attenuation(ray_info)
+    }
+
+    fn pdf(&self, _r: Ray, ray_info: &RayInfo, direction: MyVec3) -> f64
+    {
+        scatter::diffuse_pdf(ray_info.normal, direction)
+    }
+
+    fn is_specular(&self) -> bool
+    {
+        false
+    }
+
+    fn attenuation(&self, ray_info: &RayInfo) -> MyVec3
+    {
+        gain_at(self.gain, &self.texture, ray_info)
+    }
+}
+
+// GGX conductor, a perfect mirror when the roughness is zero
+#[derive(Debug, Clone)]
+pub struct Metallic
+{
+    pub gain:      MyVec3,
+    pub texture:   Option<SharedTexture>,
+    pub roughness: f64,                     // Perceptual roughness in [0, 1]
+
+    // Complex refractive index, which gain then tints; without it, gain is the reflectance at normal incidence
+    pub conductor: Option<Conductor>
+}
+
+impl Metallic
+{
+    // Fraction of the light reflected at a facet, from the cosine of the angle of incidence
+    fn fresnel(&self, ray_info: &RayInfo, cos_i: f64) -> MyVec3
+    {
+        let gain = self.attenuation(ray_info);
+
+        match &self.conductor
+        {
+            Some(conductor) => gain * conductor.fresnel(cos_i),
+            None            => microfacet::fresnel_schlick(cos_i, gain),
+        }
+    }
+}
+
+impl Material for Metallic
+{
+    fn sample(&self, r: Ray, ray_info: &RayInfo, sampler: &mut dyn Sampler) -> Option<ScatterSample>
+    {
+        scatter::conductor_scatter(r, ray_info.normal, microfacet::alpha_from_roughness(self.roughness), &|cos_i| self.fresnel(ray_info, cos_i), sampler)
+    }
+
+    fn evaluate(&self, r: Ray, ray_info: &RayInfo, direction: MyVec3) -> MyVec3
+    {
+        scatter::conductor_evaluate(r, ray_info.normal, microfacet::alpha_from_roughness(self.roughness), &|cos_i| self.fresnel(ray_info, cos_i), direction).0
+    }
+
+    fn pdf(&self, r: Ray, ray_info: &RayInfo, direction: MyVec3) -> f64
+    {
+        scatter::conductor_evaluate(r, ray_info.normal, microfacet::alpha_from_roughness(self.roughness), &|cos_i| self.fresnel(ray_info, cos_i), direction).1
+    }
+
+    fn is_specular(&self) -> bool
+    {
+        microfacet::alpha_from_roughness(self.roughness) < MIN_ALPHA
+    }
+
+    fn attenuation(&self, ray_info: &RayInfo) -> MyVec3
+    {
+        gain_at(self.gain, &self.texture, ray_info)
+    }
+}
+
+// Glass, smooth or (with a roughness) GGX; gain tints the light at every crossing of the surface, absorption along the
+// distance travelled inside, so thick glass is more deeply coloured than thin
+#[derive(Debug, Clone)]
+pub struct Refractive
+{
     pub gain:                MyVec3,
-    pub metal_fuzz:          Option<f64>,
-    pub index_of_refraction: Option<f64>
+    pub index_of_refraction: f64,
+    pub roughness:           f64,
+    pub absorption:          MyVec3,    // Per unit distance, zero for clear glass
+    pub priority:            u32
+}
+
+impl Refractive
+{
+    // Ratio of the refractive index beyond the surface to that on the side the ray arrives from; against air unless
+    // the renderer knows better (e.g. glass in water)
+    fn relative_index(&self, ray_info: &RayInfo) -> f64
+    {
+        ray_info.relative_index.unwrap_or(if ray_info.is_front {self.index_of_refraction} else {1.0 / self.index_of_refraction})
+    }
 }
 
+impl Material for Refractive
+{
+    fn sample(&self, r: Ray, ray_info: &RayInfo, sampler: &mut dyn Sampler) -> Option<ScatterSample>
+    {
+        if self.is_specular()
+        {
+            let direction = scatter::refractive_scatter(r, ray_info.normal, self.relative_index(ray_info), sampler);
+
+            return Some(ScatterSample { direction, weight: self.gain, pdf: None });
+        }
+
+        scatter::rough_dielectric_scatter(r, ray_info.normal, microfacet::alpha_from_roughness(self.roughness), self.relative_index(ray_info), self.gain, sampler)
+    }
+
+    fn evaluate(&self, r: Ray, ray_info: &RayInfo, direction: MyVec3) -> MyVec3
+    {
+        if self.is_specular()
+        {
+            return MyVec3 { x: 0.0, y: 0.0, z: 0.0 };
+        }
+
+        scatter::rough_dielectric_evaluate(r, ray_info.normal, microfacet::alpha_from_roughness(self.roughness), self.relative_index(ray_info), direction).0 * self.gain
+    }
+
+    fn pdf(&self, r: Ray, ray_info: &RayInfo, direction: MyVec3) -> f64
+    {
+        if self.is_specular()
+        {
+            return 0.0;
+        }
+
+        scatter::rough_dielectric_evaluate(r, ray_info.normal, microfacet::alpha_from_roughness(self.roughness), self.relative_index(ray_info), direction).1
+    }
+
+    fn is_specular(&self) -> bool
+    {
+        microfacet::alpha_from_roughness(self.roughness) < MIN_ALPHA
+    }
+
+    fn attenuation(&self, _ray_info: &RayInfo) -> MyVec3
+    {
+        self.gain
+    }
+
+    fn interior(&self) -> Option<Interior>
+    {
+        Some(Interior { index_of_refraction: self.index_of_refraction, absorption: self.absorption, priority: self.priority })
+    }
+}
+
+// Disney-style uber material combining diffuse, specular, transmission, clearcoat and sheen lobes (see principled.rs);
+// every parameter except the index of refraction is in [0, 1]. Never perfectly smooth, so lights are always sampled
+#[derive(Debug, Clone)]
+pub struct Principled
+{
+    pub base_colour:         MyVec3,
+    pub texture:             Option<SharedTexture>,     // Where present, replaces base_colour
+    pub metallic:            f64,
+    pub roughness:           f64,
+    pub specular:            f64,                       // Reflectance at normal incidence of the dielectric, 0.5 being 4%
+    pub transmission:        f64,
+    pub index_of_refraction: f64,
+    pub clearcoat:           f64,
+    pub clearcoat_roughness: f64,
+    pub sheen:               f64,
+    pub sheen_tint:          f64,                       // How far the sheen takes on the hue of the base colour
+    pub anisotropy:          f64
+}
+
+impl Default for Principled
+{
+    // A grey, fairly rough plastic
+    fn default() -> Principled
+    {
+        Principled { base_colour:         MyVec3 { x: 0.8, y: 0.8, z: 0.8 },
+                     texture:             None,
+                     metallic:            0.0,
+                     roughness:           0.5,
+                     specular:            0.5,
+                     transmission:        0.0,
+                     index_of_refraction: 1.5,
+                     clearcoat:           0.0,
+                     clearcoat_roughness: 0.03,
+                     sheen:               0.0,
+                     sheen_tint:          0.5,
+                     anisotropy:          0.0 }
+    }
+}
+
+impl Material for Principled
+{
+    fn sample(&self, r: Ray, ray_info: &RayInfo, sampler: &mut dyn Sampler) -> Option<ScatterSample>
+    {
+        scatter::principled_scatter(r, ray_info.normal, &PrincipledBsdf::new(self, self.attenuation(ray_info), ray_info.is_front), sampler)
+    }
+
+    fn evaluate(&self, r: Ray, ray_info: &RayInfo, direction: MyVec3) -> MyVec3
+    {
+        scatter::principled_evaluate(r, ray_info.normal, &PrincipledBsdf::new(self, self.attenuation(ray_info), ray_info.is_front), direction).0
+    }
+
+    fn pdf(&self, r: Ray, ray_info: &RayInfo, direction: MyVec3) -> f64
+    {
+        scatter::principled_evaluate(r, ray_info.normal, &PrincipledBsdf::new(self, self.attenuation(ray_info), ray_info.is_front), direction).1
+    }
+
+    fn is_specular(&self) -> bool
+    {
+        false
+    }
+
+    fn attenuation(&self, ray_info: &RayInfo) -> MyVec3
+    {
+        gain_at(self.base_colour, &self.texture, ray_info)
+    }
+}
+
+// Light source; absorbs all incoming light, ending the path, and emits strength times colour
+#[derive(Debug, Clone)]
+pub struct Emissive
+{
+    pub colour:   MyVec3,
+    pub strength: f64
+}
+
+impl Material for Emissive
+{
+    fn sample(&self, _r: Ray, _ray_info: &RayInfo, _sampler: &mut dyn Sampler) -> Option<ScatterSample>
+    {
+        None
+    }
+
+    fn emission(&self) -> Option<MyVec3>
+    {
+        Some(self.strength * self.colour)
+    }
+
+    fn attenuation(&self, _ray_info: &RayInfo) -> MyVec3
+    {
+        MyVec3 { x: 0.0, y: 0.0, z: 0.0 }
+    }
+}
+
+// Invisible surface enclosing a medium; rays pass straight through, entering or leaving the medium
+#[derive(Debug, Clone)]
+pub struct MediumBoundary
+{
+    pub medium: Medium
+}
+
+impl Material for MediumBoundary
+{
+    fn sample(&self, r: Ray, _ray_info: &RayInfo, _sampler: &mut dyn Sampler) -> Option<ScatterSample>
+    {
+        Some(ScatterSample { direction: r.direction, weight: MyVec3 { x: 1.0, y: 1.0, z: 1.0 }, pdf: None })
+    }
+
+    fn attenuation(&self, _ray_info: &RayInfo) -> MyVec3
+    {
+        MyVec3 { x: 1.0, y: 1.0, z: 1.0 }
+    }
+
+    fn medium(&self) -> Option<&Medium>
+    {
+        Some(&self.medium)
+    }
+}
+
+
+// Material of RayInfo::default(), i.e. of rays which hit nothing
+pub static DEFAULT_MATERIAL: Diffuse = Diffuse{gain: MyVec3 {x: 0.0, y: 0.0, z: 0.0}, texture: None};
 
-pub const GLASS:              Material = Material{surface: ScatteringType::RefractiveScattering, gain: MyVec3 {x: 1.0, y: 1.0, z: 1.0}, metal_fuzz:      None, index_of_refraction: Some(1.5)};
-pub const PERFECT_REFLECTION: Material = Material{surface: ScatteringType::MetallicScattering,   gain: MyVec3 {x: 1.0, y: 1.0, z: 1.0}, metal_fuzz: Some(0.0), index_of_refraction: None};
-pub const YELLOW_TINT:        Material = Material{surface: ScatteringType::DiffuseScattering,    gain: MyVec3 {x: 0.7, y: 0.5, z: 0.2}, metal_fuzz:      None, index_of_refraction: None};
-pub const PURE_RED:           Material = Material{surface: ScatteringType::DiffuseScattering,    gain: MyVec3 {x: 1.0, y: 0.0, z: 0.0}, metal_fuzz:      None, index_of_refraction: None};
-pub const PURE_GREEN:         Material = Material{surface: ScatteringType::DiffuseScattering,    gain: MyVec3 {x: 0.0, y: 1.0, z: 0.0}, metal_fuzz:      None, index_of_refraction: None};
-pub const PURE_BLUE:          Material = Material{surface: ScatteringType::DiffuseScattering,    gain: MyVec3 {x: 0.0, y: 0.0, z: 1.0}, metal_fuzz:      None, index_of_refraction: None};
-pub const NEUTRAL_GREY:       Material = Material{surface: ScatteringType::DiffuseScattering,    gain: MyVec3 {x: 0.5, y: 0.5, z: 1.5}, metal_fuzz:      None, index_of_refraction: None};
\ No newline at end of file
+pub const GLASS:              Refractive = Refractive{gain: MyVec3 {x: 1.0, y: 1.0, z: 1.0}, index_of_refraction: 1.5, roughness: 0.0, absorption: MyVec3 {x: 0.0, y: 0.0, z: 0.0}, priority: 0};
+pub const PERFECT_REFLECTION: Metallic   = Metallic  {gain: MyVec3 {x: 1.0, y: 1.0, z: 1.0}, texture: None, roughness: 0.0, conductor: None};
+pub const YELLOW_TINT:        Diffuse    = Diffuse   {gain: MyVec3 {x: 0.7, y: 0.5, z: 0.2}, texture: None};
+pub const PURE_RED:           Diffuse    = Diffuse   {gain: MyVec3 {x: 1.0, y: 0.0, z: 0.0}, texture: None};
+pub const PURE_GREEN:         Diffuse    = Diffuse   {gain: MyVec3 {x: 0.0, y: 1.0, z: 0.0}, texture: None};
+pub const PURE_BLUE:          Diffuse    = Diffuse   {gain: MyVec3 {x: 0.0, y: 0.0, z: 1.0}, texture: None};
+pub const NEUTRAL_GREY:       Diffuse    = Diffuse   {gain: MyVec3 {x: 0.5, y: 0.5, z: 1.5}, texture: None};
@@ -0,0 +1,276 @@
+// Participating media: volumes of particles (smoke, fog, cloud) which scatter light inside them rather than at a surface
+//
+// A medium of constant density fills the inside of any closed object whose material is a medium material; the surface
+// itself is invisible and only marks where rays enter and leave. The scene may also be filled with fog, the medium
+// outside every such object. The density is the chance per unit distance of a ray hitting a particle, so the
+// distance a ray travels before scattering is exponentially distributed; at a particle a fraction albedo of the light
+// is scattered, into a direction chosen by the phase function, and the rest is absorbed.
+//
+// The density of a heterogeneous medium varies from place to place, following a voxel grid. Distances are then
+// sampled by delta tracking: tentative collisions are placed as if the whole medium had the grid's maximum density
+// (the majorant), and each is accepted as a real collision with probability density / majorant, otherwise the ray
+// carries on (a null collision). Transmittance is estimated by ratio tracking, the product of the probabilities of
+// passing each tentative collision; both are unbiased however coarse the majorant, though a majorant far above the
+// typical density takes many steps.
+
+use std::{f64::consts::PI, sync::Arc};
+
+use crate::{my_vec3::{MyVec3, vec3_normalize, vec3_orthonormal_basis}, my_matrix4::MyMatrix4, voxel_grid::VoxelGrid, sampler::Sampler, world_instance::Transform};
+
+// Distribution of the direction of scattered light about the direction the light was travelling
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PhaseFunction
+{
+    Isotropic,                  // Equally in all directions
+    HenyeyGreenstein(f64)       // Anisotropy g in (-1, 1), the mean cosine of the scattering angle: g > 0 forward, g < 0 back
+}
+
+impl PhaseFunction
+{
+    // Anisotropy zero is isotropic scattering
+    pub fn new(anisotropy: f64) -> Result<PhaseFunction, String>
+    {
+        if !(anisotropy > -1.0 && anisotropy < 1.0)
+        {
+            return Err(format!("The anisotropy must be between -1 and 1 (exclusive), not {}", anisotropy));
+        }
+
+        Ok(if anisotropy == 0.0 {PhaseFunction::Isotropic} else {PhaseFunction::HenyeyGreenstein(anisotropy)})
+    }
+
+    // Density (with respect to solid angle) of light travelling along incoming being scattered into outgoing; this is
+    // also the density with which sample chooses outgoing
+    pub fn value(&self, incoming: MyVec3, outgoing: MyVec3) -> f64
+    {
+        match *self
+        {
+            PhaseFunction::Isotropic => 0.25 / PI,
+            PhaseFunction::HenyeyGreenstein(g) =>
+            {
+                let cos_theta   = vec3_normalize(incoming).dot(vec3_normalize(outgoing));
+                let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+
+                0.25 / PI * (1.0 - g * g) / (denominator * f64::sqrt(denominator))
+            }
+        }
+    }
+
+    // Choose the direction of the scattered light from two uniform random numbers (a unit vector)
+    pub fn sample(&self, incoming: MyVec3, u: (f64, f64)) -> MyVec3
+    {
+        // Inverting the cumulative distribution of the cosine of the scattering angle
+        let cos_theta = match *self
+        {
+            PhaseFunction::Isotropic => 1.0 - 2.0 * u.0,
+            PhaseFunction::HenyeyGreenstein(g) =>
+            {
+                let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u.0);
+
+                f64::clamp((1.0 + g * g - s * s) / (2.0 * g), -1.0, 1.0)
+            }
+        };
+
+        let sin_theta = f64::sqrt(f64::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi       = 2.0 * PI * u.1;
+        let w         = vec3_normalize(incoming);
+        let (a, b)    = vec3_orthonormal_basis(w);
+
+        (sin_theta * f64::cos(phi)) * a + (sin_theta * f64::sin(phi)) * b + cos_theta * w
+    }
+}
+
+// Chance per unit distance of hitting a particle
+#[derive(Debug, Clone)]
+pub enum Density
+{
+    Constant(f64),
+    Grid { grid: Arc<VoxelGrid>, scale: f64, to_grid: MyMatrix4 }     // scale times the grid's value, to_grid from world co-ordinates
+}
+
+#[derive(Debug, Clone)]
+pub struct Medium
+{
+    pub density: Density,
+    pub albedo:  MyVec3,        // Fraction of the light scattered (rather than absorbed) by a particle
+    pub phase:   PhaseFunction
+}
+
+impl Medium
+{
+    pub fn new(density: f64, albedo: MyVec3, anisotropy: f64) -> Result<Medium, String>
+    {
+        if !(density > 0.0 && density.is_finite())
+        {
+            return Err(format!("The density must be greater than zero, not {}", density));
+        }
+
+        Ok(Medium { density: Density::Constant(density), albedo, phase: PhaseFunction::new(anisotropy)? })
+    }
+
+    // Density scale times the grid's values, the grid placed in the world by transform
+    pub fn from_grid(grid: Arc<VoxelGrid>, scale: f64, transform: &Transform, albedo: MyVec3, anisotropy: f64) -> Result<Medium, String>
+    {
+        if !(scale > 0.0 && scale.is_finite())
+        {
+            return Err(format!("The density must be greater than zero, not {}", scale));
+        }
+
+        Ok(Medium { density: Density::Grid { grid, scale, to_grid: transform.to_object }, albedo, phase: PhaseFunction::new(anisotropy)? })
+    }
+
+    // Distance along the unit vector direction from origin to the first particle hit; None if the ray would get
+    // further than max_distance (the next surface), which happens with probability equal to the transmittance
+    pub fn sample_distance(&self, origin: MyVec3, direction: MyVec3, max_distance: f64, sampler: &mut dyn Sampler) -> Option<f64>
+    {
+        match &self.density
+        {
+            Density::Constant(density) =>
+            {
+                let distance = -f64::ln(1.0 - sampler.next_1d()) / density;
+
+                if distance < max_distance {Some(distance)} else {None}
+            }
+            Density::Grid { grid, scale, to_grid } =>
+            {
+                let majorant = scale * grid.max_value();
+
+                if majorant <= 0.0
+                {
+                    return None;
+                }
+
+                let mut distance = 0.0;
+
+                // Delta tracking
+                loop
+                {
+                    distance -= f64::ln(1.0 - sampler.next_1d()) / majorant;
+
+                    if distance >= max_distance
+                    {
+                        return None;
+                    }
+
+                    let density = scale * grid.density_at(to_grid.transform_point(origin + distance * direction));
+
+                    if sampler.next_1d() * majorant < density
+                    {
+                        return Some(distance);
+                    }
+                }
+            }
+        }
+    }
+
+    // Fraction of the light which travels distance along the unit vector direction from origin without hitting a
+    // particle (for a grid, an unbiased random estimate of it)
+    pub fn transmittance(&self, origin: MyVec3, direction: MyVec3, distance: f64, sampler: &mut dyn Sampler) -> f64
+    {
+        match &self.density
+        {
+            Density::Constant(density) => f64::exp(-density * distance),
+            Density::Grid { grid, scale, to_grid } =>
+            {
+                let majorant = scale * grid.max_value();
+
+                if majorant <= 0.0
+                {
+                    return 1.0;
+                }
+
+                let mut transmittance = 1.0;
+                let mut t             = 0.0;
+
+                // Ratio tracking
+                loop
+                {
+                    t -= f64::ln(1.0 - sampler.next_1d()) / majorant;
+
+                    if t >= distance
+                    {
+                        return transmittance;
+                    }
+
+                    transmittance *= 1.0 - scale * grid.density_at(to_grid.transform_point(origin + t * direction)) / majorant;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::sampler::SamplerType;
+
+    // The mean cosine of the sampled scattering angle is the anisotropy, and the directions are sampled with density
+    // value: the mean of 1 / value over the sampled directions is then the area of the sphere
+    #[test]
+    fn henyey_greenstein_sampling_matches_value()
+    {
+        let incoming = MyVec3{x: 0.3, y: -1.0, z: 0.2};
+        let n        = 200;
+
+        for g in [-0.6, 0.0, 0.3, 0.9]
+        {
+            let phase = PhaseFunction::new(g).unwrap();
+
+            let mut mean_cos = 0.0;
+            let mut area     = 0.0;
+
+            for i in 0..n
+            {
+                for j in 0..n
+                {
+                    let u         = ((i as f64 + 0.5) / n as f64, (j as f64 + 0.5) / n as f64);
+                    let direction = phase.sample(incoming, u);
+
+                    mean_cos += vec3_normalize(incoming).dot(direction) / (n * n) as f64;
+                    area     += 1.0 / (phase.value(incoming, direction) * (n * n) as f64);
+                }
+            }
+
+            assert!((phase.sample(incoming, (0.3, 0.7)).length() - 1.0).abs() < 1e-12);
+            assert!((mean_cos - g).abs() < 1e-3, "g = {}: mean cosine {}", g, mean_cos);
+            assert!((area / (4.0 * PI) - 1.0).abs() < 1e-2, "g = {}: area {}", g, area);
+        }
+    }
+
+    // Through a grid whose density varies from a to b along x, the mean of the ratio tracking estimates and the fraction
+    // of rays which delta tracking lets through are both the transmittance, exp(-(a + b) / 2) across the unit cube
+    #[test]
+    fn tracking_estimates_transmittance()
+    {
+        let grid      = Arc::new(VoxelGrid::new([2, 1, 1], vec![0.25, 1.0]).unwrap());
+        let transform = Transform::new(MyMatrix4::identity()).unwrap();
+        let medium    = Medium::from_grid(grid, 2.0, &transform, MyVec3{x: 1.0, y: 1.0, z: 1.0}, 0.0).unwrap();
+
+        let origin    = MyVec3{x: -0.5, y: 0.1, z: -0.2};
+        let direction = MyVec3{x: 1.0, y: 0.0, z: 0.0};
+        let expected  = f64::exp(-2.0 * (0.25 + 1.0) / 2.0);
+
+        let mut sampler = SamplerType::Independent.create(1, 1);
+        let n           = 20000;
+
+        let mut ratio_tracking = 0.0;
+        let mut escaped        = 0;
+
+        for i in 0..n
+        {
+            sampler.start_sample(0, i);
+
+            ratio_tracking += medium.transmittance(origin, direction, 1.0, sampler.as_mut()) / n as f64;
+
+            if medium.sample_distance(origin, direction, 1.0, sampler.as_mut()).is_none()
+            {
+                escaped += 1;
+            }
+        }
+
+        let delta_tracking = escaped as f64 / n as f64;
+
+        assert!((ratio_tracking - expected).abs() < 0.01, "ratio tracking {} expected {}", ratio_tracking, expected);
+        assert!((delta_tracking - expected).abs() < 0.015, "delta tracking {} expected {}", delta_tracking, expected);
+    }
+}
@@ -0,0 +1,441 @@
+// Microfacet reflection and refraction (GGX / Trowbridge-Reitz)
+//
+// A rough surface is modelled as a great many tiny mirror facets whose normals are spread about the surface normal by
+// the GGX distribution D with width alpha. Light reflects or refracts at a single facet, and is lost where other
+// facets shadow or mask it (the Smith G terms). Directions are chosen by sampling the facet normals which are visible
+// from the incoming direction (Heitz 2018), so that almost every sample is useful, and the throughput weight of a
+// sample is then just the Fresnel factor times G2 / G1.
+//
+// Vectors here are in the local frame of the shading normal (z along the normal) and point away from the surface:
+// wo towards where the ray came from, wi towards where the light comes from.
+
+use std::f64::consts::PI;
+
+use crate::my_vec3::{MyVec3, vec3_normalize};
+
+// Below this width the surface is treated as perfectly smooth (a single mirror direction)
+pub const MIN_ALPHA: f64 = 1e-3;
+
+// Width of the facet distribution from the perceptual roughness in [0, 1]
+pub fn alpha_from_roughness(roughness: f64) -> f64
+{
+    let roughness = f64::clamp(roughness, 0.0, 1.0);
+
+    roughness * roughness
+}
+
+// The fuzz of the old metallic material (a random offset within a ball of radius fuzz added to the mirror direction)
+// spreads the reflected directions about as much as GGX with alpha = 0.3 * fuzz (matching the median angle of the spread)
+pub fn roughness_from_fuzz(fuzz: f64) -> f64
+{
+    f64::sqrt(0.3 * f64::max(fuzz, 0.0))
+}
+
+// Real and imaginary parts of the refractive index of a conductor, per red, green and blue
+#[derive(Debug, Copy, Clone)]
+pub struct Conductor
+{
+    pub eta: MyVec3,
+    pub k:   MyVec3
+}
+
+// Measured values at about 650, 550 and 450 nm
+pub const CONDUCTOR_PRESETS: [(&str, Conductor); 4] = [
+    ("gold",      Conductor { eta: MyVec3 { x: 0.143, y: 0.374, z: 1.442 }, k: MyVec3 { x: 3.983, y: 2.385, z: 1.603 } }),
+    ("copper",    Conductor { eta: MyVec3 { x: 0.200, y: 0.924, z: 1.102 }, k: MyVec3 { x: 3.912, y: 2.452, z: 2.142 } }),
+    ("aluminium", Conductor { eta: MyVec3 { x: 1.657, y: 0.880, z: 0.521 }, k: MyVec3 { x: 9.224, y: 6.270, z: 4.837 } }),
+    ("silver",    Conductor { eta: MyVec3 { x: 0.155, y: 0.117, z: 0.138 }, k: MyVec3 { x: 4.828, y: 3.122, z: 2.147 } }),
+];
+
+impl Conductor
+{
+    pub fn preset(name: &str) -> Option<Conductor>
+    {
+        CONDUCTOR_PRESETS.iter().find(|(preset_name, _)| *preset_name == name).map(|(_, conductor)| *conductor)
+    }
+
+    // Fraction of unpolarised light reflected, from the cosine of the angle of incidence
+    pub fn fresnel(&self, cos_i: f64) -> MyVec3
+    {
+        MyVec3 { x: fresnel_conductor(cos_i, self.eta.x, self.k.x),
+                 y: fresnel_conductor(cos_i, self.eta.y, self.k.y),
+                 z: fresnel_conductor(cos_i, self.eta.z, self.k.z) }
+    }
+}
+
+// Exact Fresnel reflectance of a conductor with complex refractive index eta + ik (relative to the outside)
+pub fn fresnel_conductor(cos_i: f64, eta: f64, k: f64) -> f64
+{
+    let cos2 = f64::clamp(cos_i * cos_i, 0.0, 1.0);
+    let sin2 = 1.0 - cos2;
+
+    let t0    = eta * eta - k * k - sin2;
+    let a2_b2 = f64::sqrt(t0 * t0 + 4.0 * eta * eta * k * k);
+    let a     = f64::sqrt(f64::max(0.0, 0.5 * (a2_b2 + t0)));
+    let t1    = a2_b2 + cos2;
+    let t2    = 2.0 * f64::abs(cos_i) * a;
+    let rs    = (t1 - t2) / (t1 + t2);
+    let t3    = cos2 * a2_b2 + sin2 * sin2;
+    let t4    = t2 * sin2;
+    let rp    = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rs + rp)
+}
+
+// Exact Fresnel reflectance of unpolarised light at a dielectric, eta being the ratio of the refractive index on the far
+// side to that on the side the light arrives from; 1 for total internal reflection
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64
+{
+    let cos_i  = f64::clamp(cos_i, 0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+
+    if sin2_t >= 1.0
+    {
+        return 1.0;
+    }
+
+    let cos_t = f64::sqrt(1.0 - sin2_t);
+
+    let r_parallel      = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+// Schlick's approximation, for surfaces described only by their reflectance f0 at normal incidence
+pub fn fresnel_schlick(cos_i: f64, f0: MyVec3) -> MyVec3
+{
+    let w = f64::powi(1.0 - f64::clamp(cos_i, 0.0, 1.0), 5);
+
+    (1.0 - w) * f0 + w * MyVec3 { x: 1.0, y: 1.0, z: 1.0 }
+}
+
+pub fn reflect(w: MyVec3, h: MyVec3) -> MyVec3
+{
+    2.0 * w.dot(h) * h - w
+}
+
+// Direction of the light refracted through the facet with normal h (on the same side as w), eta as for
+// fresnel_dielectric; None for total internal reflection
+pub fn refract(w: MyVec3, h: MyVec3, eta: f64) -> Option<MyVec3>
+{
+    let cos_i  = w.dot(h);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+
+    if sin2_t >= 1.0
+    {
+        return None;
+    }
+
+    let cos_t = f64::sqrt(1.0 - sin2_t);
+
+    Some((-1.0 / eta) * w + (cos_i / eta - cos_t) * h)
+}
+
+// Facet distribution with widths alpha_x and alpha_y along the x and y axes of the local frame (equal for an isotropic
+// surface)
+#[derive(Debug, Copy, Clone)]
+pub struct Ggx
+{
+    pub alpha_x: f64,
+    pub alpha_y: f64
+}
+
+impl Ggx
+{
+    pub fn new(alpha: f64) -> Ggx
+    {
+        Ggx { alpha_x: alpha, alpha_y: alpha }
+    }
+
+    // Stretched along x and narrowed along y by anisotropy in [0, 1] (as in the Disney BRDF), keeping the mean width
+    // about alpha; never narrower than MIN_ALPHA
+    pub fn anisotropic(alpha: f64, anisotropy: f64) -> Ggx
+    {
+        let aspect = f64::sqrt(1.0 - 0.9 * f64::clamp(anisotropy, 0.0, 1.0));
+
+        Ggx { alpha_x: f64::max(alpha / aspect, MIN_ALPHA), alpha_y: f64::max(alpha * aspect, MIN_ALPHA) }
+    }
+
+    pub fn is_smooth(&self) -> bool
+    {
+        self.alpha_x < MIN_ALPHA && self.alpha_y < MIN_ALPHA
+    }
+
+    // Density of facet normals (per unit projected area of the surface)
+    pub fn d(&self, h: MyVec3) -> f64
+    {
+        if h.z <= 0.0
+        {
+            return 0.0;
+        }
+
+        let t = h.x * h.x / (self.alpha_x * self.alpha_x) + h.y * h.y / (self.alpha_y * self.alpha_y) + h.z * h.z;
+
+        1.0 / (PI * self.alpha_x * self.alpha_y * t * t)
+    }
+
+    fn lambda(&self, w: MyVec3) -> f64
+    {
+        let alpha2_tan2 = (self.alpha_x * self.alpha_x * w.x * w.x + self.alpha_y * self.alpha_y * w.y * w.y) / (w.z * w.z);
+
+        0.5 * (f64::sqrt(1.0 + alpha2_tan2) - 1.0)
+    }
+
+    // Fraction of the facets facing w which are visible from w
+    pub fn g1(&self, w: MyVec3) -> f64
+    {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Fraction visible from both wo and wi (height-correlated)
+    pub fn g2(&self, wo: MyVec3, wi: MyVec3) -> f64
+    {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of the facet normals visible from wo, as chosen by sample_visible_normal
+    pub fn visible_normal_pdf(&self, wo: MyVec3, h: MyVec3) -> f64
+    {
+        self.g1(wo) * f64::max(0.0, wo.dot(h)) * self.d(h) / f64::abs(wo.z)
+    }
+
+    // Choose a facet normal visible from wo (wo.z > 0) from two uniform random numbers
+    pub fn sample_visible_normal(&self, wo: MyVec3, u: (f64, f64)) -> MyVec3
+    {
+        // Stretch so that the facets become a hemisphere, sample the projected hemisphere, and unstretch
+        let v = vec3_normalize(MyVec3 { x: self.alpha_x * wo.x, y: self.alpha_y * wo.y, z: wo.z });
+
+        let t1 = if v.z < 0.9999 {vec3_normalize(MyVec3 { x: -v.y, y: v.x, z: 0.0 })} else {MyVec3 { x: 1.0, y: 0.0, z: 0.0 }};
+        let t2 = v.cross(t1);
+
+        let r   = f64::sqrt(u.0);
+        let phi = 2.0 * PI * u.1;
+        let p1  = r * f64::cos(phi);
+        let s   = 0.5 * (1.0 + v.z);
+        let p2  = (1.0 - s) * f64::sqrt(1.0 - p1 * p1) + s * r * f64::sin(phi);
+        let p3  = f64::sqrt(f64::max(0.0, 1.0 - p1 * p1 - p2 * p2));
+
+        let n = p1 * t1 + p2 * t2 + p3 * v;
+
+        vec3_normalize(MyVec3 { x: self.alpha_x * n.x, y: self.alpha_y * n.y, z: f64::max(1e-9, n.z) })
+    }
+
+    // For a dielectric with relative index eta (as for fresnel_dielectric), the BSDF times the cosine factor and the
+    // density of wi when the facet is chosen by sample_visible_normal and then reflection or refraction in proportion to
+    // the Fresnel reflectance; wi above the surface is reflected, below refracted. As with smooth glass, refraction does
+    // not scale the radiance by the square of the relative index
+    pub fn dielectric(&self, wo: MyVec3, wi: MyVec3, eta: f64) -> (f64, f64)
+    {
+        let reflection = wi.z > 0.0;
+
+        // The facet which turns wo into wi, on the same side as the normal
+        let mut h = if reflection {wo + wi} else {eta * wi + wo};
+
+        if h.squared_length() == 0.0
+        {
+            return (0.0, 0.0);
+        }
+
+        h = vec3_normalize(h);
+        h = if h.z < 0.0 {-1.0 * h} else {h};
+
+        let cos_o = wo.dot(h);
+        let cos_i = wi.dot(h);
+
+        // Facets seen from behind by either direction contribute nothing
+        if cos_o <= 0.0 || cos_i * wi.z <= 0.0
+        {
+            return (0.0, 0.0);
+        }
+
+        let fresnel = fresnel_dielectric(cos_o, eta);
+        let d       = self.d(h);
+        let g2      = self.g2(wo, wi);
+
+        if reflection
+        {
+            (fresnel * d * g2 / (4.0 * wo.z), fresnel * self.visible_normal_pdf(wo, h) / (4.0 * cos_o))
+        }
+        else
+        {
+            let denominator = cos_i + cos_o / eta;
+            let dh_dwi      = f64::abs(cos_i) / (denominator * denominator);
+
+            ((1.0 - fresnel) * d * g2 * cos_o * dh_dwi / wo.z, (1.0 - fresnel) * self.visible_normal_pdf(wo, h) * dh_dwi)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // The distribution of normals covers the surface once (D integrates to one over projected area), the visible
+    // normals' density integrates to one, and the mean of a function of the sampled normals matches its integral
+    // against that density
+    #[test]
+    fn visible_normals_match_pdf()
+    {
+        for (ggx, wo) in [(Ggx::new(0.5), MyVec3 { x: 0.3, y: -0.2, z: 0.9 }), (Ggx::new(0.2), MyVec3 { x: 0.8, y: 0.1, z: 0.3 }),
+                          (Ggx::new(0.9), MyVec3 { x: 0.0, y: 0.0, z: 1.0 }), (Ggx::anisotropic(0.4, 0.8), MyVec3 { x: 0.5, y: 0.4, z: 0.6 })]
+        {
+            let wo = vec3_normalize(wo);
+            let n   = 500;
+
+            let mut projected_area = 0.0;
+            let mut pdf_integral   = 0.0;
+            let mut mean_integral  = MyVec3 { x: 0.0, y: 0.0, z: 0.0 };
+
+            for i in 0..n
+            {
+                for j in 0..n
+                {
+                    let theta = 0.5 * PI * (i as f64 + 0.5) / n as f64;
+                    let phi   = 2.0 * PI * (j as f64 + 0.5) / n as f64;
+                    let h     = MyVec3 { x: f64::sin(theta) * f64::cos(phi), y: f64::sin(theta) * f64::sin(phi), z: f64::cos(theta) };
+                    let d_w   = f64::sin(theta) * (0.5 * PI / n as f64) * (2.0 * PI / n as f64);
+                    let pdf   = ggx.visible_normal_pdf(wo, h);
+
+                    projected_area += ggx.d(h) * h.z * d_w;
+                    pdf_integral   += pdf * d_w;
+                    mean_integral   = mean_integral + (pdf * d_w) * h;
+                }
+            }
+
+            let mut mean_sampled = MyVec3 { x: 0.0, y: 0.0, z: 0.0 };
+
+            for i in 0..n
+            {
+                for j in 0..n
+                {
+                    let h = ggx.sample_visible_normal(wo, ((i as f64 + 0.5) / n as f64, (j as f64 + 0.5) / n as f64));
+
+                    assert!(h.z > 0.0 && wo.dot(h) > -1e-9 && (h.length() - 1.0).abs() < 1e-9);
+
+                    mean_sampled = mean_sampled + (1.0 / (n * n) as f64) * h;
+                }
+            }
+
+            assert!((projected_area - 1.0).abs() < 1e-2, "{:?}: projected area {}", ggx, projected_area);
+            assert!((pdf_integral - 1.0).abs() < 1e-2, "{:?}: pdf integral {}", ggx, pdf_integral);
+            assert!((mean_sampled - mean_integral).length() < 1e-2, "{:?}: mean {:?} != {:?}", ggx, mean_sampled, mean_integral);
+        }
+    }
+
+    // Choosing a visible facet, then reflection or refraction in proportion to the Fresnel reflectance, gives
+    // directions with the density returned by dielectric: its integral over the sphere is the fraction of the choices
+    // which do not go the wrong side of the surface. The weight of each direction is G2 / G1
+    #[test]
+    fn dielectric_pdf_matches_sampling()
+    {
+        for (alpha, eta) in [(0.3, 1.5), (0.5, 1.0 / 1.5), (0.8, 1.33)]
+        {
+            let ggx = Ggx::new(alpha);
+            let wo  = vec3_normalize(MyVec3 { x: 0.4, y: 0.1, z: 0.8 });
+            let n   = 800;
+
+            let mut integral = 0.0;
+
+            for i in 0..n
+            {
+                for j in 0..n
+                {
+                    let theta = PI * (i as f64 + 0.5) / n as f64;
+                    let phi   = 2.0 * PI * (j as f64 + 0.5) / n as f64;
+                    let wi    = MyVec3 { x: f64::sin(theta) * f64::cos(phi), y: f64::sin(theta) * f64::sin(phi), z: f64::cos(theta) };
+
+                    let (f_cos, pdf) = ggx.dielectric(wo, wi, eta);
+
+                    if pdf > 0.0
+                    {
+                        assert!((f_cos / pdf - ggx.g2(wo, wi) / ggx.g1(wo)).abs() < 1e-9);
+                    }
+
+                    integral += pdf * f64::sin(theta) * (PI / n as f64) * (2.0 * PI / n as f64);
+                }
+            }
+
+            let n_facets  = 300;
+            let n_choices = 8;
+
+            let mut kept = 0;
+
+            for i in 0..n_facets
+            {
+                for j in 0..n_facets
+                {
+                    let h       = ggx.sample_visible_normal(wo, ((i as f64 + 0.5) / n_facets as f64, (j as f64 + 0.5) / n_facets as f64));
+                    let fresnel = fresnel_dielectric(wo.dot(h), eta);
+
+                    for k in 0..n_choices
+                    {
+                        let reflected = (k as f64 + 0.5) / (n_choices as f64) < fresnel;
+                        let wi        = if reflected {Some(reflect(wo, h))} else {refract(wo, h, eta)};
+
+                        if wi.is_some_and(|wi| (wi.z > 0.0) == reflected)
+                        {
+                            kept += 1;
+                        }
+                    }
+                }
+            }
+
+            let sampled = kept as f64 / (n_facets * n_facets * n_choices) as f64;
+
+            assert!((integral - sampled).abs() < 0.01, "alpha {}, eta {}: integral {} != {}", alpha, eta, integral, sampled);
+        }
+    }
+
+    #[test]
+    fn fresnel_limits()
+    {
+        // Glass: 4% at normal incidence, total internal reflection from inside beyond the critical angle
+        assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-12);
+        assert_eq!(fresnel_dielectric(0.5, 1.0 / 1.5), 1.0);
+
+        // A conductor with k = 0 is a dielectric
+        for cos_i in [1.0, 0.7, 0.2]
+        {
+            assert!((fresnel_conductor(cos_i, 1.5, 0.0) - fresnel_dielectric(cos_i, 1.5)).abs() < 1e-9);
+        }
+
+        // Everything reflects at grazing incidence
+        assert!((fresnel_conductor(0.0, 0.2, 3.9) - 1.0).abs() < 1e-9);
+    }
+
+    // Reflectance of unpolarised light against values worked from the Fresnel equations by hand
+    #[test]
+    fn dielectric_fresnel_known_values()
+    {
+        // Normal incidence: ((n - 1) / (n + 1))^2, the same from either side
+        for (eta, expected) in [(1.5, 0.04), (1.33, 0.020059), (2.42, 0.172395)]
+        {
+            assert!((fresnel_dielectric(1.0, eta) - expected).abs() < 1e-6, "eta {}: {}", eta, fresnel_dielectric(1.0, eta));
+            assert!((fresnel_dielectric(1.0, 1.0 / eta) - expected).abs() < 1e-6);
+        }
+
+        // At Brewster's angle (tan = 1.5) only the perpendicular polarisation is reflected
+        let brewster = f64::atan(1.5);
+        assert!((fresnel_dielectric(f64::cos(brewster), 1.5) - 0.073964).abs() < 1e-6);
+
+        // 45 degrees into glass
+        assert!((fresnel_dielectric(f64::cos(PI / 4.0), 1.5) - 0.050240).abs() < 1e-6);
+
+        // Towards grazing incidence everything is reflected, and nothing passes between equal indices
+        assert!(fresnel_dielectric(1e-4, 1.5) > 0.999);
+        assert_eq!(fresnel_dielectric(0.0, 1.5), 1.0);
+        assert!(fresnel_dielectric(0.3, 1.0).abs() < 1e-12);
+
+        // Light refracted from one side at one angle is reflected as much as light coming back the other way
+        let cos_i = 0.6;
+        let cos_t = refract(MyVec3 { x: 0.8, y: 0.0, z: cos_i }, MyVec3 { x: 0.0, y: 0.0, z: 1.0 }, 1.5).unwrap().z.abs();
+        assert!((fresnel_dielectric(cos_i, 1.5) - fresnel_dielectric(cos_t, 1.0 / 1.5)).abs() < 1e-12);
+
+        // Total internal reflection from inside glass beyond the critical angle (sin = 1 / 1.5)
+        let critical = f64::asin(1.0 / 1.5);
+        assert_eq!(fresnel_dielectric(f64::cos(critical + 0.01), 1.0 / 1.5), 1.0);
+        assert!(fresnel_dielectric(f64::cos(critical - 0.01), 1.0 / 1.5) < 1.0);
+    }
+}
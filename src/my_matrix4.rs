@@ -0,0 +1,187 @@
+use std::ops;
+
+use crate::my_vec3::{MyVec3, vec3_normalize};
+
+// 4x4 matrix acting on column vectors (p' = M p), stored by rows
+// Points are (x, y, z, 1) and are translated, directions are (x, y, z, 0) and are not
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MyMatrix4 {
+    pub m: [[f64; 4]; 4]
+}
+
+impl MyMatrix4 {
+    pub fn identity() -> MyMatrix4
+    {
+        MyMatrix4::scaling(MyVec3{x: 1.0, y: 1.0, z: 1.0})
+    }
+
+    pub fn translation(t: MyVec3) -> MyMatrix4
+    {
+        MyMatrix4 { m: [[1.0, 0.0, 0.0, t.x],
+                        [0.0, 1.0, 0.0, t.y],
+                        [0.0, 0.0, 1.0, t.z],
+                        [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    pub fn scaling(s: MyVec3) -> MyMatrix4
+    {
+        MyMatrix4 { m: [[s.x, 0.0, 0.0, 0.0],
+                        [0.0, s.y, 0.0, 0.0],
+                        [0.0, 0.0, s.z, 0.0],
+                        [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    // Rotation by angle (radians) about axis, anticlockwise looking down the axis towards the origin (Rodrigues' formula)
+    pub fn rotation(axis: MyVec3, angle: f64) -> MyMatrix4
+    {
+        let a = vec3_normalize(axis);
+        let s = f64::sin(angle);
+        let c = f64::cos(angle);
+        let t = 1.0 - c;
+
+        MyMatrix4 { m: [[t * a.x * a.x + c,       t * a.x * a.y - s * a.z, t * a.x * a.z + s * a.y, 0.0],
+                        [t * a.x * a.y + s * a.z, t * a.y * a.y + c,       t * a.y * a.z - s * a.x, 0.0],
+                        [t * a.x * a.z - s * a.y, t * a.y * a.z + s * a.x, t * a.z * a.z + c,       0.0],
+                        [0.0,                     0.0,                     0.0,                     1.0]] }
+    }
+
+    pub fn transpose(&self) -> MyMatrix4
+    {
+        let mut m = [[0.0; 4]; 4];
+
+        for (i, row) in m.iter_mut().enumerate()
+        {
+            for (j, value) in row.iter_mut().enumerate()
+            {
+                *value = self.m[j][i];
+            }
+        }
+
+        MyMatrix4 { m }
+    }
+
+    // Gauss-Jordan elimination with partial pivoting; None if the matrix is singular (e.g. a scale of zero)
+    pub fn inverse(&self) -> Option<MyMatrix4>
+    {
+        let mut a       = self.m;
+        let mut inverse = MyMatrix4::identity().m;
+
+        for column in 0..4
+        {
+            let pivot = (column..4).max_by(|&i, &j| f64::abs(a[i][column]).total_cmp(&f64::abs(a[j][column])))?;
+
+            if f64::abs(a[pivot][column]) < 1e-12
+            {
+                return None;
+            }
+
+            a.swap(column, pivot);
+            inverse.swap(column, pivot);
+
+            let recip_pivot = 1.0 / a[column][column];
+
+            for j in 0..4
+            {
+                a[column][j]       *= recip_pivot;
+                inverse[column][j] *= recip_pivot;
+            }
+
+            for i in 0..4
+            {
+                let factor = a[i][column];
+
+                if i != column && factor != 0.0
+                {
+                    for j in 0..4
+                    {
+                        a[i][j]       -= factor * a[column][j];
+                        inverse[i][j] -= factor * inverse[column][j];
+                    }
+                }
+            }
+        }
+
+        Some(MyMatrix4 { m: inverse })
+    }
+
+    // The bottom row is (0, 0, 0, 1), so the matrix maps points to points without a perspective divide
+    pub fn is_affine(&self) -> bool
+    {
+        self.m[3] == [0.0, 0.0, 0.0, 1.0]
+    }
+
+    pub fn transform_point(&self, p: MyVec3) -> MyVec3
+    {
+        let m = &self.m;
+
+        MyVec3{x: m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+               y: m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+               z: m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3]}
+    }
+
+    pub fn transform_vector(&self, v: MyVec3) -> MyVec3
+    {
+        let m = &self.m;
+
+        MyVec3{x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+               y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+               z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z}
+    }
+}
+
+
+impl ops::Mul for MyMatrix4
+{
+    type Output = Self;
+
+    // Applying the product applies rhs first, then self
+    fn mul(self, rhs: Self) -> Self
+    {
+        let mut m = [[0.0; 4]; 4];
+
+        for (i, row) in m.iter_mut().enumerate()
+        {
+            for (j, value) in row.iter_mut().enumerate()
+            {
+                *value = (0..4).map(|k| self.m[i][k] * rhs.m[k][j]).sum();
+            }
+        }
+
+        Self { m }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn assert_close(a: MyVec3, b: MyVec3)
+    {
+        assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn inverse_undoes_transform()
+    {
+        let matrix = MyMatrix4::translation(MyVec3{x: 1.0, y: -2.0, z: 3.0})
+                   * MyMatrix4::rotation(MyVec3{x: 1.0, y: 1.0, z: 0.0}, 0.7)
+                   * MyMatrix4::scaling(MyVec3{x: 2.0, y: 0.5, z: -3.0});
+
+        let inverse = matrix.inverse().unwrap();
+        let p       = MyVec3{x: 0.3, y: 4.0, z: -1.5};
+
+        assert_close(inverse.transform_point(matrix.transform_point(p)), p);
+        assert_close(inverse.transform_vector(matrix.transform_vector(p)), p);
+        assert!(MyMatrix4::scaling(MyVec3{x: 1.0, y: 0.0, z: 1.0}).inverse().is_none());
+    }
+
+    #[test]
+    fn rotation_is_anticlockwise()
+    {
+        let rotation = MyMatrix4::rotation(MyVec3{x: 0.0, y: 0.0, z: 1.0}, std::f64::consts::FRAC_PI_2);
+
+        assert_close(rotation.transform_vector(MyVec3{x: 1.0, y: 0.0, z: 0.0}), MyVec3{x: 0.0, y: 1.0, z: 0.0});
+        assert_close(rotation.transform_point(MyVec3{x: 0.0, y: 1.0, z: 5.0}), MyVec3{x: -1.0, y: 0.0, z: 5.0});
+    }
+}
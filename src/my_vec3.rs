@@ -1,6 +1,8 @@
 
 use std::ops;
 
+use rand::Rng;
+
 use crate::common::{uniform_random, random_in_interval};
 
 //
@@ -23,6 +25,7 @@ impl MyVec3 {
         f64::sqrt(self.squared_length())
     }
 
+    #[allow(clippy::assign_op_pattern)]
     pub fn normalize(&mut self)
     {
         let recip_length = 1.0 / self.length();
@@ -48,6 +51,7 @@ impl ops::Add for MyVec3
 {
     type Output = Self;
 
+    #[allow(clippy::needless_return)]
     fn add(self, rhs: Self) -> Self 
     {
         return Self {x: self.x + rhs.x, y: self.y + rhs.y, z: self.z + rhs.z}
@@ -58,6 +62,7 @@ impl ops::Sub for MyVec3
 {
     type Output = Self;
 
+    #[allow(clippy::needless_return)]
     fn sub(self, rhs: Self) -> Self 
     {
         return Self {x: self.x - rhs.x, y: self.y - rhs.y, z: self.z - rhs.z}
@@ -68,6 +73,7 @@ impl ops::Mul for MyVec3
 {
     type Output = Self;
 
+    #[allow(clippy::needless_return)]
     fn mul(self, rhs: Self) -> Self 
     {
         return Self {x: self.x * rhs.x, y: self.y * rhs.y, z: self.z * rhs.z}
@@ -79,6 +85,7 @@ impl ops::Div<f64> for MyVec3
 {
     type Output = Self;
 
+    #[allow(clippy::needless_return)]
     fn div(self, rhs: f64) -> Self 
     {
         return Self {x: self.x / rhs, y: self.y / rhs, z: self.z / rhs}
@@ -90,6 +97,7 @@ impl ops::Mul<MyVec3> for f64
 {
     type Output = MyVec3;
 
+    #[allow(clippy::needless_return)]
     fn mul(self, rhs: MyVec3) -> MyVec3 
     {
         return MyVec3 {x: rhs.x * self, y: rhs.y * self, z: rhs.z * self};
@@ -97,15 +105,15 @@ impl ops::Mul<MyVec3> for f64
 }
 
 
-pub fn random_vec3() -> MyVec3
+pub fn random_vec3(rng: &mut impl Rng) -> MyVec3
 {
-    MyVec3{x: uniform_random(), y: uniform_random(), z: uniform_random()}
+    MyVec3{x: uniform_random(rng), y: uniform_random(rng), z: uniform_random(rng)}
 }
 
 
-pub fn random_in_interval_vec3(min: f64, max: f64) -> MyVec3
+pub fn random_in_interval_vec3(rng: &mut impl Rng, min: f64, max: f64) -> MyVec3
 {
-    MyVec3{x: random_in_interval(min, max), y: random_in_interval(min, max), z: random_in_interval(min, max)}
+    MyVec3{x: random_in_interval(rng, min, max), y: random_in_interval(rng, min, max), z: random_in_interval(rng, min, max)}
 }
 
 
@@ -113,3 +121,14 @@ pub fn vec3_normalize(v: MyVec3) -> MyVec3
 {
     MyVec3 {x: v.x / v.length(), y: v.y / v.length(), z: v.z / v.length()}
 }
+
+
+// Two unit vectors which, together with the unit vector n, form a right-handed orthonormal basis (Duff et al. 2017)
+pub fn vec3_orthonormal_basis(n: MyVec3) -> (MyVec3, MyVec3)
+{
+    let sign = if n.z >= 0.0 {1.0} else {-1.0};
+    let a    = -1.0 / (sign + n.z);
+    let b    = n.x * n.y * a;
+
+    (MyVec3{x: 1.0 + sign * n.x * n.x * a, y: sign * b, z: -sign * n.x}, MyVec3{x: b, y: sign + n.y * n.y * a, z: -n.y})
+}
@@ -0,0 +1,700 @@
+// Wavefront OBJ (and MTL material library) loader
+//
+// Supported OBJ statements:
+//     v x y z [w]        position (w is ignored)
+//     vt u [v [w]]       texture co-ordinate
+//     vn x y z           normal
+//     f a b c ...        face; each vertex is p, p/t, p//n or p/t/n, polygons are fan-triangulated
+//                        negative indices are relative to the end of the list read so far
+//     g / o name         group / object, each starts a new mesh
+//     usemtl name        material for the following faces, starts a new mesh
+//     mtllib file ...    material libraries, relative to the directory of the OBJ file
+// Other statements (s, l, p, vp, ...) are ignored
+//
+// Supported MTL statements: newmtl, Kd, Ks, Ke, Ns, Ni, d, Tr, Tf, illum, map_Kd, and the PBR extension's Pr, Pm, Ps,
+// Pc, Pcr and aniso (all others ignored)
+// map_Kd images are relative to the directory of the MTL file and replace Kd (options such as -s are not supported)
+// A material with any of the PBR statements becomes a principled material with Kd as its base colour, 1 - d as its
+// transmission, and the mean of Ks as its specular level (as written by Blender); there is no statement for the sheen
+// tint, which keeps its default. Otherwise the illumination model chooses between diffuse, metallic and refractive
+
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::Arc};
+
+use crate::{my_vec3::MyVec3, material::{SharedMaterial, Diffuse, Metallic, Refractive, Emissive, Principled}, microfacet, world_mesh::WETriangleMesh, texture::{SharedTexture, ImageTexture}};
+
+// Material used for faces which precede any usemtl statement (MTL default Kd is 0.8)
+const DEFAULT_MATERIAL: Diffuse = Diffuse{gain: MyVec3 {x: 0.8, y: 0.8, z: 0.8}, texture: None};
+
+// Load every face in the OBJ file; one mesh is returned per group and material
+pub fn load_obj(path: &Path) -> Result<Vec<WETriangleMesh>, String>
+{
+    let file_name = path.display().to_string();
+    let contents  = fs::read_to_string(path).map_err(|e| format!("{}: Unable to read OBJ file: {}", file_name, e))?;
+
+    let mut positions: Vec<MyVec3>     = vec![];
+    let mut uvs:       Vec<(f64, f64)> = vec![];
+    let mut normals:   Vec<MyVec3>     = vec![];
+
+    let mut materials: HashMap<String, SharedMaterial> = HashMap::new();
+    let mut builder = MeshBuilder::new(Arc::new(DEFAULT_MATERIAL));
+    let mut meshes  = vec![];
+
+    for (line_index, line) in contents.lines().enumerate()
+    {
+        let line_number = line_index + 1;
+        let error       = |message: String| format!("{}:{}: {}", file_name, line_number, message);
+
+        let line = strip_comment(line);
+        let mut tokens = line.split_whitespace();
+
+        let keyword = match tokens.next()
+        {
+            Some(keyword) => keyword,
+            None          => continue,
+        };
+
+        let arguments: Vec<&str> = tokens.collect();
+
+        match keyword
+        {
+            "v" =>
+            {
+                let [x, y, z] = parse_floats::<3>(&arguments, 3, 4).map_err(error)?;
+                positions.push(MyVec3 { x, y, z });
+            }
+            "vt" =>
+            {
+                let [u, v] = parse_floats::<2>(&arguments, 1, 3).map_err(error)?;
+                uvs.push((u, v));
+            }
+            "vn" =>
+            {
+                let [x, y, z] = parse_floats::<3>(&arguments, 3, 3).map_err(error)?;
+                normals.push(MyVec3 { x, y, z });
+            }
+            "f" =>
+            {
+                if arguments.len() < 3
+                {
+                    return Err(error(format!("Face has {} vertices, at least 3 are required", arguments.len())));
+                }
+
+                let mut face = Vec::with_capacity(arguments.len());
+
+                for argument in &arguments
+                {
+                    face.push(parse_face_vertex(argument, positions.len(), uvs.len(), normals.len()).map_err(error)?);
+                }
+
+                // Fan triangulation about the first vertex (exact for convex polygons)
+                for n in 1..face.len() - 1
+                {
+                    let triangle = [builder.vertex(face[0], &positions, &uvs, &normals),
+                                    builder.vertex(face[n], &positions, &uvs, &normals),
+                                    builder.vertex(face[n + 1], &positions, &uvs, &normals)];
+
+                    builder.indices.push(triangle);
+                }
+            }
+            "g" | "o" =>
+            {
+                let material = builder.material.clone();
+                meshes.extend(builder.finish().map_err(error)?);
+                builder = MeshBuilder::new(material);
+            }
+            "usemtl" =>
+            {
+                let name = arguments.join(" ");
+                let material = match materials.get(&name)
+                {
+                    Some(material) => material.clone(),
+                    None           => return Err(error(format!("Material '{}' is not defined in any material library (mtllib must precede usemtl)", name))),
+                };
+
+                meshes.extend(builder.finish().map_err(error)?);
+                builder = MeshBuilder::new(material);
+            }
+            "mtllib" =>
+            {
+                if arguments.is_empty()
+                {
+                    return Err(error("mtllib requires at least one file name".to_string()));
+                }
+
+                let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+                for library in &arguments
+                {
+                    materials.extend(load_mtl(&directory.join(library)).map_err(|e| error(format!("In material library referenced here: {}", e)))?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    meshes.extend(builder.finish().map_err(|e| format!("{}: {}", file_name, e))?);
+
+    if meshes.is_empty()
+    {
+        return Err(format!("{}: OBJ file contains no faces", file_name));
+    }
+
+    Ok(meshes)
+}
+
+// Load every material in an MTL file, keyed by name
+pub fn load_mtl(path: &Path) -> Result<HashMap<String, SharedMaterial>, String>
+{
+    let file_name = path.display().to_string();
+    let contents  = fs::read_to_string(path).map_err(|e| format!("{}: Unable to read MTL file: {}", file_name, e))?;
+
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlProperties)> = None;
+
+    // Images used by several materials are only loaded once
+    let mut images: HashMap<PathBuf, SharedTexture> = HashMap::new();
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    for (line_index, line) in contents.lines().enumerate()
+    {
+        let line_number = line_index + 1;
+        let error       = |message: String| format!("{}:{}: {}", file_name, line_number, message);
+
+        let line = strip_comment(line);
+        let mut tokens = line.split_whitespace();
+
+        let keyword = match tokens.next()
+        {
+            Some(keyword) => keyword,
+            None          => continue,
+        };
+
+        let arguments: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl"
+        {
+            if let Some((name, properties)) = current.take()
+            {
+                materials.insert(name, properties.to_material());
+            }
+
+            if arguments.is_empty()
+            {
+                return Err(error("newmtl requires a material name".to_string()));
+            }
+
+            current = Some((arguments.join(" "), MtlProperties::default()));
+            continue;
+        }
+
+        let properties = match &mut current
+        {
+            Some((_, properties)) => properties,
+            None if matches!(keyword, "Kd" | "Ks" | "Ke" | "Ns" | "Ni" | "d" | "Tr" | "Tf" | "illum" | "map_Kd" | "Pr" | "Pm" | "Ps" | "Pc" | "Pcr" | "aniso") => return Err(error(format!("'{}' appears before any newmtl statement", keyword))),
+            None => continue,
+        };
+
+        match keyword
+        {
+            "Kd" => properties.kd = parse_colour(&arguments).map_err(error)?,
+            "Ks" => properties.ks = Some(parse_colour(&arguments).map_err(error)?),
+            "Ke" => properties.ke = parse_colour(&arguments).map_err(error)?,
+            "Tf" => properties.tf = Some(parse_colour(&arguments).map_err(error)?),
+            "Ns" => properties.ns = parse_floats::<1>(&arguments, 1, 1).map_err(error)?[0],
+            "Ni" => properties.ni = Some(parse_floats::<1>(&arguments, 1, 1).map_err(error)?[0]),
+            "d"  => properties.d  = parse_floats::<1>(&arguments, 1, 1).map_err(error)?[0],
+            "Tr" => properties.d  = 1.0 - parse_floats::<1>(&arguments, 1, 1).map_err(error)?[0],
+            "Pr"    => properties.pbr.roughness           = parse_fraction(&arguments).map_err(error)?,
+            "Pm"    => properties.pbr.metallic            = parse_fraction(&arguments).map_err(error)?,
+            "Ps"    => properties.pbr.sheen               = parse_fraction(&arguments).map_err(error)?,
+            "Pc"    => properties.pbr.clearcoat           = parse_fraction(&arguments).map_err(error)?,
+            "Pcr"   => properties.pbr.clearcoat_roughness = parse_fraction(&arguments).map_err(error)?,
+            "aniso" => properties.pbr.anisotropy          = parse_fraction(&arguments).map_err(error)?,
+            "map_Kd" =>
+            {
+                if arguments.len() != 1
+                {
+                    return Err(error(format!("map_Kd requires a single file name (options are not supported), found '{}'", arguments.join(" "))));
+                }
+
+                let image_path = directory.join(arguments[0]);
+
+                let texture = match images.get(&image_path)
+                {
+                    Some(texture) => Arc::clone(texture),
+                    None =>
+                    {
+                        let texture: SharedTexture = Arc::new(ImageTexture::load(&image_path).map_err(error)?);
+                        images.insert(image_path, Arc::clone(&texture));
+                        texture
+                    }
+                };
+
+                properties.map_kd = Some(texture);
+            }
+            "illum" =>
+            {
+                properties.illum = match arguments.first().map(|a| a.parse::<u32>())
+                {
+                    Some(Ok(illum)) if arguments.len() == 1 => illum,
+                    _ => return Err(error(format!("illum requires a single integer argument, found '{}'", arguments.join(" ")))),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    if let Some((name, properties)) = current.take()
+    {
+        materials.insert(name, properties.to_material());
+    }
+
+    Ok(materials)
+}
+
+// Raw MTL values, converted into one of the available scattering types once the whole material has been read
+struct MtlProperties
+{
+    kd:     MyVec3,
+    ks:     Option<MyVec3>,
+    ke:     MyVec3,
+    tf:     Option<MyVec3>,
+    ns:     f64,
+    ni:     Option<f64>,
+    d:      f64,
+    illum:  u32,
+    map_kd: Option<SharedTexture>,
+    pbr:    PbrProperties
+}
+
+// Values of the PBR extension's statements, None where not given
+#[derive(Default)]
+struct PbrProperties
+{
+    roughness:           Option<f64>,
+    metallic:            Option<f64>,
+    sheen:               Option<f64>,
+    clearcoat:           Option<f64>,
+    clearcoat_roughness: Option<f64>,
+    anisotropy:          Option<f64>
+}
+
+impl PbrProperties
+{
+    fn is_empty(&self) -> bool
+    {
+        [self.roughness, self.metallic, self.sheen, self.clearcoat, self.clearcoat_roughness, self.anisotropy].iter().all(Option::is_none)
+    }
+}
+
+impl Default for MtlProperties
+{
+    fn default() -> Self
+    {
+        MtlProperties { kd: DEFAULT_MATERIAL.gain, ks: None, ke: MyVec3::default(), tf: None, ns: 0.0, ni: None, d: 1.0, illum: 2, map_kd: None, pbr: PbrProperties::default() }
+    }
+}
+
+impl MtlProperties
+{
+    fn to_material(&self) -> SharedMaterial
+    {
+        let max_component = |c: MyVec3| f64::max(f64::max(c.x, c.y), c.z);
+
+        // Light source
+        if max_component(self.ke) > 0.0
+        {
+            return Arc::new(Emissive { colour: self.ke, strength: 1.0 });
+        }
+
+        // Principled (any of the PBR statements)
+        if !self.pbr.is_empty()
+        {
+            let default = Principled::default();
+
+            return Arc::new(Principled { base_colour:         self.kd,
+                                         texture:             self.map_kd.clone(),
+                                         metallic:            self.pbr.metallic.unwrap_or(default.metallic),
+                                         roughness:           self.pbr.roughness.unwrap_or(default.roughness),
+                                         specular:            self.ks.map_or(default.specular, |ks| f64::clamp((ks.x + ks.y + ks.z) / 3.0, 0.0, 1.0)),
+                                         transmission:        f64::clamp(1.0 - self.d, 0.0, 1.0),
+                                         index_of_refraction: self.ni.unwrap_or(default.index_of_refraction),
+                                         clearcoat:           self.pbr.clearcoat.unwrap_or(default.clearcoat),
+                                         clearcoat_roughness: self.pbr.clearcoat_roughness.unwrap_or(default.clearcoat_roughness),
+                                         sheen:               self.pbr.sheen.unwrap_or(default.sheen),
+                                         anisotropy:          self.pbr.anisotropy.unwrap_or(default.anisotropy),
+                                         ..default });
+        }
+
+        // Transparent (d < 1, or one of the refraction illumination models)
+        if self.d < 1.0 || matches!(self.illum, 4 | 6 | 7 | 9)
+        {
+            let gain = self.tf.unwrap_or(MyVec3 { x: 1.0, y: 1.0, z: 1.0 });
+
+            return Arc::new(Refractive { gain, index_of_refraction: self.ni.unwrap_or(1.5), roughness: 0.0, absorption: MyVec3::default(), priority: 0 });
+        }
+
+        let ks = self.ks.unwrap_or_default();
+
+        // Reflective (ray traced reflection illumination models, with a specular colour which dominates the diffuse colour)
+        if matches!(self.illum, 3 | 5 | 8) && max_component(ks) > 0.0 && max_component(ks) >= max_component(self.kd)
+        {
+            // The Phong exponent is mapped to a fuzz extent with a similar angular spread, large exponents giving sharp reflections,
+            // and that to a roughness
+            let fuzz = f64::sqrt(2.0 / (self.ns + 2.0));
+
+            return Arc::new(Metallic { gain: ks, texture: None, roughness: microfacet::roughness_from_fuzz(fuzz), conductor: None });
+        }
+
+        Arc::new(Diffuse { gain: self.kd, texture: self.map_kd.clone() })
+    }
+}
+
+// Collects the faces of one group/material, de-duplicating the (position, texture, normal) index triples of the OBJ
+// file into the single-index vertices used by WETriangleMesh
+struct MeshBuilder
+{
+    material:     SharedMaterial,
+    vertex_map:   HashMap<FaceVertex, usize>,
+    positions:    Vec<MyVec3>,
+    uvs:          Vec<Option<(f64, f64)>>,
+    normals:      Vec<Option<MyVec3>>,
+    indices:      Vec<[usize; 3]>
+}
+
+// Zero-based indices into the position, texture co-ordinate and normal lists
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct FaceVertex
+{
+    position: usize,
+    uv:       Option<usize>,
+    normal:   Option<usize>
+}
+
+impl MeshBuilder
+{
+    fn new(material: SharedMaterial) -> MeshBuilder
+    {
+        MeshBuilder { material, vertex_map: HashMap::new(), positions: vec![], uvs: vec![], normals: vec![], indices: vec![] }
+    }
+
+    fn vertex(&mut self, face_vertex: FaceVertex, positions: &[MyVec3], uvs: &[(f64, f64)], normals: &[MyVec3]) -> usize
+    {
+        if let Some(&index) = self.vertex_map.get(&face_vertex)
+        {
+            return index;
+        }
+
+        let index = self.positions.len();
+
+        self.positions.push(positions[face_vertex.position]);
+        self.uvs.push(face_vertex.uv.map(|i| uvs[i]));
+        self.normals.push(face_vertex.normal.map(|i| normals[i]));
+        self.vertex_map.insert(face_vertex, index);
+
+        index
+    }
+
+    // Texture co-ordinates and normals are only kept if every vertex of the mesh has one
+    fn finish(self) -> Result<Option<WETriangleMesh>, String>
+    {
+        if self.indices.is_empty()
+        {
+            return Ok(None);
+        }
+
+        let uvs:     Option<Vec<(f64, f64)>> = self.uvs.into_iter().collect();
+        let normals: Option<Vec<MyVec3>>     = self.normals.into_iter().collect();
+
+        WETriangleMesh::new(self.positions, normals, uvs, self.indices, self.material).map(Some)
+    }
+}
+
+fn strip_comment(line: &str) -> &str
+{
+    match line.find('#')
+    {
+        Some(position) => &line[..position],
+        None           => line,
+    }
+}
+
+// Parse between min and max numbers, of which the first N are returned (missing values are zero)
+fn parse_floats<const N: usize>(arguments: &[&str], min: usize, max: usize) -> Result<[f64; N], String>
+{
+    if arguments.len() < min || arguments.len() > max
+    {
+        let expected = if min == max {format!("{}", min)} else {format!("{} to {}", min, max)};
+
+        return Err(format!("Expected {} numbers, found {}", expected, arguments.len()));
+    }
+
+    let mut values = [0.0; N];
+
+    for (n, argument) in arguments.iter().enumerate().take(N)
+    {
+        values[n] = argument.parse::<f64>().map_err(|_| format!("Expected a number, found '{}'", argument))?;
+    }
+
+    Ok(values)
+}
+
+// A single number between 0 and 1, as for the PBR statements
+fn parse_fraction(arguments: &[&str]) -> Result<Option<f64>, String>
+{
+    let value = parse_floats::<1>(arguments, 1, 1)?[0];
+
+    if !(0.0..=1.0).contains(&value)
+    {
+        return Err(format!("Expected a number between 0 and 1, found {}", value));
+    }
+
+    Ok(Some(value))
+}
+
+// Colours are either "r g b" or a single grey level
+fn parse_colour(arguments: &[&str]) -> Result<MyVec3, String>
+{
+    if arguments.first() == Some(&"spectral") || arguments.first() == Some(&"xyz")
+    {
+        return Err(format!("'{}' colours are not supported, use r g b", arguments[0]));
+    }
+
+    let [x, y, z] = parse_floats::<3>(arguments, 1, 3)?;
+
+    if arguments.len() == 1
+    {
+        return Ok(MyVec3 { x, y: x, z: x });
+    }
+    if arguments.len() == 2
+    {
+        return Err("Expected 1 or 3 colour components, found 2".to_string());
+    }
+
+    Ok(MyVec3 { x, y, z })
+}
+
+// One vertex of a face: p, p/t, p//n or p/t/n with 1-based (or negative, relative) indices
+fn parse_face_vertex(argument: &str, number_of_positions: usize, number_of_uvs: usize, number_of_normals: usize) -> Result<FaceVertex, String>
+{
+    let mut parts = argument.split('/');
+
+    let position = resolve_index(parts.next().unwrap_or(""), number_of_positions, "Position", argument)?;
+
+    let uv = match parts.next()
+    {
+        None | Some("") => None,
+        Some(part)      => Some(resolve_index(part, number_of_uvs, "Texture co-ordinate", argument)?),
+    };
+
+    let normal = match parts.next()
+    {
+        None | Some("") => None,
+        Some(part)      => Some(resolve_index(part, number_of_normals, "Normal", argument)?),
+    };
+
+    if parts.next().is_some()
+    {
+        return Err(format!("Face vertex '{}' has too many components", argument));
+    }
+
+    Ok(FaceVertex { position, uv, normal })
+}
+
+// kind names the list indexed, starting with a capital as it begins the error messages
+fn resolve_index(index: &str, count: usize, kind: &str, argument: &str) -> Result<usize, String>
+{
+    let value = index.parse::<i64>().map_err(|_| format!("{} index '{}' in face vertex '{}' is not a number", kind, index, argument))?;
+
+    // 1-based from the start of the list, or negative from the end of the list (-1 is the most recent)
+    let resolved = if value > 0 {value - 1} else {count as i64 + value};
+
+    if value == 0 || resolved < 0 || resolved >= count as i64
+    {
+        return Err(format!("{} index {} in face vertex '{}' is out of range ({} defined so far)", kind, value, argument, count));
+    }
+
+    Ok(resolved as usize)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{common::TemporaryPath, material::Material};
+    use std::any::Any;
+
+    // Write files into a directory of their own, named after the test, and return the path of the first; the directory
+    // is removed when the TemporaryPath returned with it is dropped
+    fn write_files(test: &str, files: &[(&str, &str)]) -> (TemporaryPath, PathBuf)
+    {
+        let directory = TemporaryPath::new(&format!("obj_loader_{}", test));
+        fs::create_dir_all(&directory.0).unwrap();
+
+        for (name, contents) in files
+        {
+            fs::write(directory.0.join(name), contents).unwrap();
+        }
+
+        let path = directory.0.join(files[0].0);
+
+        (directory, path)
+    }
+
+    // The material, which must be of type T
+    fn downcast<T: Material>(material: &SharedMaterial) -> &T
+    {
+        let any: &dyn Any = material.as_ref();
+
+        any.downcast_ref::<T>().unwrap_or_else(|| panic!("{:?} is not a {}", material, std::any::type_name::<T>()))
+    }
+
+    fn components(c: MyVec3) -> [f64; 3]
+    {
+        [c.x, c.y, c.z]
+    }
+
+    // Negative indices count back from the most recent vertex read, so the same face can be written either way
+    #[test]
+    fn negative_indices()
+    {
+        let (_directory, path) = write_files("negative_indices", &[("model.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 0 0 1\nf 1 -3 -1\n")]);
+
+        let meshes = load_obj(&path).unwrap();
+
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].indices, vec![[0, 1, 2], [0, 1, 3]]);
+        assert_eq!(meshes[0].positions[3].z, 1.0);
+    }
+
+    // Quads and larger polygons become fans of triangles about their first vertex
+    #[test]
+    fn polygons_fan_triangulated()
+    {
+        let (_directory, path) = write_files("polygons_fan_triangulated", &[("model.obj", "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv -1 1 0\nf 1 2 3 4\ng pentagon\nf 1 2 3 4 5\n")]);
+
+        let meshes = load_obj(&path).unwrap();
+
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].indices, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(meshes[1].indices, vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+    }
+
+    // Each of the p, p/t, p//n and p/t/n forms of a face vertex, one group for each
+    #[test]
+    fn face_vertex_forms()
+    {
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvn 0 0 1\n\
+                   f 1 2 3\ng\nf 1/1 2/2 3/3\ng\nf 1//1 2//1 3//1\ng\nf 1/1/1 2/2/1 3/3/1\n";
+
+        let (_directory, path) = write_files("face_vertex_forms", &[("model.obj", obj)]);
+
+        let meshes = load_obj(&path).unwrap();
+
+        let forms: Vec<(bool, bool)> = meshes.iter().map(|mesh| (mesh.uvs.is_some(), mesh.normals.is_some())).collect();
+
+        assert_eq!(forms, vec![(false, false), (true, false), (false, true), (true, true)]);
+        assert_eq!(meshes[3].uvs.as_ref().unwrap()[1], (1.0, 0.0));
+        assert_eq!(meshes[3].normals.as_ref().unwrap()[2].z, 1.0);
+    }
+
+    // Bad indices are reported with the file and line of the face
+    #[test]
+    fn invalid_indices()
+    {
+        for (test, face, message) in [("index_zero",            "f 0 1 2",       "model.obj:4: Position index 0 in face vertex '0' is out of range (3 defined so far)"),
+                                      ("position_after_end",    "f 1 2 4",       "model.obj:4: Position index 4 in face vertex '4' is out of range (3 defined so far)"),
+                                      ("position_before_start", "f -4 1 2",      "model.obj:4: Position index -4 in face vertex '-4' is out of range (3 defined so far)"),
+                                      ("uv_after_end",          "f 1/1 2/1 3/2", "model.obj:4: Texture co-ordinate index 1 in face vertex '1/1' is out of range (0 defined so far)"),
+                                      ("normal_not_a_number",   "f 1//x 2 3",    "model.obj:4: Normal index 'x' in face vertex '1//x' is not a number")]
+        {
+            let (_directory, path) = write_files(test, &[("model.obj", &format!("v 0 0 0\nv 1 0 0\nv 0 1 0\n{}\n", face))]);
+            let error = load_obj(&path).err().unwrap();
+
+            assert!(error.ends_with(message), "{}", error);
+        }
+    }
+
+    // Materials must be loaded before they are used
+    #[test]
+    fn usemtl_before_mtllib()
+    {
+        let (_directory, path) = write_files("usemtl_before_mtllib", &[("model.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nmtllib model.mtl\nf 1 2 3\n"),
+                                                                     ("model.mtl", "newmtl red\nKd 1 0 0\n")]);
+
+        let error = load_obj(&path).err().unwrap();
+
+        assert!(error.ends_with("model.obj:4: Material 'red' is not defined in any material library (mtllib must precede usemtl)"), "{}", error);
+    }
+
+    // The illumination model (with d, Ks and Ke) chooses the kind of material
+    #[test]
+    fn mtl_illumination_models()
+    {
+        let mtl = "newmtl plain\nKd 0.5 0.5 0.5\nillum 2\n\
+                   newmtl mirror\nKd 0.1 0.1 0.1\nKs 0.9 0.9 0.9\nNs 100\nillum 3\n\
+                   newmtl dull_mirror\nKd 0.8 0.8 0.8\nKs 0.2 0.2 0.2\nillum 3\n\
+                   newmtl glass\nTf 0.9 1 0.9\nNi 1.4\nillum 7\n\
+                   newmtl faded\nKd 0.5 0.5 0.5\nd 0.5\n\
+                   newmtl lamp\nKe 4 4 4\nillum 2\n";
+
+        let (_directory, path) = write_files("mtl_illumination_models", &[("model.mtl", mtl)]);
+
+        let materials = load_mtl(&path).unwrap();
+
+        let plain = downcast::<Diffuse>(&materials["plain"]);
+
+        assert_eq!(components(plain.gain), [0.5; 3]);
+        assert!(plain.texture.is_none());
+
+        // The Phong exponent is converted to the roughness of a similar spread
+        let mirror = downcast::<Metallic>(&materials["mirror"]);
+
+        assert_eq!(components(mirror.gain), [0.9; 3]);
+        assert_eq!(mirror.roughness, microfacet::roughness_from_fuzz(f64::sqrt(2.0 / 102.0)));
+        assert!(mirror.texture.is_none() && mirror.conductor.is_none());
+
+        // Mostly diffuse, so the specular reflection is dropped
+        assert_eq!(components(downcast::<Diffuse>(&materials["dull_mirror"]).gain), [0.8; 3]);
+
+        for (name, gain, index_of_refraction) in [("glass", [0.9, 1.0, 0.9], 1.4), ("faded", [1.0; 3], 1.5)]
+        {
+            let refractive = downcast::<Refractive>(&materials[name]);
+
+            assert_eq!(components(refractive.gain), gain, "{}", name);
+            assert_eq!(refractive.index_of_refraction, index_of_refraction, "{}", name);
+            assert_eq!((refractive.roughness, components(refractive.absorption), refractive.priority), (0.0, [0.0; 3], 0), "{}", name);
+        }
+
+        let lamp = downcast::<Emissive>(&materials["lamp"]);
+
+        assert_eq!((components(lamp.colour), lamp.strength), ([4.0; 3], 1.0));
+    }
+
+    #[test]
+    fn mtl_pbr_material()
+    {
+        let (_directory, path) = write_files("mtl_pbr_material", &[("pbr.mtl", "newmtl pbr\nKd 0.8 0.4 0.2\nKs 0.25\nNi 1.45\nd 0.75\nPr 0.3\nPm 0.6\nPs 0.1\nPc 0.4\nPcr 0.05\naniso 0.7\n")]);
+
+        let materials = load_mtl(&path).unwrap();
+        let pbr       = downcast::<Principled>(&materials["pbr"]);
+
+        assert_eq!(components(pbr.base_colour), [0.8, 0.4, 0.2]);
+        assert!(pbr.texture.is_none());
+        assert_eq!([pbr.metallic, pbr.roughness, pbr.specular, pbr.transmission, pbr.index_of_refraction, pbr.clearcoat, pbr.clearcoat_roughness, pbr.sheen, pbr.anisotropy],
+                   [0.6, 0.3, 0.25, 0.25, 1.45, 0.4, 0.05, 0.1, 0.7]);
+
+        // There is no statement for the sheen tint
+        assert_eq!(pbr.sheen_tint, Principled::default().sheen_tint);
+    }
+
+    #[test]
+    fn mtl_pbr_fraction_out_of_range()
+    {
+        let (_directory, path) = write_files("mtl_pbr_fraction_out_of_range", &[("pbr.mtl", "newmtl pbr\nKd 0.8 0.4 0.2\nPr 2\n")]);
+
+        let error = load_mtl(&path).unwrap_err();
+
+        assert!(error.ends_with("pbr.mtl:3: Expected a number between 0 and 1, found 2"), "{}", error);
+    }
+}
@@ -0,0 +1,282 @@
+// Writing the rendered image to disk
+//
+// The renderer produces a linear floating point framebuffer (three f32 values per pixel, rows from the top of the
+// image). The file extension selects the encoder:
+//     .exr, .hdr, .pfm    floating point, the linear values are written unchanged
+//     .png                8 or 16 bits per channel
+//     .jpg, .jpeg         8 bits per channel
+// For the integer formats the values are converted for display by the tone mapping (exposure, white balance, operator,
+// and sRGB encoding); the floating point formats are left unmapped so that they can be graded later (load_linear_image)
+
+use std::{fs::{self, File}, io::{BufWriter, Write}, path::Path};
+
+use image::{ImageBuffer, Rgb, codecs::hdr::HdrEncoder};
+
+use crate::{my_vec3::MyVec3, tone_mapping::ToneMapping};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat
+{
+    OpenExr,
+    RadianceHdr,
+    Pfm,
+    Png,
+    Jpeg
+}
+
+impl OutputFormat
+{
+    pub fn from_path(path: &Path) -> Result<OutputFormat, String>
+    {
+        let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).unwrap_or_default();
+
+        match extension.as_str()
+        {
+            "exr"          => Ok(OutputFormat::OpenExr),
+            "hdr"          => Ok(OutputFormat::RadianceHdr),
+            "pfm"          => Ok(OutputFormat::Pfm),
+            "png"          => Ok(OutputFormat::Png),
+            "jpg" | "jpeg" => Ok(OutputFormat::Jpeg),
+            _              => Err(format!("{}: Unsupported output file extension '{}' (use exr, hdr, pfm, png, jpg, or jpeg)", path.display(), extension)),
+        }
+    }
+
+    pub fn is_high_dynamic_range(&self) -> bool
+    {
+        matches!(self, OutputFormat::OpenExr | OutputFormat::RadianceHdr | OutputFormat::Pfm)
+    }
+}
+
+// Write the framebuffer to path; png_bit_depth (8 or 16) is only used for PNG files
+pub fn save_image(path: &Path, width: u32, height: u32, framebuffer: &[f32], tone_mapping: &ToneMapping, png_bit_depth: u32) -> Result<(), String>
+{
+    let format = OutputFormat::from_path(path)?;
+    let error  = |e: String| format!("{}: Unable to write image: {}", path.display(), e);
+
+    match format
+    {
+        OutputFormat::OpenExr =>
+        {
+            let img: ImageBuffer<Rgb<f32>, Vec<f32>> = ImageBuffer::from_vec(width, height, framebuffer.to_vec()).ok_or_else(|| error("framebuffer size does not match image size".to_string()))?;
+
+            img.save(path).map_err(|e| error(e.to_string()))
+        }
+        OutputFormat::RadianceHdr =>
+        {
+            let pixels: Vec<Rgb<f32>> = framebuffer.chunks_exact(3).map(|p| Rgb([p[0], p[1], p[2]])).collect();
+            let file = File::create(path).map_err(|e| error(e.to_string()))?;
+
+            HdrEncoder::new(BufWriter::new(file)).encode(&pixels, width as usize, height as usize).map_err(|e| error(e.to_string()))
+        }
+        OutputFormat::Pfm => write_pfm(path, width, height, framebuffer).map_err(|e| error(e.to_string())),
+        OutputFormat::Png if png_bit_depth == 16 =>
+        {
+            let data: Vec<u16> = display_values(framebuffer, tone_mapping).map(|v| (65535.0 * v).round() as u16).collect();
+            let img: ImageBuffer<Rgb<u16>, Vec<u16>> = ImageBuffer::from_vec(width, height, data).ok_or_else(|| error("framebuffer size does not match image size".to_string()))?;
+
+            img.save(path).map_err(|e| error(e.to_string()))
+        }
+        OutputFormat::Png | OutputFormat::Jpeg =>
+        {
+            let data: Vec<u8> = display_values(framebuffer, tone_mapping).map(|v| (255.0 * v).round() as u8).collect();
+            let img: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_vec(width, height, data).ok_or_else(|| error("framebuffer size does not match image size".to_string()))?;
+
+            img.save(path).map_err(|e| error(e.to_string()))
+        }
+    }
+}
+
+// Display values in the range [0, 1], one per channel in framebuffer order
+fn display_values<'a>(framebuffer: &'a [f32], tone_mapping: &'a ToneMapping) -> impl Iterator<Item = f64> + 'a
+{
+    framebuffer.chunks_exact(3).flat_map(move |p|
+    {
+        let display = tone_mapping.display(MyVec3{x: p[0] as f64, y: p[1] as f64, z: p[2] as f64});
+
+        [display.x, display.y, display.z]
+    })
+}
+
+// Read a linear floating point image (EXR, HDR, or PFM) previously written by save_image
+// Returns (width, height, framebuffer)
+pub fn load_linear_image(path: &Path) -> Result<(u32, u32, Vec<f32>), String>
+{
+    let error = |e: String| format!("{}: Unable to read image: {}", path.display(), e);
+
+    match OutputFormat::from_path(path)?
+    {
+        OutputFormat::OpenExr | OutputFormat::RadianceHdr =>
+        {
+            let img = image::open(path).map_err(|e| error(e.to_string()))?.to_rgb32f();
+
+            Ok((img.width(), img.height(), img.into_raw()))
+        }
+        OutputFormat::Pfm => read_pfm(&fs::read(path).map_err(|e| error(e.to_string()))?).map_err(error),
+        _ => Err(format!("{}: Only linear images (exr, hdr, or pfm) can be re-graded", path.display())),
+    }
+}
+
+// Portable float map: a short text header followed by little-endian f32 RGB triples, rows from the bottom of the image
+// (a negative scale in the header indicates little-endian data)
+fn write_pfm(path: &Path, width: u32, height: u32, framebuffer: &[f32]) -> std::io::Result<()>
+{
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+
+    let row_length = (3 * width) as usize;
+
+    for row in framebuffer.chunks_exact(row_length).rev()
+    {
+        for value in row
+        {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    writer.flush()
+}
+
+fn read_pfm(data: &[u8]) -> Result<(u32, u32, Vec<f32>), String>
+{
+    // Header: three whitespace separated tokens after the "PF" magic, then a single whitespace character
+    let mut tokens   = Vec::new();
+    let mut position = 0;
+
+    while tokens.len() < 4
+    {
+        while position < data.len() && data[position].is_ascii_whitespace()
+        {
+            position += 1;
+        }
+
+        let start = position;
+
+        while position < data.len() && !data[position].is_ascii_whitespace()
+        {
+            position += 1;
+        }
+
+        if start == position
+        {
+            return Err("truncated PFM header".to_string());
+        }
+
+        tokens.push(String::from_utf8_lossy(&data[start..position]).into_owned());
+    }
+
+    position += 1;
+
+    if tokens[0] != "PF"
+    {
+        return Err(format!("'{}' is not a colour PFM file (expected PF)", tokens[0]));
+    }
+
+    let width:  u32 = tokens[1].parse().map_err(|_| format!("invalid PFM width '{}'", tokens[1]))?;
+    let height: u32 = tokens[2].parse().map_err(|_| format!("invalid PFM height '{}'", tokens[2]))?;
+    let scale:  f32 = tokens[3].parse().map_err(|_| format!("invalid PFM scale '{}'", tokens[3]))?;
+
+    let row_length = 3 * width as usize;
+    let pixels     = data.get(position..).unwrap_or_default();
+
+    if pixels.len() < 4 * row_length * height as usize
+    {
+        return Err(format!("PFM data is too short for a {}x{} image", width, height));
+    }
+
+    let values: Vec<f32> = pixels.chunks_exact(4)
+                                 .take(row_length * height as usize)
+                                 .map(|b| if scale < 0.0 {f32::from_le_bytes([b[0], b[1], b[2], b[3]])} else {f32::from_be_bytes([b[0], b[1], b[2], b[3]])})
+                                 .collect();
+
+    // Rows are stored bottom to top
+    let framebuffer = values.chunks_exact(row_length).rev().flatten().copied().collect();
+
+    Ok((width, height, framebuffer))
+}
+
+// Heat map of the number of samples taken for each pixel, from black (no samples) through purple, red, and orange to
+// pale yellow (max_samples); only 8-bit formats are supported
+pub fn save_sample_map(path: &Path, width: u32, height: u32, sample_counts: &[u32], max_samples: u32) -> Result<(), String>
+{
+    if OutputFormat::from_path(path)?.is_high_dynamic_range()
+    {
+        return Err(format!("{}: The sample map must be a png or jpg file", path.display()));
+    }
+
+    const RAMP: [[f64; 3]; 5] = [[0.0, 0.0, 0.02], [0.34, 0.06, 0.38], [0.73, 0.21, 0.33], [0.98, 0.55, 0.04], [0.99, 1.0, 0.64]];
+
+    let data: Vec<u8> = sample_counts.iter().flat_map(|&count|
+    {
+        let position = (RAMP.len() - 1) as f64 * f64::clamp(count as f64 / f64::max(max_samples as f64, 1.0), 0.0, 1.0);
+        let index    = usize::min(position as usize, RAMP.len() - 2);
+        let w        = position - index as f64;
+
+        [0, 1, 2].map(|c| (255.0 * ((1.0 - w) * RAMP[index][c] + w * RAMP[index + 1][c])).round() as u8)
+    }).collect();
+
+    let img: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_vec(width, height, data).ok_or_else(|| format!("{}: sample counts do not match image size", path.display()))?;
+
+    img.save(path).map_err(|e| format!("{}: Unable to write image: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::common::TemporaryPath;
+
+    // Rows are written bottom first, as little-endian floats after a header with a negative scale
+    #[test]
+    fn pfm_round_trip()
+    {
+        let file        = TemporaryPath::new("output_pfm_round_trip.pfm");
+        let framebuffer = (0..18).map(|n| n as f32 * 0.25 - 1.0).collect::<Vec<f32>>();
+
+        save_image(&file.0, 2, 3, &framebuffer, &ToneMapping::default(), 8).unwrap();
+
+        let data   = fs::read(&file.0).unwrap();
+        let header = b"PF\n2 3\n-1.0\n";
+
+        assert_eq!(&data[..header.len()], header);
+        assert_eq!(data.len(), header.len() + 4 * 18);
+
+        let first_value = f32::from_le_bytes(data[header.len()..header.len() + 4].try_into().unwrap());
+
+        assert_eq!(first_value, framebuffer[12]);
+        assert_eq!(load_linear_image(&file.0).unwrap(), (2, 3, framebuffer));
+    }
+
+    // A positive scale marks big-endian data
+    #[test]
+    fn pfm_big_endian()
+    {
+        let mut data = b"PF\n1 2\n1.0\n".to_vec();
+
+        for value in [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0]
+        {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+
+        assert_eq!(read_pfm(&data).unwrap(), (1, 2, vec![4.0, 5.0, 6.0, 1.0, 2.0, 3.0]));
+    }
+
+    #[test]
+    fn format_from_extension()
+    {
+        for (name, format) in [("a.exr", OutputFormat::OpenExr), ("a.hdr", OutputFormat::RadianceHdr), ("a.pfm", OutputFormat::Pfm),
+                               ("a.png", OutputFormat::Png), ("a.jpg", OutputFormat::Jpeg), ("a.JPEG", OutputFormat::Jpeg)]
+        {
+            assert_eq!(OutputFormat::from_path(Path::new(name)), Ok(format));
+        }
+
+        assert_eq!(OutputFormat::from_path(Path::new("a.tiff")), Err("a.tiff: Unsupported output file extension 'tiff' (use exr, hdr, pfm, png, jpg, or jpeg)".to_string()));
+        assert!(OutputFormat::from_path(Path::new("image")).is_err());
+
+        // Nothing is written for an unknown extension
+        let file = TemporaryPath::new("output_format_from_extension.bmp");
+
+        assert!(save_image(&file.0, 1, 1, &[0.5, 0.5, 0.5], &ToneMapping::default(), 8).is_err());
+        assert!(!file.0.exists());
+    }
+}
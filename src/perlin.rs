@@ -0,0 +1,108 @@
+// Perlin (gradient) noise, as described in Ray Tracing the Next Week
+// Random unit vectors are placed at the integer lattice points and the noise value is the smoothly interpolated
+// dot product of each gradient with the offset to the sample point; permutation tables hash the lattice points
+
+use rand::Rng;
+
+use crate::{my_vec3::{MyVec3, vec3_normalize, random_in_interval_vec3}, common::uniform_random};
+
+const POINT_COUNT: usize = 256;
+
+#[derive(Debug)]
+pub struct Perlin
+{
+    gradients: Vec<MyVec3>,
+    perm_x:    Vec<usize>,
+    perm_y:    Vec<usize>,
+    perm_z:    Vec<usize>
+}
+
+impl Perlin
+{
+    pub fn new(rng: &mut impl Rng) -> Perlin
+    {
+        let gradients = (0..POINT_COUNT).map(|_| vec3_normalize(random_in_interval_vec3(rng, -1.0, 1.0))).collect();
+
+        Perlin { gradients, perm_x: generate_permutation(rng), perm_y: generate_permutation(rng), perm_z: generate_permutation(rng) }
+    }
+
+    // Noise in the range [-1, 1]
+    pub fn noise(&self, p: MyVec3) -> f64
+    {
+        let (i, j, k) = (f64::floor(p.x), f64::floor(p.y), f64::floor(p.z));
+        let (u, v, w) = (p.x - i, p.y - j, p.z - k);
+        let (i, j, k) = (i as i64, j as i64, k as i64);
+
+        let mut c = [[[MyVec3::default(); 2]; 2]; 2];
+
+        for (di, plane) in c.iter_mut().enumerate()
+        {
+            for (dj, row) in plane.iter_mut().enumerate()
+            {
+                for (dk, corner) in row.iter_mut().enumerate()
+                {
+                    let index = self.perm_x[((i + di as i64) & 255) as usize] ^ self.perm_y[((j + dj as i64) & 255) as usize] ^ self.perm_z[((k + dk as i64) & 255) as usize];
+
+                    *corner = self.gradients[index];
+                }
+            }
+        }
+
+        // Hermite smoothing of the interpolation weights removes grid artifacts
+        let uu = u * u * (3.0 - 2.0 * u);
+        let vv = v * v * (3.0 - 2.0 * v);
+        let ww = w * w * (3.0 - 2.0 * w);
+
+        let mut accumulated = 0.0;
+
+        for (di, plane) in c.iter().enumerate()
+        {
+            for (dj, row) in plane.iter().enumerate()
+            {
+                for (dk, corner) in row.iter().enumerate()
+                {
+                    let (fi, fj, fk) = (di as f64, dj as f64, dk as f64);
+                    let weight       = MyVec3 { x: u - fi, y: v - fj, z: w - fk };
+
+                    accumulated += (fi * uu + (1.0 - fi) * (1.0 - uu))
+                                 * (fj * vv + (1.0 - fj) * (1.0 - vv))
+                                 * (fk * ww + (1.0 - fk) * (1.0 - ww))
+                                 * corner.dot(weight);
+                }
+            }
+        }
+
+        accumulated
+    }
+
+    // Sum of noise at doubling frequencies and halving amplitudes
+    pub fn turbulence(&self, p: MyVec3, depth: u32) -> f64
+    {
+        let mut accumulated = 0.0;
+        let mut point       = p;
+        let mut weight      = 1.0;
+
+        for _ in 0..depth
+        {
+            accumulated += weight * self.noise(point);
+            weight      *= 0.5;
+            point        = 2.0 * point;
+        }
+
+        f64::abs(accumulated)
+    }
+}
+
+// Random permutation of 0..POINT_COUNT (Fisher-Yates)
+fn generate_permutation(rng: &mut impl Rng) -> Vec<usize>
+{
+    let mut permutation: Vec<usize> = (0..POINT_COUNT).collect();
+
+    for i in (1..POINT_COUNT).rev()
+    {
+        let target = usize::min((uniform_random(rng) * (i + 1) as f64) as usize, i);
+        permutation.swap(i, target);
+    }
+
+    permutation
+}
@@ -0,0 +1,287 @@
+// Principled material (after the Disney BSDF): a single surface described by the familiar look-development
+// parameters, made up of four lobes
+//
+//   diffuse      - Burley's diffuse, with its retro-reflection at grazing angles on rough surfaces, plus sheen; for
+//                  the part of the surface which is neither metal nor transmissive
+//   specular     - GGX reflection, white on a dielectric and taking on the base colour as the surface becomes metallic
+//   transmission - rough glass, tinted by the base colour
+//   clearcoat    - a second, white GGX reflection on top, with the reflectance of a varnish of index 1.5
+//
+// A direction is sampled from one lobe, chosen in proportion to a rough estimate of how much light each lobe
+// scatters, and weighted by the sum of all the lobes over the combined density, so the choice only affects the noise.
+// Vectors are in the local frame of the shading normal, as in microfacet.rs; the anisotropic lobes are stretched
+// along its x axis, which scatter.rs keeps horizontal.
+
+use std::f64::consts::PI;
+
+use crate::{my_vec3::{MyVec3, vec3_normalize}, common::unit_sphere_surface_from_square, sampler::Sampler, material::Principled,
+            microfacet::{self, Ggx, MIN_ALPHA}};
+
+// Reflectance at normal incidence of the clearcoat
+const CLEARCOAT_F0: f64 = 0.04;
+
+// The principled material at one intersect: the weights of its lobes, with the base colour looked up from any texture
+#[derive(Debug, Copy, Clone)]
+pub struct PrincipledBsdf
+{
+    diffuse:             MyVec3,
+    sheen:               MyVec3,
+    roughness:           f64,
+    specular_f0:         MyVec3,
+    specular_weight:     f64,
+    transmission_weight: f64,
+    transmission_tint:   MyVec3,
+    eta:                 f64,       // Ratio of the refractive index beyond the surface to that on the side of wo
+    clearcoat:           f64,
+    ggx:                 Ggx,
+    clearcoat_ggx:       Ggx
+}
+
+pub fn luminance(c: MyVec3) -> f64
+{
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+fn grey(value: f64) -> MyVec3
+{
+    MyVec3 { x: value, y: value, z: value }
+}
+
+fn lerp(a: MyVec3, b: MyVec3, t: f64) -> MyVec3
+{
+    (1.0 - t) * a + t * b
+}
+
+// (1 - cos)^5, the weight of the grazing-angle term in Schlick's approximation
+fn schlick_weight(cos: f64) -> f64
+{
+    f64::powi(1.0 - f64::clamp(cos, 0.0, 1.0), 5)
+}
+
+impl PrincipledBsdf
+{
+    // From the material, its base colour at the intersect, and whether the ray arrives from outside the surface
+    pub fn new(material: &Principled, base_colour: MyVec3, is_front: bool) -> PrincipledBsdf
+    {
+        let alpha      = microfacet::alpha_from_roughness(material.roughness);
+        let ggx        = Ggx::anisotropic(alpha, material.anisotropy);
+        let dielectric = (1.0 - material.metallic) * material.transmission;
+
+        // Light refracted into and back out of the surface passes through it twice, so each pass takes the square root
+        let transmission_tint = MyVec3 { x: f64::sqrt(base_colour.x), y: f64::sqrt(base_colour.y), z: f64::sqrt(base_colour.z) };
+
+        // Inside a transmissive object only the glass surface matters: the other lobes describe the outside of it
+        if !is_front && dielectric > 0.0
+        {
+            return PrincipledBsdf { diffuse:             grey(0.0),
+                                    sheen:               grey(0.0),
+                                    roughness:           material.roughness,
+                                    specular_f0:         grey(0.0),
+                                    specular_weight:     0.0,
+                                    transmission_weight: 1.0,
+                                    transmission_tint,
+                                    eta:                 1.0 / material.index_of_refraction,
+                                    clearcoat:           0.0,
+                                    ggx,
+                                    clearcoat_ggx:       ggx };
+        }
+
+        let diffuse_weight = (1.0 - material.metallic) * (1.0 - material.transmission);
+        let tint           = if luminance(base_colour) > 0.0 {base_colour / luminance(base_colour)} else {grey(1.0)};
+        let clearcoat_ggx  = Ggx::new(f64::max(microfacet::alpha_from_roughness(material.clearcoat_roughness), MIN_ALPHA));
+
+        PrincipledBsdf { diffuse:             diffuse_weight * base_colour,
+                         sheen:               (diffuse_weight * material.sheen) * lerp(grey(1.0), tint, material.sheen_tint),
+                         roughness:           material.roughness,
+                         specular_f0:         lerp(grey(0.08 * material.specular), base_colour, material.metallic),
+                         specular_weight:     1.0 - dielectric,
+                         transmission_weight: dielectric,
+                         transmission_tint,
+                         eta:                 material.index_of_refraction,
+                         clearcoat:           0.25 * material.clearcoat,
+                         ggx,
+                         clearcoat_ggx }
+    }
+
+    // Probabilities of sampling the diffuse, specular, transmission and clearcoat lobes; all zero if nothing scatters
+    fn lobe_probabilities(&self, wo: MyVec3) -> [f64; 4]
+    {
+        let weights = [luminance(self.diffuse) + luminance(self.sheen),
+                       self.specular_weight * luminance(microfacet::fresnel_schlick(wo.z, self.specular_f0)),
+                       self.transmission_weight,
+                       self.clearcoat * microfacet::fresnel_schlick(wo.z, grey(CLEARCOAT_F0)).x];
+
+        let total = weights.iter().sum::<f64>();
+
+        if total <= 0.0
+        {
+            return [0.0; 4];
+        }
+
+        weights.map(|weight| weight / total)
+    }
+
+    // Choose the direction wi of the incoming light for wo (wo.z > 0); returns it, its weight (the BSDF times the
+    // cosine factor over the density) and its density
+    pub fn sample(&self, wo: MyVec3, sampler: &mut dyn Sampler) -> Option<(MyVec3, MyVec3, f64)>
+    {
+        let probabilities = self.lobe_probabilities(wo);
+
+        if wo.z <= 0.0 || probabilities.iter().all(|&p| p == 0.0)
+        {
+            return None;
+        }
+
+        let lobe = sampler.next_1d();
+        let u    = sampler.next_2d();
+
+        let wi = if lobe < probabilities[0]
+        {
+            // Cosine distribution about the normal
+            let direction = MyVec3 { x: 0.0, y: 0.0, z: 1.0 } + unit_sphere_surface_from_square(u);
+
+            if direction.squared_length() < 1e-12
+            {
+                return None;
+            }
+
+            vec3_normalize(direction)
+        }
+        else if lobe < probabilities[0] + probabilities[1]
+        {
+            microfacet::reflect(wo, self.ggx.sample_visible_normal(wo, u))
+        }
+        else if lobe < probabilities[0] + probabilities[1] + probabilities[2]
+        {
+            let h = self.ggx.sample_visible_normal(wo, u);
+
+            if sampler.next_1d() < microfacet::fresnel_dielectric(wo.dot(h), self.eta)
+            {
+                microfacet::reflect(wo, h)
+            }
+            else
+            {
+                microfacet::refract(wo, h, self.eta)?
+            }
+        }
+        else
+        {
+            microfacet::reflect(wo, self.clearcoat_ggx.sample_visible_normal(wo, u))
+        };
+
+        let (f_cos, pdf) = self.evaluate(wo, wi);
+
+        if pdf <= 0.0
+        {
+            return None;
+        }
+
+        Some((wi, (1.0 / pdf) * f_cos, pdf))
+    }
+
+    // BSDF times the cosine factor, and the density with which sample chooses wi
+    pub fn evaluate(&self, wo: MyVec3, wi: MyVec3) -> (MyVec3, f64)
+    {
+        let mut f_cos = grey(0.0);
+        let mut pdf   = 0.0;
+
+        if wo.z <= 0.0 || wi.z == 0.0
+        {
+            return (f_cos, pdf);
+        }
+
+        let probabilities = self.lobe_probabilities(wo);
+
+        if wi.z > 0.0
+        {
+            let h     = vec3_normalize(wo + wi);
+            let cos_d = wi.dot(h);
+
+            // Diffuse and sheen
+            let fd90   = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+            let burley = (1.0 + (fd90 - 1.0) * schlick_weight(wi.z)) * (1.0 + (fd90 - 1.0) * schlick_weight(wo.z));
+
+            f_cos = f_cos + wi.z * ((burley / PI) * self.diffuse + schlick_weight(cos_d) * self.sheen);
+            pdf  += probabilities[0] * wi.z / PI;
+
+            // Specular and clearcoat reflection
+            for (ggx, weight, fresnel, probability) in [(self.ggx,           self.specular_weight, microfacet::fresnel_schlick(wo.dot(h), self.specular_f0),     probabilities[1]),
+                                                        (self.clearcoat_ggx, self.clearcoat,       microfacet::fresnel_schlick(wo.dot(h), grey(CLEARCOAT_F0)), probabilities[3])]
+            {
+                f_cos = f_cos + (weight * ggx.d(h) * ggx.g2(wo, wi) / (4.0 * wo.z)) * fresnel;
+                pdf  += probability * ggx.visible_normal_pdf(wo, h) / (4.0 * wo.dot(h));
+            }
+        }
+
+        if self.transmission_weight > 0.0
+        {
+            let (dielectric_f_cos, dielectric_pdf) = self.ggx.dielectric(wo, wi, self.eta);
+            let tint                               = if wi.z > 0.0 {grey(1.0)} else {self.transmission_tint};
+
+            f_cos = f_cos + (self.transmission_weight * dielectric_f_cos) * tint;
+            pdf  += probabilities[2] * dielectric_pdf;
+        }
+
+        (f_cos, pdf)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::sampler::SamplerType;
+
+    fn material() -> Principled
+    {
+        Principled { base_colour: MyVec3 { x: 0.8, y: 0.4, z: 0.2 }, texture: None, metallic: 0.3, roughness: 0.4, specular: 0.5, transmission: 0.5,
+                     index_of_refraction: 1.5, clearcoat: 0.8, clearcoat_roughness: 0.1, sheen: 0.5, sheen_tint: 0.5, anisotropy: 0.6 }
+    }
+
+    // The density of the sampled directions integrates to (almost) one over the sphere, and the weight of every sample
+    // is the BSDF over that density
+    #[test]
+    fn sample_matches_evaluate()
+    {
+        for (bsdf, wo) in [(PrincipledBsdf::new(&material(), material().base_colour, true),  MyVec3 { x: 0.3, y: 0.2, z: 0.9 }),
+                           (PrincipledBsdf::new(&material(), material().base_colour, true),  MyVec3 { x: -0.7, y: 0.1, z: 0.3 }),
+                           (PrincipledBsdf::new(&material(), material().base_colour, false), MyVec3 { x: 0.2, y: -0.4, z: 0.8 })]
+        {
+            let wo = vec3_normalize(wo);
+            let n  = 1000;
+
+            let mut integral = 0.0;
+
+            for i in 0..n
+            {
+                for j in 0..n
+                {
+                    let theta = PI * (i as f64 + 0.5) / n as f64;
+                    let phi   = 2.0 * PI * (j as f64 + 0.5) / n as f64;
+                    let wi    = MyVec3 { x: f64::sin(theta) * f64::cos(phi), y: f64::sin(theta) * f64::sin(phi), z: f64::cos(theta) };
+
+                    integral += bsdf.evaluate(wo, wi).1 * f64::sin(theta) * (PI / n as f64) * (2.0 * PI / n as f64);
+                }
+            }
+
+            // Some refracted samples end up on the wrong side of the surface, and the densities of the sharp lobes
+            // are not fully resolved by the grid
+            assert!(integral > 0.9 && integral < 1.01, "{:?}: pdf integral {}", wo, integral);
+
+            let mut sampler = SamplerType::Independent.create(1, 1);
+
+            for i in 0..10000
+            {
+                sampler.start_sample(0, i);
+
+                if let Some((wi, weight, pdf)) = bsdf.sample(wo, sampler.as_mut())
+                {
+                    let (f_cos, evaluated_pdf) = bsdf.evaluate(wo, wi);
+
+                    assert!((pdf - evaluated_pdf).abs() <= 1e-9 * pdf);
+                    assert!((pdf * weight - f_cos).length() <= 1e-9 * f_cos.length());
+                }
+            }
+        }
+    }
+}
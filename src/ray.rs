@@ -12,6 +12,7 @@ pub struct Ray
 
 impl Ray
 {
+    #[allow(clippy::needless_return)]
     pub fn at(&self, ds: f64) -> MyVec3
     {
         return self.p + ds * self.direction;
@@ -1,16 +1,37 @@
 use crate::my_vec3::MyVec3;
-use crate::material::Material;
+use crate::material::{Material, DEFAULT_MATERIAL};
+use crate::light::Light;
 
 // This debug attribute implements fmt::Debug which will allow us
 // to print the struct using {:?}
-#[derive(Debug, Default)]
+#[derive(Debug)]
 
-pub struct RayInfo 
+pub struct RayInfo<'a>
 {
     pub intersect: MyVec3, 
     pub normal:    MyVec3, 
     pub ds:        f64,
     pub is_front:  bool,
 
-    pub material: Material
+    // Surface (texture) co-ordinates of the intersect, each in the range [0, 1]
+    pub u:         f64,
+    pub v:         f64,
+
+    // Material of the object which was hit
+    pub material: &'a dyn Material,
+
+    // For a dielectric, the ratio of the refractive index beyond the surface to that on the side the ray arrives from,
+    // where the renderer knows the objects on either side; None for a dielectric surrounded by air
+    pub relative_index: Option<f64>,
+
+    // The world's light which is the object hit, None where the object is not one of the world's lights
+    pub light: Option<&'a dyn Light>
+}
+
+impl Default for RayInfo<'_>
+{
+    fn default() -> Self
+    {
+        RayInfo { intersect: MyVec3::default(), normal: MyVec3::default(), ds: 0.0, is_front: false, u: 0.0, v: 0.0, material: &DEFAULT_MATERIAL, relative_index: None, light: None }
+    }
 }
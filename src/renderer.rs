@@ -1,193 +1,1163 @@
-use crate::{ray::Ray, camera::Camera, my_vec3::{MyVec3, vec3_normalize}, world_element::WorldElement, scatter::scatter};
+use crate::{ray::Ray, camera::Camera, my_vec3::{MyVec3, vec3_normalize}, world_element::WorldElement,
+            light::power_heuristic, sampler::{Sampler, SamplerType}, film::{Film, PixelStatistics}, filter::Filter, medium::Medium,
+            world_element::Intersect, bounding_box::WEBoundingBox, dielectric_stack::DielectricStack, material::Interior};
+
+use std::{sync::{Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}};
 
-use rand::Rng;
 use crossbeam_utils::thread;
+use serde::Deserialize;
+
+// Light arriving from rays which leave the scene without hitting anything
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Sky
+{
+    #[default]
+    Gradient,   // White at the horizon to light blue overhead
+    Black       // No light from the sky, the scene is lit only by emissive objects
+}
+
+impl Sky
+{
+    pub fn colour(&self, direction: MyVec3) -> MyVec3
+    {
+        match self
+        {
+            Sky::Gradient =>
+            {
+                let w = 0.5 * (vec3_normalize(direction).y + 1.0);
+
+                (1.0 - w) * MyVec3{x: 1.0, y: 1.0, z: 1.0} + w * MyVec3{x: 0.5, y: 0.7, z: 1.0}
+            }
+            Sky::Black => MyVec3{x: 0.0, y: 0.0, z: 0.0},
+        }
+    }
+}
 
-// A structure created to workaround one of Rust's deficiencies (not being able to use different elements of a vector as function parameters in multiple functions)
-#[derive(Copy, Clone)]
-pub struct RenderScope
+// Order in which tiles are handed out to the render threads (the order they appear in a preview of the render)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum, Default)]
+pub enum TileOrder
 {
-    begin_line:      u32,
-    number_of_lines: u32
+    #[default]
+    Scanline,   // Left to right, top to bottom
+    Spiral,     // Outwards from the centre of the image, so the subject is usually finished first
+    Hilbert     // Along a Hilbert curve, consecutive tiles are neighbours which keeps the scene data they use in cache
+}
+
+// Tiles are small enough that there are many more tiles than threads, so the threads finish at about the same time
+// whatever the distribution of expensive (e.g. glass) and cheap (e.g. sky) pixels in the image
+const TILE_SIZE: u32 = 32;
+
+// Rectangle of the image, in pixels
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tile
+{
+    x0:     u32,
+    y0:     u32,
+    width:  u32,
+    height: u32
+}
+
+// Pixels stop taking samples once the estimated relative error of their luminance is at most target_error, after at
+// least min_samples samples (and at most samples_per_pixel)
+#[derive(Debug, Copy, Clone)]
+pub struct AdaptiveSampling
+{
+    pub min_samples:  u32,
+    pub target_error: f64
+}
+
+impl AdaptiveSampling
+{
+    // The error of a pixel is estimated from the spread of its samples, so at least two are taken (and so at least two
+    // must be allowed)
+    pub fn new(min_samples: u32, samples_per_pixel: u32, target_error: f64) -> Result<AdaptiveSampling, String>
+    {
+        if samples_per_pixel < 2
+        {
+            return Err(format!("Adaptive sampling needs at least 2 samples per pixel, not {}", samples_per_pixel));
+        }
+
+        Ok(AdaptiveSampling { min_samples: u32::clamp(min_samples, 2, samples_per_pixel), target_error })
+    }
 }
 
 pub struct Renderer
 {
     image_width:          u32,
     image_height:         u32, 
-    colour_channels:      u32, 
     samples_per_pixel:    u32, 
     max_ray_bounce_depth: u32, 
     camera:               Camera, 
-    world_element:        WorldElement
+    world_element:        WorldElement,
+    sky:                  Sky,
+    light_sampling:       bool,
+    tile_order:           TileOrder,
+    sampler_type:         SamplerType,
+    seed:                 u64,
+    adaptive_sampling:    Option<AdaptiveSampling>,
+    filter:               Filter,
+
+    // Medium filling the space outside every object's medium; rays which hit nothing leave it at the edge of scene_bounds
+    // (there is no fog outside an empty or unbounded scene)
+    fog:                  Option<Medium>,
+    scene_bounds:         WEBoundingBox
 }
 
 impl Renderer 
 {
-    pub fn new(image_width: u32, image_height: u32, colour_channels: u32, samples_per_pixel: u32, max_ray_bounce_depth: u32, camera: Camera, world_element: WorldElement) -> Renderer
+    // One argument per render setting; the optional features are switched on afterwards by the set_ methods
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(image_width: u32, image_height: u32, samples_per_pixel: u32, max_ray_bounce_depth: u32, camera: Camera, world_element: WorldElement, sky: Sky, light_sampling: bool, tile_order: TileOrder, sampler_type: SamplerType, seed: u64) -> Renderer
     {
+        let scene_bounds = world_element.bounding_box(0.0, camera.exposure_length.unwrap_or(0.0));
+
         Renderer{
                  image_width,
                  image_height,
-                 colour_channels, 
                  samples_per_pixel, 
                  max_ray_bounce_depth, 
                  camera, 
-                 world_element
+                 world_element,
+                 sky,
+                 light_sampling,
+                 tile_order,
+                 sampler_type,
+                 seed,
+                 adaptive_sampling: None,
+                 filter:            Filter::default(),
+                 fog:               None,
+                 scene_bounds
         }
     }
+
+    // Change the sampling without rebuilding the scene (e.g. to compare samplers)
+    pub fn set_sampling(&mut self, samples_per_pixel: u32, sampler_type: SamplerType, seed: u64)
+    {
+        self.samples_per_pixel = samples_per_pixel;
+        self.sampler_type      = sampler_type;
+        self.seed              = seed;
+    }
+
+    pub fn set_adaptive_sampling(&mut self, adaptive_sampling: Option<AdaptiveSampling>)
+    {
+        self.adaptive_sampling = adaptive_sampling;
+    }
+
+    pub fn set_filter(&mut self, filter: Filter)
+    {
+        self.filter = filter;
+    }
+
+    pub fn set_fog(&mut self, fog: Option<Medium>)
+    {
+        self.fog = fog;
+    }
+}
+
+// Render the whole image with samples_per_pixel samples per pixel
+pub fn render(rdr: &Renderer, number_of_threads: u32) -> Film
+{
+    let mut film = Film::new(rdr.image_width, rdr.image_height);
+
+    render_pass(rdr, &mut film, rdr.samples_per_pixel, number_of_threads, &AtomicBool::new(false));
+
+    film
 }
 
-pub fn render(rdr: Renderer, target_number_of_threads: u32) -> Vec<u8>
+// Add samples to every pixel of the film until it has sample_limit samples (fewer for pixels which have converged with
+// adaptive sampling, and never more than samples_per_pixel). Each pixel continues from the samples it already has, so a
+// pass stopped early, by setting stop, leaves the film valid and the next pass picks up where it left off
+pub fn render_pass(rdr: &Renderer, film: &mut Film, sample_limit: u32, number_of_threads: u32, stop: &AtomicBool)
 {
     let viewport          = rdr.camera.viewport;
 
-    let horizontal_step   =        (viewport.width  as f64 / rdr.image_width  as f64) * viewport.horizontal_vector;
-    let vertical_step     = -1.0 * (viewport.height as f64 / rdr.image_height as f64) * viewport.vertical_vector;
+    let horizontal_step   =        (viewport.width  / rdr.image_width  as f64) * viewport.horizontal_vector;
+    let vertical_step     = -(viewport.height / rdr.image_height as f64) * viewport.vertical_vector;
 
-    let mut number_of_threads = target_number_of_threads;
-    let mut lines_per_thread  = (rdr.image_height as f64 / number_of_threads as f64).ceil() as u32;
+    let tiles             = generate_tiles(rdr.image_width, rdr.image_height, rdr.tile_order);
+    let number_of_threads = u32::clamp(number_of_threads, 1, tiles.len() as u32);
+    let sample_limit      = u32::min(sample_limit, rdr.samples_per_pixel);
+
+    /*
+     * Each thread takes the next tile from the queue (the shared index of the next tile to render), renders it, and
+     * adds the result to the film; a thread which has been given cheap tiles simply takes more of them
+     */
+
+    let next_tile = AtomicUsize::new(0);
+    let pass_film = Mutex::new(PassFilm { film, pending: (0..tiles.len()).map(|_| None).collect(), next: 0 });
+
+    thread::scope(|scope| {
 
-    // Take care of the degenerate case where there are as many threads as horizontal lines in the image by reducing the number of threads
-    while lines_per_thread * (number_of_threads - 1) >= rdr.image_height
+        for _ in 0..number_of_threads
+        {
+            scope.spawn(|_| {
+                loop
+                {
+                    let index = next_tile.fetch_add(1, Ordering::Relaxed);
+
+                    let Some(&tile) = tiles.get(index) else { break };
+
+                    if stop.load(Ordering::Relaxed)
+                    {
+                        break;
+                    }
+
+                    let mut statistics = read_statistics(pass_film.lock().unwrap().film, tile);
+
+                    let splats = render_tile(rdr, horizontal_step, vertical_step, tile, &mut statistics, sample_limit, stop);
+
+                    let mut pass_film = pass_film.lock().unwrap();
+
+                    write_statistics(pass_film.film, tile, &statistics);
+                    pass_film.add_splats(index, splats);
+                }
+            });
+        }
+
+    }).unwrap();
+
+    // Tiles skipped when the pass was stopped leave gaps in the queue of splats
+    pass_film.into_inner().unwrap().flush();
+}
+
+// The samples of a tile splatted onto the tile and the pixels around it, waiting to be added to the film
+struct TileSplats
+{
+    region: Tile,                   // The tile grown by the filter's reach, clipped to the image
+    values: Vec<(MyVec3, f64)>      // Weighted sum of the samples and sum of their weights, rows of region.width pixels
+}
+
+impl TileSplats
+{
+    fn new(tile: Tile, reach: u32, image_width: u32, image_height: u32) -> TileSplats
     {
-        number_of_threads = number_of_threads - 1;
-        lines_per_thread  = (rdr.image_height as f64 / number_of_threads as f64).ceil() as u32;
+        let x0 = tile.x0.saturating_sub(reach);
+        let y0 = tile.y0.saturating_sub(reach);
+        let x1 = u32::min(tile.x0 + tile.width  + reach, image_width);
+        let y1 = u32::min(tile.y0 + tile.height + reach, image_height);
+
+        let region = Tile { x0, y0, width: x1 - x0, height: y1 - y0 };
+
+        TileSplats { region, values: vec![(MyVec3::default(), 0.0); (region.width * region.height) as usize] }
     }
 
-    /*
-     * Temporary memory locations to which each thread will write (one per thread)
-     * Concatenated to give the full image at the end of the function
-     */
+    // Add a sample at (sample_x, sample_y) in pixel (x, y) to each pixel within the filter radius
+    fn add(&mut self, filter: &Filter, x: u32, y: u32, sample_x: f64, sample_y: f64, colour: MyVec3)
+    {
+        let reach  = filter.pixel_reach();
+        let region = self.region;
 
-    let mut submap: Vec<Vec<u8>> = Vec::with_capacity(number_of_threads as usize);
-    for _ in 0..number_of_threads
+        for pixel_y in u32::max(y.saturating_sub(reach), region.y0)..u32::min(y + reach + 1, region.y0 + region.height)
+        {
+            for pixel_x in u32::max(x.saturating_sub(reach), region.x0)..u32::min(x + reach + 1, region.x0 + region.width)
+            {
+                let weight = filter.weight(sample_x - pixel_x as f64, sample_y - pixel_y as f64);
+
+                if weight != 0.0
+                {
+                    let value = &mut self.values[((pixel_y - region.y0) * region.width + pixel_x - region.x0) as usize];
+
+                    value.0 = value.0 + weight * colour;
+                    value.1 += weight;
+                }
+            }
+        }
+    }
+}
+
+// The film being rendered and the splats of tiles which have finished out of order. Splats are added to the film in
+// tile order, so the floating point sums where tiles overlap, and hence the image, do not depend on which thread
+// finishes first
+struct PassFilm<'a>
+{
+    film:    &'a mut Film,
+    pending: Vec<Option<TileSplats>>,
+    next:    usize                      // Index of the next tile whose splats are to be added
+}
+
+impl PassFilm<'_>
+{
+    fn add_splats(&mut self, index: usize, splats: TileSplats)
     {
-        submap.push(vec![0; (rdr.image_width * rdr.colour_channels * lines_per_thread) as usize]);
+        self.pending[index] = Some(splats);
+
+        while let Some(splats) = self.pending.get_mut(self.next).and_then(Option::take)
+        {
+            add_to_film(self.film, &splats);
+            self.next += 1;
+        }
     }
 
-    /*
-     * Prepare the thread rendering parameters ahead of time to allow for iterators to be used
-     * as a way to get around the ownership problem of accessing the elements of a vector directly
-     */
+    fn flush(self)
+    {
+        for splats in self.pending.into_iter().flatten()
+        {
+            add_to_film(self.film, &splats);
+        }
+    }
+}
+
+fn add_to_film(film: &mut Film, splats: &TileSplats)
+{
+    let region = splats.region;
 
-    let mut render_scope: Vec<RenderScope> = Vec::with_capacity(number_of_threads as usize);
-    let mut break_line:           Vec<u32> = Vec::with_capacity(number_of_threads as usize);
+    for (y, row) in (region.y0..region.y0 + region.height).zip(splats.values.chunks_exact(region.width as usize))
+    {
+        for (x, &(colour, weight)) in (region.x0..region.x0 + region.width).zip(row)
+        {
+            let pixel = film.pixel_mut(x, y);
+
+            pixel.colour_sum  = pixel.colour_sum + colour;
+            pixel.weight_sum += weight;
+        }
+    }
+}
+
+// The sample statistics of the pixels of a tile, rows of tile.width pixels
+fn read_statistics(film: &Film, tile: Tile) -> Vec<PixelStatistics>
+{
+    (tile.y0..tile.y0 + tile.height).flat_map(|y| (tile.x0..tile.x0 + tile.width).map(move |x| film.pixel(x, y).statistics)).collect()
+}
 
-    // Make a list of the number of lines of the image to process per thread, first (number_of_threads - 1)
-    // are added, then the final thread is allocated all remaining lines (this will be a different number
-    // lines where the total number of lines of the image is not divisible by the number of threads)
-    for n in 1..number_of_threads       
+fn write_statistics(film: &mut Film, tile: Tile, statistics: &[PixelStatistics])
+{
+    for (y, row) in (tile.y0..tile.y0 + tile.height).zip(statistics.chunks_exact(tile.width as usize))
     {
-        break_line.push(lines_per_thread * n);
+        for (x, pixel_statistics) in (tile.x0..tile.x0 + tile.width).zip(row)
+        {
+            film.pixel_mut(x, y).statistics = *pixel_statistics;
+        }
     }
-    break_line.push(rdr.image_height);
-    break_line.dedup();
+}
+
+// Split the image into tiles of (at most) TILE_SIZE x TILE_SIZE pixels, in the order they are to be rendered
+pub fn generate_tiles(image_width: u32, image_height: u32, tile_order: TileOrder) -> Vec<Tile>
+{
+    let tiles_x = image_width.div_ceil(TILE_SIZE);
+    let tiles_y = image_height.div_ceil(TILE_SIZE);
+
+    let tile_at = |(i, j): (u32, u32)| -> Tile
+    {
+        let x0 = i * TILE_SIZE;
+        let y0 = j * TILE_SIZE;
+
+        Tile { x0, y0, width: u32::min(TILE_SIZE, image_width - x0), height: u32::min(TILE_SIZE, image_height - y0) }
+    };
+
+    let grid_positions: Vec<(u32, u32)> = match tile_order
+    {
+        TileOrder::Scanline => (0..tiles_y).flat_map(|j| (0..tiles_x).map(move |i| (i, j))).collect(),
+        TileOrder::Spiral   => spiral_order(tiles_x, tiles_y),
+        TileOrder::Hilbert  => hilbert_order(tiles_x, tiles_y),
+    };
+
+    grid_positions.into_iter().map(tile_at).collect()
+}
+
+// Square spiral over the tile grid starting at the centre tile: right 1, down 1, left 2, up 2, right 3, ...
+// Positions outside the grid are skipped; the spiral continues until every tile has been visited
+fn spiral_order(tiles_x: u32, tiles_y: u32) -> Vec<(u32, u32)>
+{
+    let number_of_tiles = (tiles_x * tiles_y) as usize;
+    let mut order       = Vec::with_capacity(number_of_tiles);
+
+    let (mut i, mut j)  = (((tiles_x - 1) / 2) as i64, ((tiles_y - 1) / 2) as i64);
+    let directions      = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut step_length = 1;
+    let mut direction   = 0;
+
+    let visit = |i: i64, j: i64, order: &mut Vec<(u32, u32)>|
+    {
+        if i >= 0 && j >= 0 && i < tiles_x as i64 && j < tiles_y as i64
+        {
+            order.push((i as u32, j as u32));
+        }
+    };
 
-    let mut begin_line: u32 = 0;
-    for end_line in break_line
+    visit(i, j, &mut order);
+
+    while order.len() < number_of_tiles
     {
-        render_scope.push(RenderScope{begin_line, number_of_lines: end_line - begin_line});
+        // Each step length is used for two legs of the spiral
+        for _ in 0..2
+        {
+            let (di, dj) = directions[direction];
+
+            for _ in 0..step_length
+            {
+                i += di;
+                j += dj;
+                visit(i, j, &mut order);
+            }
 
-        begin_line = end_line;
+            direction = (direction + 1) % 4;
+        }
+
+        step_length += 1;
     }
 
-    /*
-     * Multi-threaded rendering
-     */
+    order
+}
 
-    thread::scope(|scope| {
+// Hilbert curve over the smallest power of two square covering the tile grid, skipping positions outside the grid
+fn hilbert_order(tiles_x: u32, tiles_y: u32) -> Vec<(u32, u32)>
+{
+    let n = u32::max(tiles_x, tiles_y).next_power_of_two();
 
-        for (x, z) in submap.iter_mut().zip(&mut render_scope)
+    (0..(n as u64 * n as u64)).map(|d| hilbert_position(n, d))
+                              .filter(|&(i, j)| i < tiles_x && j < tiles_y)
+                              .collect()
+}
+
+// Position of distance d along the Hilbert curve filling an n x n grid (n a power of two)
+fn hilbert_position(n: u32, d: u64) -> (u32, u32)
+{
+    let (mut x, mut y) = (0u32, 0u32);
+    let mut t          = d;
+    let mut s          = 1;
+
+    while s < n
+    {
+        let rx = (1 & (t / 2)) as u32;
+        let ry = (1 & (t ^ rx as u64)) as u32;
+
+        // Rotate the quadrant so that the curve joins up with its neighbours
+        if ry == 0
         {
-            scope.spawn(|_| {   
-                render_lines(&rdr, horizontal_step, vertical_step, &mut *x, *z);
-            });
+            if rx == 1
+            {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+
+            std::mem::swap(&mut x, &mut y);
         }
 
-    }).unwrap();
-    
-    /*
-     * Write the final image out to a vector once all the threads have terminated (guaranteed by thread::scope)
-     */
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}
+
+// Take samples for the pixels of one tile, updating their statistics (rows of tile.width pixels), and return the samples
+// splatted through the filter
+fn render_tile(rdr: &Renderer, horizontal_step: MyVec3, vertical_step: MyVec3, tile: Tile, statistics: &mut [PixelStatistics], sample_limit: u32, stop: &AtomicBool) -> TileSplats
+{
+    let mut sampler = rdr.sampler_type.create(rdr.seed, rdr.samples_per_pixel);
+    let mut splats  = TileSplats::new(tile, rdr.filter.pixel_reach(), rdr.image_width, rdr.image_height);
+
+    // With adaptive sampling samples_per_pixel is the most samples a pixel may take
+    let (min_samples, target_error) = match rdr.adaptive_sampling
+    {
+        Some(adaptive_sampling) => (u32::min(adaptive_sampling.min_samples, rdr.samples_per_pixel), adaptive_sampling.target_error),
+        None                    => (rdr.samples_per_pixel, 0.0),
+    };
+
+    for (pixel_statistics, (x, y)) in statistics.iter_mut().zip((tile.y0..tile.y0 + tile.height).flat_map(|y| (tile.x0..tile.x0 + tile.width).map(move |x| (x, y))))
+    {
+        if stop.load(Ordering::Relaxed)
+        {
+            break;
+        }
+
+        let viewport_current = rdr.camera.viewport.reference_corner + y as f64 * vertical_step + x as f64 * horizontal_step;
+
+        for s in pixel_statistics.count..sample_limit
+        {
+            if s >= min_samples && pixel_statistics.relative_error() <= target_error
+            {
+                break;
+            }
+
+            // The sampler's numbers depend only on the pixel and sample, so the image does not depend on the tiles or threads
+            sampler.start_sample((y * rdr.image_width + x) as u64, s);
+
+            // Cast rays at a random point in the neighbourhood of the exact point on the viewport and at a random time during the exposure
+            let pixel_sample    = sampler.next_2d();
+            let viewport_offset = (pixel_sample.1 - 0.5) * vertical_step + (pixel_sample.0 - 0.5) * horizontal_step;
+            let cast_time       = sampler.next_1d() * rdr.camera.exposure_length.unwrap_or(0.0);
+
+            // Cast the initial ray from the camera
+            let r      = rdr.camera.generate_ray(viewport_current + viewport_offset, cast_time, sampler.next_2d());
+            let colour = trace_path(rdr, r, cast_time, sampler.as_mut());
+
+            // Splat the sample onto the pixels around it; its position is in pixels, relative to the centre of pixel (0, 0)
+            splats.add(&rdr.filter, x, y, x as f64 + pixel_sample.0 - 0.5, y as f64 + pixel_sample.1 - 0.5, colour);
+            pixel_statistics.add(colour);
+        }
+    }
+
+    splats
+}
+
+// Light arriving at the camera along the ray r
+fn trace_path(rdr: &Renderer, r: Ray, cast_time: f64, sampler: &mut dyn Sampler) -> MyVec3
+{
+    let mut final_colour = MyVec3{x:0.0, y:0.0, z:0.0};
+    let mut total_gain   = MyVec3{x:1.0, y:1.0, z:1.0};
+    let mut ray_bounce   = 0;
+    let mut r            = r;
+
+    // Where light sources are sampled explicitly (next-event estimation), a scattered ray which then hits a light
+    // could also have been generated by light sampling; previous_scatter_pdf holds the density of the scattered
+    // direction in that case so that the two estimates of the same light can be weighted rather than added
+    let mut previous_scatter_pdf: Option<f64> = None;
+    let mut previous_intersect                = r.p;
+
+    // The medium the ray is travelling through; the camera is assumed to be outside every object's medium
+    let mut medium = rdr.fog.as_ref();
+
+    // The dielectric objects the ray is inside; the camera is assumed to be outside them all
+    let mut dielectrics = DielectricStack::new();
+
+    loop
+    {
+        let (f_intersect, mut ray_info) = rdr.world_element.intersect_all(&r, 0.001, f64::INFINITY, cast_time);
+
+        // In a medium the ray may hit a particle before it reaches the surface. The chance of getting as far as the
+        // surface is the transmittance, which is the factor by which the light from beyond is attenuated, so paths
+        // which do get there carry their light unchanged
+        if let Some(current_medium) = medium
+        {
+            let direction_length = r.direction.length();
+            let surface_scale    = if f_intersect {ray_info.ds} else {fog_exit_scale(rdr, &r)};
+
+            if let Some(distance) = current_medium.sample_distance(r.p, (1.0 / direction_length) * r.direction, surface_scale * direction_length, sampler)
+            {
+                if ray_bounce > rdr.max_ray_bounce_depth
+                {
+                    break;
+                }
+
+                let point = r.at(distance / direction_length);
+                let phase = current_medium.phase;
+
+                // A medium inside a dielectric object (e.g. murky water) scatters light the object also absorbs
+                total_gain = total_gain * dielectric_transmittance(dielectrics.current(), distance);
+
+                if rdr.light_sampling
+                {
+                    final_colour = final_colour + total_gain * direct_light(rdr, point, medium, &dielectrics, cast_time, sampler,
+                                                                            |direction| { let value = phase.value(r.direction, direction); (value * current_medium.albedo, value) });
+                }
+
+                let scatter_direction = phase.sample(r.direction, sampler.next_2d());
+
+                previous_scatter_pdf = if rdr.light_sampling {Some(phase.value(r.direction, scatter_direction))} else {None};
+                previous_intersect   = point;
+
+                r = Ray{p: point, direction: scatter_direction, cast_time: r.cast_time};
+
+                total_gain = total_gain * current_medium.albedo;
+
+                ray_bounce += 1;
+                continue;
+            }
+        }
+
+        // Light is absorbed on its way through a dielectric object
+        if f_intersect
+        {
+            total_gain = total_gain * dielectric_transmittance(dielectrics.current(), ray_info.ds * r.direction.length());
+        }
+
+        if !f_intersect
+        {
+            // Colour is determined by the ray's final direction (i.e. the ray which is the source of the light which comes from the background in this case)
+            final_colour = final_colour + total_gain * rdr.sky.colour(r.direction);
+            break;
+        }
 
-    let mut bitmap = Vec::with_capacity((rdr.image_width * rdr.colour_channels * lines_per_thread * number_of_threads) as usize);
+        // Crossing into or out of an object's medium; the ray carries on in the same direction and this is not a bounce
+        if let Some(inside) = ray_info.material.medium()
+        {
+            medium = if ray_info.is_front {Some(inside)} else {rdr.fog.as_ref()};
+
+            r = Ray{p: ray_info.intersect, direction: r.direction, cast_time: r.cast_time};
+            continue;
+        }
+
+        // The surface of a dielectric object inside one of higher priority is not there, and otherwise separates the
+        // objects the ray is in on either side of it (e.g. glass and water) rather than the object and air
+        let interior = ray_info.material.interior();
+
+        if let Some(interior) = interior
+        {
+            if dielectrics.is_false_intersection(ray_info.material, &interior, ray_info.is_front)
+            {
+                dielectrics.cross(ray_info.material, interior, ray_info.is_front);
+
+                r = Ray{p: ray_info.intersect, direction: r.direction, cast_time: r.cast_time};
+                continue;
+            }
+
+            ray_info.relative_index = Some(dielectrics.relative_index(ray_info.material, &interior, ray_info.is_front));
+        }
+
+        // Light sources end the path; the light they emit is attenuated by every surface the ray has previously scattered from
+        if let Some(emission) = ray_info.material.emission()
+        {
+            let weight = match previous_scatter_pdf
+            {
+                Some(scatter_pdf) => power_heuristic(scatter_pdf, rdr.world_element.light_pdf(previous_intersect, &ray_info)),
+                None              => 1.0,
+            };
+
+            final_colour = final_colour + weight * total_gain * emission;
+            break;
+        }
+
+        // Too many bounces, no further light is gathered
+        if ray_bounce > rdr.max_ray_bounce_depth
+        {
+            break;
+        }
+
+        // Light sampling is used for all but specular surfaces, which reflect light from a single direction which a
+        // sample chosen on a light would (almost) never match
+        let sample_lights = rdr.light_sampling && !ray_info.material.is_specular();
+
+        if sample_lights
+        {
+            final_colour = final_colour + total_gain * direct_light(rdr, ray_info.intersect, medium, &dielectrics, cast_time, sampler, |direction| (ray_info.material.evaluate(r, &ray_info, direction), ray_info.material.pdf(r, &ray_info, direction)));
+        }
+
+        let sample = match ray_info.material.sample(r, &ray_info, sampler)
+        {
+            Some(sample) => sample,
+            None         => break,
+        };
+
+        previous_scatter_pdf = if sample_lights {sample.pdf} else {None};
+        previous_intersect   = ray_info.intersect;
+
+        // Light refracted through the surface of a dielectric object enters or leaves it (the normal faces the incoming ray)
+        if let Some(interior) = interior
+        {
+            if sample.direction.dot(ray_info.normal) < 0.0
+            {
+                dielectrics.cross(ray_info.material, interior, ray_info.is_front);
+            }
+        }
+
+        r = Ray{p: ray_info.intersect, direction: sample.direction, cast_time: r.cast_time};
+
+        total_gain = total_gain * sample.weight;
+
+        ray_bounce += 1;
+    }
+
+    final_colour
+}
 
-    for x in submap.iter() 
+// Fraction of each colour remaining after distance through the inside of a dielectric object (all of it outside them)
+fn dielectric_transmittance(interior: Option<Interior>, distance: f64) -> MyVec3
+{
+    match interior
     {
-        bitmap.extend(x);
+        Some(interior) =>
+        {
+            let absorption = interior.absorption;
+
+            MyVec3{x: f64::exp(-absorption.x * distance), y: f64::exp(-absorption.y * distance), z: f64::exp(-absorption.z * distance)}
+        }
+        None => MyVec3{x: 1.0, y: 1.0, z: 1.0},
     }
+}
+
+// Light reaching a point (on a surface which is not specular, or in a medium) directly from one randomly chosen light source, weighted
+// against the chance of the scattered ray reaching the same light (multiple importance sampling with the power heuristic)
+// scattering gives, for a unit direction towards the light, the fraction of the light scattered towards the camera
+// (including the cosine factor at a surface) and the density with which that direction would have been scattered
+// The point is in medium and inside the dielectric objects of dielectrics, as the path which reached it
+fn direct_light<'a>(rdr: &'a Renderer, point: MyVec3, medium: Option<&Medium>, dielectrics: &DielectricStack<'a>, cast_time: f64, sampler: &mut dyn Sampler, scattering: impl Fn(MyVec3) -> (MyVec3, f64)) -> MyVec3
+{
+    let black = MyVec3{x: 0.0, y: 0.0, z: 0.0};
 
-    bitmap.resize((rdr.image_width * rdr.image_height * rdr.colour_channels) as usize, 0);
+    let light_choice = sampler.next_1d();
+    let light_point  = sampler.next_2d();
 
-    return bitmap;
+    let light_sample = match rdr.world_element.sample_light(point, light_choice, light_point.0, light_point.1)
+    {
+        Some(light_sample) => light_sample,
+        None               => return black,
+    };
+
+    let (scattered, scatter_pdf) = scattering(light_sample.direction);
+
+    if scatter_pdf <= 0.0 || light_sample.pdf <= 0.0
+    {
+        return black;
+    }
+
+    let shadow_ray    = Ray{p: point, direction: light_sample.direction, cast_time};
+    let transmittance = shadow_transmittance(rdr, shadow_ray, light_sample.distance, medium, dielectrics, sampler);
+
+    let weight = power_heuristic(light_sample.pdf, scatter_pdf);
+
+    (weight / light_sample.pdf) * (transmittance * scattered * light_sample.radiance)
 }
 
+// Ray scaling factor at which a ray which hits nothing leaves the fog: the edge of the scene's bounds. An empty or
+// unbounded scene has no such edge, and fog all the way to infinity would hide the sky completely, so there the fog is
+// treated as absent
+fn fog_exit_scale(rdr: &Renderer, r: &Ray) -> f64
+{
+    let exit_scale = rdr.scene_bounds.exit_scale(r);
 
-pub fn render_lines(rdr: &Renderer, horizontal_step: MyVec3, vertical_step: MyVec3, bitmap: &mut [u8], rsc: RenderScope)
+    if exit_scale.is_finite() {exit_scale} else {0.0}
+}
+
+// Fraction of each colour of the light from distance along the shadow ray (with a unit direction) which reaches its
+// origin: none if a surface is in the way, otherwise attenuated by the media and dielectric objects passed through. As
+// for the path itself, the boundaries of media do not block the light and nor do the false intersections of nested
+// dielectrics (the surface of an object inside one of higher priority)
+fn shadow_transmittance<'a>(rdr: &'a Renderer, shadow_ray: Ray, distance: f64, medium: Option<&Medium>, dielectrics: &DielectricStack<'a>, sampler: &mut dyn Sampler) -> MyVec3
 {
-    let mut rng = rand::thread_rng();
+    let black = MyVec3{x: 0.0, y: 0.0, z: 0.0};
 
-    // The set of horizontal lines of the final image bitmap which are being processed on this call (or thread)
-    let begin_line      = rsc.begin_line;
-    let number_of_lines = rsc.number_of_lines;
+    let mut transmittance = MyVec3{x: 1.0, y: 1.0, z: 1.0};
+    let mut shadow_ray    = shadow_ray;
+    let mut remaining     = distance;
+    let mut medium        = medium;
+    let mut dielectrics   = dielectrics.clone();
 
-    for y in 0..number_of_lines
+    loop
     {
-        let mut bitmap_idx   = (y * rdr.image_width * rdr.colour_channels) as usize;
-        let     viewport_row = rdr.camera.viewport.reference_corner + (begin_line + y) as f64 * vertical_step;
+        let (f_blocked, ray_info) = rdr.world_element.intersect_all(&shadow_ray, 0.001, remaining - 0.001, shadow_ray.cast_time);
+        let segment               = if f_blocked {ray_info.ds} else {remaining};
 
-        for x in 0..rdr.image_width
+        if let Some(current_medium) = medium
         {
-            let mut final_colour     = MyVec3{x:0.0, y:0.0, z:0.0};
-            let     viewport_current = viewport_row + x as f64 * horizontal_step;
+            transmittance = current_medium.transmittance(shadow_ray.p, shadow_ray.direction, segment, sampler) * transmittance;
+        }
 
-            for _s in 0..rdr.samples_per_pixel
+        transmittance = transmittance * dielectric_transmittance(dielectrics.current(), segment);
+
+        if !f_blocked
+        {
+            return transmittance;
+        }
+
+        if let Some(inside) = ray_info.material.medium()
+        {
+            medium = if ray_info.is_front {Some(inside)} else {rdr.fog.as_ref()};
+        }
+        else
+        {
+            match ray_info.material.interior()
             {
-                let mut total_gain = MyVec3{x:1.0, y:1.0, z:1.0};
-                let mut ray_bounce = 0;
+                Some(interior) if dielectrics.is_false_intersection(ray_info.material, &interior, ray_info.is_front) =>
+                {
+                    dielectrics.cross(ray_info.material, interior, ray_info.is_front);
+                }
+                _ => return black,
+            }
+        }
 
-                // Cast rays at a random point in the neighbourhood of the exact point on the viewport and at a random time during the exposure
-                let     viewport_offset = (rng.gen::<f64>() - 0.5) * vertical_step + (rng.gen::<f64>() - 0.5) * horizontal_step;
-                let     cast_time       = rng.gen::<f64>() * rdr.camera.exposure_length.unwrap_or(0.0);
+        shadow_ray = Ray{p: ray_info.intersect, direction: shadow_ray.direction, cast_time: shadow_ray.cast_time};
+        remaining -= ray_info.ds;
+    }
+}
 
-                // Cast the initial ray from the camera
-                let mut r = rdr.camera.generate_ray(viewport_current + viewport_offset, cast_time);
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{material::{Diffuse, Emissive, MediumBoundary, Refractive, SharedMaterial}, filter::FilterType, film::FilmPixel, common::RandomStream};
+    use std::sync::Arc;
+    use rand::Rng;
+
+    // A diffuse floor lit only by a small spherical light (out of view), which scattered rays rarely find by chance
+    fn small_light_renderer(light_sampling: bool, tile_order: TileOrder, seed: u64, image_size: u32) -> Renderer
+    {
+        let floor = Arc::new(Diffuse {gain: MyVec3{x: 0.5, y: 0.5, z: 0.5}, texture: None});
+        let light = Arc::new(Emissive{colour: MyVec3{x: 1.0, y: 1.0, z: 1.0}, strength: 50.0});
+
+        let mut world_element = WorldElement::new();
+        world_element.add_sphere(0.0, -1000.0, 0.0, 1000.0, floor);
+        world_element.add_sphere(0.0, 2.0, 0.0, 0.2, light);
+        world_element.build_bvh(0.0, 0.0);
+
+        let camera = Camera::new(MyVec3{x: 0.0, y: 1.0, z: 4.0}, None, Some(MyVec3{x: 0.0, y: 0.0, z: 0.0}), None, None, None, None, 1.0, 0.5).unwrap();
+
+        Renderer::new(image_size, image_size, 16, 8, camera, world_element, Sky::Black, light_sampling, tile_order, SamplerType::Independent, seed)
+    }
+
+    // Looking along the axis of concentric balls, 2 across at most, at a light which is (almost) a flat wall 5 from the
+    // camera; the pixels see little more than the middle of the balls
+    fn light_wall_renderer(balls: &[(f64, SharedMaterial)]) -> Renderer
+    {
+        let light = Arc::new(Emissive{colour: MyVec3{x: 1.0, y: 1.0, z: 1.0}, strength: 1.0});
+
+        let mut world_element = WorldElement::new();
+        world_element.add_sphere(0.0, 0.0, -1005.0, 1000.0, light);
+
+        for (radius, material) in balls
+        {
+            world_element.add_sphere(0.0, 0.0, -2.5, *radius, material.clone());
+        }
 
-                loop {
-                    let (f_intersect, ray_info) = rdr.world_element.intersect_all(&r, 0.001, f64::INFINITY, cast_time);
+        world_element.build_bvh(0.0, 0.0);
 
-                    if f_intersect == false || ray_bounce > rdr.max_ray_bounce_depth
+        let camera = Camera::new(MyVec3{x: 0.0, y: 0.0, z: 0.0}, None, Some(MyVec3{x: 0.0, y: 0.0, z: -1.0}), None, None, None, None, 1.0, 0.05).unwrap();
+
+        Renderer::new(12, 12, 16, 8, camera, world_element, Sky::Black, true, TileOrder::Scanline, SamplerType::Independent, 1)
+    }
+
+    // Mean of each colour over the image
+    fn channel_means(renderer: &Renderer) -> [f64; 3]
+    {
+        let image = render(renderer, 2).framebuffer();
+
+        [0, 1, 2].map(|channel| image.iter().skip(channel).step_by(3).map(|&v| v as f64).sum::<f64>() / (image.len() / 3) as f64)
+    }
+
+    // Mean squared difference between two independent renders (different seeds) of the same scene (twice the per-pixel variance)
+    fn noise(light_sampling: bool) -> f64
+    {
+        let first  = render(&small_light_renderer(light_sampling, TileOrder::Scanline, 1, 12), 2).framebuffer();
+        let second = render(&small_light_renderer(light_sampling, TileOrder::Scanline, 2, 12), 2).framebuffer();
+
+        let sum: f64 = first.iter().zip(&second).map(|(&a, &b)| (a as f64 - b as f64) * (a as f64 - b as f64)).sum();
+
+        sum / first.len() as f64
+    }
+
+    // Every order must cover each pixel exactly once, including partial tiles at the right and bottom edges
+    #[test]
+    fn tiles_cover_image_once()
+    {
+        let (width, height) = (150, 70);
+
+        for tile_order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert]
+        {
+            let mut coverage = vec![0; (width * height) as usize];
+
+            for tile in generate_tiles(width, height, tile_order)
+            {
+                for y in tile.y0..tile.y0 + tile.height
+                {
+                    for x in tile.x0..tile.x0 + tile.width
                     {
-                        break;
+                        coverage[(y * width + x) as usize] += 1;
                     }
+                }
+            }
+
+            assert!(coverage.iter().all(|&c| c == 1), "{:?} order does not cover the image exactly once", tile_order);
+        }
+    }
+
+    // A smooth image (sky only) converges straight away so takes the minimum number of samples, as do the pixels of the
+    // black sky above the horizon of the small light scene; the noisy floor does not reach a very low target so its
+    // pixels take the maximum
+    #[test]
+    fn adaptive_sampling_stops_when_converged()
+    {
+        let camera = Camera::new(MyVec3{x: 0.0, y: 0.0, z: 0.0}, None, Some(MyVec3{x: 0.0, y: 1.0, z: -1.0}), None, None, None, None, 1.0, 0.5).unwrap();
+
+        let mut sky_renderer = Renderer::new(8, 8, 64, 8, camera, WorldElement::new(), Sky::Gradient, true, TileOrder::Scanline, SamplerType::Independent, 0);
+        sky_renderer.set_adaptive_sampling(Some(AdaptiveSampling { min_samples: 4, target_error: 0.01 }));
 
-                    let scatter_direction = scatter(r, &ray_info);
+        assert!(render(&sky_renderer, 1).sample_counts().iter().all(|&count| count == 4));
 
-                    r = Ray{p: ray_info.intersect, direction: scatter_direction, cast_time: r.cast_time};
+        let mut noisy_renderer = small_light_renderer(true,  TileOrder::Scanline, 0, 8);
+        noisy_renderer.set_adaptive_sampling(Some(AdaptiveSampling { min_samples: 4, target_error: 1e-6 }));
 
-                    total_gain = total_gain * ray_info.material.gain;
+        let film = render(&noisy_renderer, 1);
 
-                    ray_bounce += 1;
+        for (pixel, &count) in film.framebuffer().chunks_exact(3).zip(&film.sample_counts())
+        {
+            let expected_count = if pixel.iter().all(|&c| c == 0.0) {4} else {16};
+
+            assert_eq!(count, expected_count);
+        }
+    }
+
+    // The same seed gives the same image however the work is divided between threads (the image is several tiles)
+    #[test]
+    fn render_is_deterministic()
+    {
+        let first  = render(&small_light_renderer(true, TileOrder::Scanline, 7, 80), 1).framebuffer();
+        let second = render(&small_light_renderer(true, TileOrder::Hilbert,  7, 80), 3).framebuffer();
+
+        assert!(first == second, "renders with the same seed differ");
+    }
+
+    // Progressive passes (3 samples per pixel at a time, the last pass short) add up to the same image as a single
+    // pass, and a render stopped between passes is the image of the samples taken so far
+    #[test]
+    fn progressive_passes_accumulate()
+    {
+        let renderer = small_light_renderer(true, TileOrder::Spiral, 9, 40);
+        let mut film = Film::new(40, 40);
+
+        for sample_limit in [3, 6]
+        {
+            render_pass(&renderer, &mut film, sample_limit, 2, &AtomicBool::new(false));
+        }
+
+        render_pass(&renderer, &mut film, 9, 2, &AtomicBool::new(true));
+
+        let mut short_renderer = small_light_renderer(true, TileOrder::Spiral, 9, 40);
+        short_renderer.samples_per_pixel = 6;
+
+        assert!(film.sample_counts().iter().all(|&count| count == 6));
+        assert!(film.framebuffer() == render(&short_renderer, 2).framebuffer(), "stopped render differs from a render of the same samples");
+
+        for sample_limit in [9, 12, 15, 18]
+        {
+            render_pass(&renderer, &mut film, sample_limit, 2, &AtomicBool::new(false));
+        }
+
+        assert!(film.sample_counts().iter().all(|&count| count == 16));
+        assert!(film.framebuffer() == render(&renderer, 2).framebuffer(), "progressive render differs from a single pass");
+    }
+
+    // Splatting samples of a flat image through any filter gives the same flat image, including the pixels along tile
+    // edges which are shared between the splats of several tiles
+    #[test]
+    fn filters_preserve_flat_images()
+    {
+        let colour = MyVec3{x: 0.25, y: 0.5, z: 1.0};
+
+        for filter_type in [FilterType::Box, FilterType::Tent, FilterType::Gaussian, FilterType::Mitchell, FilterType::Lanczos]
+        {
+            let filter   = Filter::new(filter_type, None).unwrap();
+            let mut film = Film::new(70, 40);
+            let mut rng  = RandomStream::new(0, 0, 0);
+
+            for tile in generate_tiles(70, 40, TileOrder::Hilbert)
+            {
+                let mut splats = TileSplats::new(tile, filter.pixel_reach(), 70, 40);
+
+                for (x, y) in (tile.y0..tile.y0 + tile.height).flat_map(|y| (tile.x0..tile.x0 + tile.width).map(move |x| (x, y)))
+                {
+                    for _ in 0..16
+                    {
+                        splats.add(&filter, x, y, x as f64 + rng.gen::<f64>() - 0.5, y as f64 + rng.gen::<f64>() - 0.5, colour);
+                    }
                 }
 
-                // Colour is determined by the ray's final direction (i.e. the ray which is the source of the light which comes from the background in this case)
-                let w              = 0.5 * (vec3_normalize(r.direction).y + 1.0);
-                let sky_box_colour = (1.0 - w) * MyVec3{x: 1.0, y: 1.0, z: 1.0} + w * MyVec3{x: 0.5, y: 0.7, z: 1.0};
+                add_to_film(&mut film, &splats);
+            }
 
-                final_colour       = final_colour + total_gain * sky_box_colour;
+            for pixel in film.framebuffer().chunks_exact(3)
+            {
+                assert!((pixel[0] - 0.25).abs() < 1e-5 && (pixel[1] - 0.5).abs() < 1e-5 && (pixel[2] - 1.0).abs() < 1e-5,
+                        "{:?} filter gives {:?}", filter_type, pixel);
             }
+        }
+    }
 
-            final_colour = final_colour / rdr.samples_per_pixel as f64;
+    // With a single sample per pixel the negative lobes of the Lanczos filter can cancel out most of a pixel's weight;
+    // the image must still be finite, never negative, and no brighter than the brightest thing in view (the floor lit
+    // from straight above is well below the light's strength)
+    #[test]
+    fn single_sample_lanczos_is_bounded()
+    {
+        let mut renderer = small_light_renderer(true, TileOrder::Scanline, 3, 40);
+        renderer.set_sampling(1, SamplerType::Independent, 3);
+        renderer.set_filter(Filter::new(FilterType::Lanczos, Some(3.0)).unwrap());
+
+        let image = render(&renderer, 2).framebuffer();
+
+        assert!(image.iter().all(|&v| v.is_finite() && (0.0..50.0).contains(&v)), "{:?}", image.iter().copied().fold(f32::NAN, f32::max));
+        assert!(image.iter().any(|&v| v > 0.0));
+
+        // A pixel whose weights almost cancel is treated as empty, and a negative average as black
+        let mut film = Film::new(2, 1);
+        *film.pixel_mut(0, 0) = FilmPixel { colour_sum: MyVec3{x: 1.0, y: 1.0, z: 1.0}, weight_sum: 1e-9, ..FilmPixel::default() };
+        *film.pixel_mut(1, 0) = FilmPixel { colour_sum: MyVec3{x: -1.0, y: 0.5, z: 1.0}, weight_sum: 0.5, ..FilmPixel::default() };
 
-            // Coarse gamma adjust
-            let rd = f64::clamp(f64::sqrt(final_colour.x), 0.0, 0.999);
-            let gn = f64::clamp(f64::sqrt(final_colour.y), 0.0, 0.999);
-            let bl = f64::clamp(f64::sqrt(final_colour.z), 0.0, 0.999);
+        assert_eq!(film.framebuffer(), vec![0.0, 0.0, 0.0, 0.0, 1.0, 2.0]);
+    }
+
+    // Looking through black (purely absorbing) fog and a ball of black smoke at a light, the light reaching the camera is
+    // reduced by the transmittance of the fog outside the ball and of the smoke inside it
+    #[test]
+    fn media_attenuate_light()
+    {
+        let black = MyVec3{x: 0.0, y: 0.0, z: 0.0};
+        let smoke = Arc::new(MediumBoundary{medium: Medium::new(0.5, black, 0.0).unwrap()});
 
-            bitmap[bitmap_idx] = (256.0 * rd) as u8; bitmap_idx += 1;
-            bitmap[bitmap_idx] = (256.0 * gn) as u8; bitmap_idx += 1;
-            bitmap[bitmap_idx] = (256.0 * bl) as u8; bitmap_idx += 1;
+        let mut renderer = light_wall_renderer(&[(1.0, smoke)]);
+        renderer.set_fog(Some(Medium::new(0.1, black, 0.0).unwrap()));
+
+        let image = render(&renderer, 2).framebuffer();
+        let mean  = image.iter().map(|&v| v as f64).sum::<f64>() / image.len() as f64;
+
+        let expected = f64::exp(-0.1 * 3.0) * f64::exp(-0.5 * 2.0);
+
+        assert!((mean - expected).abs() < 0.03, "mean {} expected {}", mean, expected);
+    }
+
+    // An empty scene has no bounds for the fog to end at, so the fog is absent and the sky is seen as it is
+    #[test]
+    fn fog_absent_from_empty_scene()
+    {
+        let camera = Camera::new(MyVec3{x: 0.0, y: 0.0, z: 0.0}, None, Some(MyVec3{x: 0.0, y: 1.0, z: -1.0}), None, None, None, None, 1.0, 0.5).unwrap();
+
+        let mut renderer = Renderer::new(8, 8, 4, 8, camera, WorldElement::new(), Sky::Gradient, true, TileOrder::Scanline, SamplerType::Independent, 0);
+        let clear        = render(&renderer, 1).framebuffer();
+
+        renderer.set_fog(Some(Medium::new(0.5, MyVec3{x: 0.0, y: 0.0, z: 0.0}, 0.0).unwrap()));
+
+        let foggy = render(&renderer, 1).framebuffer();
+
+        assert!(clear.iter().zip(&foggy).all(|(&a, &b)| (a - b).abs() < 0.01 && b > 0.4), "fog hides the sky of an empty scene");
+    }
+
+    // Looking through a ball of coloured glass at a light, each colour is reduced by the absorption over the ball's
+    // diameter (an index of refraction of one, so the rays pass straight through without reflections)
+    #[test]
+    fn dielectrics_absorb_light()
+    {
+        let glass = Arc::new(Refractive{gain: MyVec3{x: 1.0, y: 1.0, z: 1.0}, index_of_refraction: 1.0, roughness: 0.0, absorption: MyVec3{x: 0.1, y: 0.5, z: 1.0}, priority: 0});
+
+        let means = channel_means(&light_wall_renderer(&[(1.0, glass)]));
+
+        for (channel, absorption) in [0.1, 0.5, 1.0].iter().enumerate()
+        {
+            let expected = f64::exp(-absorption * 2.0);
+
+            assert!((means[channel] - expected).abs() < 0.01, "channel {}: mean {} expected {}", channel, means[channel], expected);
         }
     }
-}
\ No newline at end of file
+
+    // A ball of glass with a ball of water half its size at its centre. With the water at the lower priority its
+    // surface is a false intersection inside the glass, and the light is only absorbed by the glass; at the higher
+    // priority the light passes from glass to water and back, and is absorbed by each over half the distance. Either
+    // way the rays pass (almost) straight through, and the light is also reduced by the Fresnel reflection at each
+    // true boundary.
+    #[test]
+    fn nested_dielectrics_refract_and_absorb()
+    {
+        let glass_absorption = [0.1, 0.5, 1.0];
+        let water_absorption = [2.0, 1.0, 0.5];
+
+        let dielectric = |index_of_refraction: f64, absorption: [f64; 3], priority: u32| -> SharedMaterial
+        {
+            Arc::new(Refractive{gain: MyVec3{x: 1.0, y: 1.0, z: 1.0}, index_of_refraction, roughness: 0.0, absorption: MyVec3{x: absorption[0], y: absorption[1], z: absorption[2]}, priority})
+        };
+
+        // Fraction of light passing through a boundary face-on
+        let transmitted = |n1: f64, n2: f64| 1.0 - f64::powi((n1 - n2) / (n1 + n2), 2);
+
+        for water_priority in [1, 3]
+        {
+            let means = channel_means(&light_wall_renderer(&[(1.0, dielectric(1.5, glass_absorption, 2)), (0.5, dielectric(1.33, water_absorption, water_priority))]));
+
+            for channel in 0..3
+            {
+                let expected = if water_priority == 1
+                {
+                    f64::powi(transmitted(1.0, 1.5), 2) * f64::exp(-glass_absorption[channel] * 2.0)
+                }
+                else
+                {
+                    f64::powi(transmitted(1.0, 1.5) * transmitted(1.5, 1.33), 2) * f64::exp(-glass_absorption[channel] - water_absorption[channel])
+                };
+
+                assert!((means[channel] - expected).abs() < 0.01, "water priority {}, channel {}: mean {} expected {}", water_priority, channel, means[channel], expected);
+            }
+        }
+    }
+
+    // A ball of white smoke which scatters light (almost) straight on, inside a ball of coloured glass: the light is
+    // absorbed over the whole diameter of the glass, including the parts of the path which end at a particle of smoke
+    #[test]
+    fn dielectrics_absorb_scattered_light()
+    {
+        let white = MyVec3{x: 1.0, y: 1.0, z: 1.0};
+        let glass = Arc::new(Refractive{gain: white, index_of_refraction: 1.0, roughness: 0.0, absorption: MyVec3{x: 0.1, y: 0.5, z: 1.0}, priority: 0});
+        let smoke = Arc::new(MediumBoundary{medium: Medium::new(2.0, white, 0.999).unwrap()});
+
+        let means = channel_means(&light_wall_renderer(&[(1.0, glass), (0.5, smoke)]));
+
+        for (channel, absorption) in [0.1, 0.5, 1.0].iter().enumerate()
+        {
+            let expected = f64::exp(-absorption * 2.0);
+
+            assert!((means[channel] - expected).abs() < 0.02, "channel {}: mean {} expected {}", channel, means[channel], expected);
+        }
+    }
+
+    // Shadow rays, like paths, pass through the surface of a dielectric object inside one of higher priority and are
+    // absorbed by the object they are in, but are blocked by a true boundary between dielectrics
+    #[test]
+    fn shadow_rays_skip_false_intersections()
+    {
+        let dielectric = |absorption: f64, priority: u32| -> SharedMaterial
+        {
+            Arc::new(Refractive{gain: MyVec3{x: 1.0, y: 1.0, z: 1.0}, index_of_refraction: 1.5, roughness: 0.0, absorption: MyVec3{x: absorption, y: absorption, z: absorption}, priority})
+        };
+
+        for (water_priority, expected) in [(1, f64::exp(-0.2 * 2.5)), (3, 0.0)]
+        {
+            let glass = dielectric(0.2, 2);
+            let water = dielectric(1.0, water_priority);
+
+            let mut world_element = WorldElement::new();
+            world_element.add_sphere(0.0, 0.0, 0.0, 2.0, glass.clone());
+            world_element.add_sphere(0.0, 0.0, -1.0, 0.5, water);
+            world_element.build_bvh(0.0, 0.0);
+
+            let camera   = Camera::new(MyVec3{x: 0.0, y: 0.0, z: 5.0}, None, Some(MyVec3{x: 0.0, y: 0.0, z: 0.0}), None, None, None, None, 1.0, 0.5).unwrap();
+            let renderer = Renderer::new(1, 1, 1, 8, camera, world_element, Sky::Black, true, TileOrder::Scanline, SamplerType::Independent, 0);
+
+            let mut dielectrics = DielectricStack::new();
+            dielectrics.cross(glass.as_ref(), glass.interior().unwrap(), true);
+
+            let shadow_ray    = Ray{p: MyVec3{x: 0.0, y: 0.0, z: 1.0}, direction: MyVec3{x: 0.0, y: 0.0, z: -1.0}, cast_time: 0.0};
+            let transmittance = shadow_transmittance(&renderer, shadow_ray, 2.5, None, &dielectrics, SamplerType::Independent.create(0, 1).as_mut());
+
+            assert!((transmittance.x - expected).abs() < 1e-9, "water priority {}: transmittance {:?} expected {}", water_priority, transmittance, expected);
+        }
+    }
+
+    // The minimum number of samples is at least two and at most samples_per_pixel, and adaptive sampling is refused
+    // when only one sample is allowed
+    #[test]
+    fn adaptive_sampling_limits()
+    {
+        assert_eq!(AdaptiveSampling::new(16, 64, 0.01).unwrap().min_samples, 16);
+        assert_eq!(AdaptiveSampling::new(1, 64, 0.01).unwrap().min_samples, 2);
+        assert_eq!(AdaptiveSampling::new(16, 8, 0.01).unwrap().min_samples, 8);
+        assert_eq!(AdaptiveSampling::new(16, 2, 0.01).unwrap().min_samples, 2);
+        assert!(AdaptiveSampling::new(16, 1, 0.01).is_err());
+    }
+
+    // A render stopped part way through and continued (as when resuming a checkpoint) gives the same image as one
+    // rendered in a single pass
+    #[test]
+    fn stopped_render_resumes()
+    {
+        let renderer = small_light_renderer(true, TileOrder::Scanline, 5, 40);
+        let mut film = Film::new(40, 40);
+
+        render_pass(&renderer, &mut film, 4, 2, &AtomicBool::new(false));
+        render_pass(&renderer, &mut film, 16, 2, &AtomicBool::new(true));
+
+        assert!(film.sample_counts().iter().all(|&count| count == 4));
+
+        render_pass(&renderer, &mut film, 16, 2, &AtomicBool::new(false));
+
+        assert!(film.framebuffer() == render(&renderer, 2).framebuffer(), "resumed render differs");
+    }
+
+    #[test]
+    fn light_sampling_reduces_noise()
+    {
+        let noise_with_light_sampling    = noise(true);
+        let noise_without_light_sampling = noise(false);
+
+        assert!(noise_with_light_sampling < 0.02 * noise_without_light_sampling,
+                "light sampling noise {} is not much lower than scattering-only noise {}", noise_with_light_sampling, noise_without_light_sampling);
+    }
+}
@@ -0,0 +1,380 @@
+// Samplers hand out the "random" numbers used by each sample of a pixel
+//
+// A sample uses a sequence of dimensions (pixel position, time, lens position, then for each bounce the light sample
+// and the scattered direction), each a 1D or 2D request in [0, 1). A low-discrepancy sampler spreads the values of
+// every dimension evenly over the samples of a pixel, rather than leaving clumps and gaps as independent random
+// numbers do, so the pixel converges with fewer samples. Each request takes the next dimension; the values depend
+// only on (seed, pixel, sample, dimension), so images remain deterministic whichever thread renders the pixel.
+
+use rand::Rng;
+
+use crate::common::{RandomStream, mix64};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum, Default)]
+pub enum SamplerType
+{
+    #[default]
+    Independent,    // Uniform random numbers
+    Stratified,     // Jittered: one value in each of N equal strata (2D: a jittered grid), strata shuffled per dimension
+    Halton,         // Radical inverse in a different prime base per dimension, Owen-scrambled per pixel
+    Sobol           // Owen-scrambled Sobol (0, 2) sequence, padded to higher dimensions by shuffling (Burley 2020)
+}
+
+impl SamplerType
+{
+    pub fn create(&self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler>
+    {
+        match self
+        {
+            SamplerType::Independent => Box::new(IndependentSampler { seed, rng: RandomStream::new(seed, 0, 0) }),
+            SamplerType::Stratified  => Box::new(StratifiedSampler  { seed, samples_per_pixel, state: SampleState::default(), rng: RandomStream::new(seed, 0, 0) }),
+            SamplerType::Halton      => Box::new(HaltonSampler      { seed, state: SampleState::default(), rng: RandomStream::new(seed, 0, 0) }),
+            SamplerType::Sobol       => Box::new(SobolSampler       { seed, state: SampleState::default() }),
+        }
+    }
+}
+
+pub trait Sampler
+{
+    // Begin sample sample_index of the pixel; the following requests start again from the first dimension
+    fn start_sample(&mut self, pixel_index: u64, sample_index: u32);
+
+    fn next_1d(&mut self) -> f64;
+
+    fn next_2d(&mut self) -> (f64, f64);
+}
+
+// The pixel, sample, and next dimension of the current sample
+#[derive(Debug, Default, Copy, Clone)]
+struct SampleState
+{
+    pixel_index:  u64,
+    sample_index: u32,
+    dimension:    u32
+}
+
+impl SampleState
+{
+    fn start(&mut self, pixel_index: u64, sample_index: u32)
+    {
+        *self = SampleState { pixel_index, sample_index, dimension: 0 };
+    }
+
+    fn next_dimension(&mut self) -> u32
+    {
+        self.dimension += 1;
+
+        self.dimension - 1
+    }
+
+    // Hash of (seed, pixel, dimension), the same for every sample of the pixel
+    fn dimension_hash(&self, seed: u64, dimension: u32) -> u64
+    {
+        RandomStream::new(seed, self.pixel_index, dimension as u64).gen::<u64>()
+    }
+}
+
+/*
+ * Independent
+ */
+
+pub struct IndependentSampler
+{
+    seed: u64,
+    rng:  RandomStream
+}
+
+impl Sampler for IndependentSampler
+{
+    fn start_sample(&mut self, pixel_index: u64, sample_index: u32)
+    {
+        self.rng = RandomStream::new(self.seed, pixel_index, sample_index as u64);
+    }
+
+    fn next_1d(&mut self) -> f64
+    {
+        self.rng.gen::<f64>()
+    }
+
+    fn next_2d(&mut self) -> (f64, f64)
+    {
+        (self.rng.gen::<f64>(), self.rng.gen::<f64>())
+    }
+}
+
+/*
+ * Stratified
+ */
+
+pub struct StratifiedSampler
+{
+    seed:              u64,
+    samples_per_pixel: u32,
+    state:             SampleState,
+    rng:               RandomStream    // Jitter within the strata
+}
+
+impl Sampler for StratifiedSampler
+{
+    fn start_sample(&mut self, pixel_index: u64, sample_index: u32)
+    {
+        self.state.start(pixel_index, sample_index);
+        self.rng = RandomStream::new(self.seed, pixel_index, sample_index as u64);
+    }
+
+    fn next_1d(&mut self) -> f64
+    {
+        let dimension = self.state.next_dimension();
+        let n         = self.samples_per_pixel;
+        let stratum   = permute(self.state.sample_index % n, n, self.state.dimension_hash(self.seed, dimension) as u32);
+
+        (stratum as f64 + self.rng.gen::<f64>()) / n as f64
+    }
+
+    fn next_2d(&mut self) -> (f64, f64)
+    {
+        // As square a grid as possible with at least one cell per sample; when the number of samples is not a product
+        // of the grid sides some cells are left empty
+        let dimension = self.state.next_dimension();
+        let nx        = u32::max(1, f64::sqrt(self.samples_per_pixel as f64) as u32);
+        let ny        = self.samples_per_pixel.div_ceil(nx);
+        let cell      = permute(self.state.sample_index % (nx * ny), nx * ny, self.state.dimension_hash(self.seed, dimension) as u32);
+
+        ((((cell % nx) as f64) + self.rng.gen::<f64>()) / nx as f64, (((cell / nx) as f64) + self.rng.gen::<f64>()) / ny as f64)
+    }
+}
+
+// Element i of a random permutation of 0..l chosen by p, without storing the permutation (Kensler 2013)
+fn permute(i: u32, l: u32, p: u32) -> u32
+{
+    if l <= 1
+    {
+        return 0;
+    }
+
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    // Cycle walking: repeat the bijection on the next power of two until the result is in range
+    let mut i = i;
+
+    loop
+    {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+
+        if i < l
+        {
+            return (i.wrapping_add(p)) % l;
+        }
+    }
+}
+
+/*
+ * Halton
+ */
+
+const PRIMES: [u32; 32] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131];
+
+pub struct HaltonSampler
+{
+    seed:  u64,
+    state: SampleState,
+    rng:   RandomStream     // Dimensions beyond the table of primes are independent random numbers
+}
+
+impl HaltonSampler
+{
+    fn next(&mut self) -> f64
+    {
+        let dimension = self.state.next_dimension();
+
+        match PRIMES.get(dimension as usize)
+        {
+            Some(&base) => owen_scrambled_radical_inverse(self.state.sample_index, base, self.state.dimension_hash(self.seed, dimension)),
+            None        => self.rng.gen::<f64>(),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler
+{
+    fn start_sample(&mut self, pixel_index: u64, sample_index: u32)
+    {
+        self.state.start(pixel_index, sample_index);
+        self.rng = RandomStream::new(self.seed, pixel_index, sample_index as u64);
+    }
+
+    fn next_1d(&mut self) -> f64
+    {
+        self.next()
+    }
+
+    fn next_2d(&mut self) -> (f64, f64)
+    {
+        let u = self.next();
+
+        (u, self.next())
+    }
+}
+
+// The digits of index in the given base mirrored about the decimal point, with each digit randomly permuted by a
+// permutation which depends on the digits before it (Owen scrambling). Unscrambled, the first few points of the higher
+// bases are nearly the same in every dimension (i / base), which correlates the dimensions badly
+fn owen_scrambled_radical_inverse(index: u32, base: u32, hash: u64) -> f64
+{
+    let recip_base          = 1.0 / base as f64;
+    let mut i               = index as u64;
+    let mut reversed_digits = 0u64;
+    let mut scale           = 1.0;
+
+    // Leading zero digits are permuted too, so continue until the digits are below the precision of an f64
+    while 1.0 - scale < 1.0
+    {
+        let digit       = (i % base as u64) as u32;
+        let digit_hash  = mix64(hash ^ reversed_digits) as u32;
+
+        reversed_digits = reversed_digits * base as u64 + permute(digit, base, digit_hash) as u64;
+        scale          *= recip_base;
+        i              /= base as u64;
+    }
+
+    f64::min(scale * reversed_digits as f64, 1.0 - f64::EPSILON)
+}
+
+/*
+ * Sobol (Owen-scrambled)
+ */
+
+pub struct SobolSampler
+{
+    seed:  u64,
+    state: SampleState
+}
+
+impl SobolSampler
+{
+    // The first two Sobol dimensions, for a shuffled index and scrambled per dimension
+    // Every request has its own shuffle of the sample indices, so different requests are not correlated
+    fn sobol_2d(&mut self) -> (f64, f64)
+    {
+        let dimension = self.state.next_dimension();
+        let hash      = self.state.dimension_hash(self.seed, dimension);
+
+        let index = nested_uniform_scramble(self.state.sample_index, hash as u32);
+        let x     = nested_uniform_scramble(index.reverse_bits(), (hash >> 32) as u32);
+        let y     = nested_uniform_scramble(sobol_second_dimension(index), (hash >> 32) as u32 ^ 0x9e3779b9);
+
+        (x as f64 / 4294967296.0, y as f64 / 4294967296.0)
+    }
+}
+
+impl Sampler for SobolSampler
+{
+    fn start_sample(&mut self, pixel_index: u64, sample_index: u32)
+    {
+        self.state.start(pixel_index, sample_index);
+    }
+
+    fn next_1d(&mut self) -> f64
+    {
+        self.sobol_2d().0
+    }
+
+    fn next_2d(&mut self) -> (f64, f64)
+    {
+        self.sobol_2d()
+    }
+}
+
+// Sobol dimension 1 (primitive polynomial x + 1); the direction numbers are v[0] = 1 << 31, v[i] = v[i-1] ^ (v[i-1] >> 1)
+fn sobol_second_dimension(index: u32) -> u32
+{
+    let mut result    = 0;
+    let mut direction = 1u32 << 31;
+    let mut i         = index;
+
+    while i != 0
+    {
+        if i & 1 != 0
+        {
+            result ^= direction;
+        }
+
+        i         >>= 1;
+        direction  ^= direction >> 1;
+    }
+
+    result
+}
+
+// Owen scrambling: a random permutation of each binary digit which depends on the digits above it, done with a hash
+// which only lets lower bits affect higher bits (Laine and Karras 2011) applied to the bit-reversed value
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32
+{
+    let mut v = x.reverse_bits();
+
+    v = v.wrapping_add(seed);
+    v ^= v.wrapping_mul(0x6c50b47c);
+    v ^= v.wrapping_mul(0xb82f1e52);
+    v ^= v.wrapping_mul(0xc7afe638);
+    v ^= v.wrapping_mul(0x8d22f6e6);
+
+    v.reverse_bits()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // The low-discrepancy samplers must put exactly one of N samples in each of the N equal intervals of [0, 1) in
+    // every pixel (for the first dimension; later Halton dimensions use other bases)
+    #[test]
+    fn samples_are_stratified()
+    {
+        const N: u32 = 16;
+
+        for sampler_type in [SamplerType::Stratified, SamplerType::Halton, SamplerType::Sobol]
+        {
+            let mut sampler = sampler_type.create(3, N);
+
+            for pixel_index in 0..4
+            {
+                let mut strata = [0; N as usize];
+
+                for sample_index in 0..N
+                {
+                    sampler.start_sample(pixel_index, sample_index);
+
+                    let u = sampler.next_1d();
+
+                    assert!((0.0..1.0).contains(&u));
+                    strata[(u * N as f64) as usize] += 1;
+                }
+
+                assert!(strata.iter().all(|&count| count == 1), "{:?} samples are not stratified: {:?}", sampler_type, strata);
+            }
+        }
+    }
+}
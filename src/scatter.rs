@@ -1,37 +1,31 @@
-use crate::{my_vec3::MyVec3, ray::Ray, rayinfo::RayInfo, common::{uniform_random, random_point_in_unit_sphere}};
+// Scattering at surfaces: choosing the direction of the scattered ray, and the BSDF for light sampling, for the
+// materials in material.rs
 
-#[derive(Debug, Copy, Clone)]
-pub enum ScatteringType
-{
-    DiffuseScattering,
-    MetallicScattering,
-    RefractiveScattering
-}
+use crate::{my_vec3::{MyVec3, vec3_normalize, vec3_orthonormal_basis}, ray::Ray, common::unit_sphere_surface_from_square, sampler::Sampler,
+            microfacet::{self, Ggx}, principled::PrincipledBsdf};
 
-// #derive(Default) is experimental for enums, so implement explicitly instead
-impl Default for ScatteringType
+// A scattered direction, and the factor by which the light arriving along it is multiplied on its way towards the
+// camera (the BSDF times the cosine factor over the density of the direction, including the colour of the surface)
+#[derive(Debug, Copy, Clone)]
+pub struct ScatterSample
 {
-    fn default() -> Self {ScatteringType::DiffuseScattering}
+    pub direction: MyVec3,
+    pub weight:    MyVec3,
+    pub pdf:       Option<f64>      // Density (with respect to solid angle) of direction; None for a specular reflection or refraction
 }
 
-
-pub fn scatter(r: Ray, ray_info: &RayInfo) -> MyVec3
+// Density (with respect to solid angle) with which diffuse_scatter chooses direction; the scattered directions
+// have a cosine distribution about the normal
+pub fn diffuse_pdf(normal: MyVec3, direction: MyVec3) -> f64
 {
-    match ray_info.material.surface
-    {
-        ScatteringType::DiffuseScattering    => diffuse_scatter   (r, ray_info.normal),
-        ScatteringType::MetallicScattering   => metallic_scatter  (r, ray_info.normal, ray_info.material.metal_fuzz.unwrap_or(0.0)),
-        ScatteringType::RefractiveScattering => refractive_scatter(r, ray_info.normal, ray_info.is_front, 0.0, ray_info.material.index_of_refraction.unwrap_or(0.0)),
-    }
+    f64::max(0.0, normal.dot(direction) / direction.length()) / std::f64::consts::PI
 }
 
-pub fn diffuse_scatter(_ray: Ray, normal: MyVec3) -> MyVec3
+#[allow(clippy::needless_return)]
+pub fn diffuse_scatter(_ray: Ray, normal: MyVec3, sampler: &mut dyn Sampler) -> MyVec3
 {
-    // Generate the scattering ray, unless the ray is very small (thus causing numerical errors and possible NaNs) in which case we regenerate
-    let mut scatter_offset = random_point_in_unit_sphere();
-
-    // Random point on the unit circle
-    scatter_offset.normalize();
+    // Random point on the unit sphere; adding it to the normal gives a cosine distribution of directions
+    let scatter_offset = unit_sphere_surface_from_square(sampler.next_2d());
 
     // Generate the scattering ray, unless the ray is very small (thus causing numerical errors and possible NaNs) in which case we just use the normal
     let mut scatter_direction = normal + scatter_offset;
@@ -44,72 +38,202 @@ pub fn diffuse_scatter(_ray: Ray, normal: MyVec3) -> MyVec3
     return scatter_direction;
 }
 
-pub fn metallic_scatter(ray: Ray, normal: MyVec3, fuzz_extent: f64) -> MyVec3
+// Co-ordinates with z along the normal (which faces the incoming ray), as used by the microfacet functions
+struct ShadingFrame
 {
-    let ray_to_normal_projection = ray.direction.dot(normal) * normal;
-    let mut scatter_direction    = ray.direction - 2.0 * ray_to_normal_projection;
+    a: MyVec3,
+    b: MyVec3,
+    n: MyVec3
+}
 
-    // Fuzzy reflections
-    let fuzz_vector = fuzz_extent * random_point_in_unit_sphere();
-    
-    // Normalize before adding fuzz so that the fuzz is consistently applied for all input angles
-    // Consider removing for speed
-    scatter_direction.normalize();
+impl ShadingFrame
+{
+    fn new(normal: MyVec3) -> ShadingFrame
+    {
+        let n      = vec3_normalize(normal);
+        let (a, b) = vec3_orthonormal_basis(n);
 
-    scatter_direction = scatter_direction + fuzz_vector;
+        ShadingFrame { a, b, n }
+    }
 
-    // Ensure that the generated ray points out, if not replace with the normal
-    scatter_direction = if normal.dot(scatter_direction) > 0.0 {scatter_direction} else {normal};
+    // With the x axis horizontal, along the circle about the world's vertical (y) axis through the intersect, so that
+    // anisotropic highlights are stretched in a direction which varies smoothly over the surface (except where the
+    // normal is vertical, where any horizontal direction will do)
+    fn horizontal(normal: MyVec3) -> ShadingFrame
+    {
+        let n       = vec3_normalize(normal);
+        let tangent = MyVec3 { x: 0.0, y: 1.0, z: 0.0 }.cross(n);
 
-    return scatter_direction;
+        if tangent.squared_length() < 1e-12
+        {
+            return ShadingFrame::new(n);
+        }
+
+        let a = vec3_normalize(tangent);
+
+        ShadingFrame { a, b: n.cross(a), n }
+    }
+
+    fn to_local(&self, v: MyVec3) -> MyVec3
+    {
+        MyVec3 { x: v.dot(self.a), y: v.dot(self.b), z: v.dot(self.n) }
+    }
+
+    fn to_world(&self, v: MyVec3) -> MyVec3
+    {
+        v.x * self.a + v.y * self.b + v.z * self.n
+    }
 }
 
-pub fn refractive_scatter(ray: Ray, normal: MyVec3, is_front: bool, reflectivity: f64, refractive_index: f64) -> MyVec3
+// GGX reflection from a conductor whose normal faces the incoming ray r; fresnel gives the fraction of the light
+// reflected at a facet from the cosine of the angle of incidence. A perfect mirror below microfacet::MIN_ALPHA
+pub fn conductor_scatter(r: Ray, normal: MyVec3, alpha: f64, fresnel: &dyn Fn(f64) -> MyVec3, sampler: &mut dyn Sampler) -> Option<ScatterSample>
 {
-    // Some reflections and some refraction
-    let x = uniform_random();
-    
-    if x < reflectivity  // Fixed surface reflection
+    let frame = ShadingFrame::new(normal);
+    let wo    = frame.to_local(-1.0 * vec3_normalize(r.direction));
+
+    if wo.z <= 0.0
     {
-        return reflect(ray, normal);
+        return None;
     }
-    else        // Refraction or reflection dependent on the angle of incidence (since total internal reflection can sometimes occur rather than refraction when going from high refractive indices to low)
+
+    let ggx = Ggx::new(alpha);
+
+    if ggx.is_smooth()
+    {
+        let wi = MyVec3 { x: -wo.x, y: -wo.y, z: wo.z };
+
+        return Some(ScatterSample { direction: frame.to_world(wi), weight: fresnel(wo.z), pdf: None });
+    }
+
+    let h  = ggx.sample_visible_normal(wo, sampler.next_2d());
+    let wi = microfacet::reflect(wo, h);
+
+    if wi.z <= 0.0
+    {
+        return None;
+    }
+
+    let weight = ggx.g2(wo, wi) / ggx.g1(wo) * fresnel(wo.dot(h));
+    let pdf    = ggx.visible_normal_pdf(wo, h) / (4.0 * wo.dot(h));
+
+    Some(ScatterSample { direction: frame.to_world(wi), weight, pdf: Some(pdf) })
+}
+
+// BSDF times cosine, and density, of conductor_scatter for light arriving along the unit vector direction
+pub fn conductor_evaluate(r: Ray, normal: MyVec3, alpha: f64, fresnel: &dyn Fn(f64) -> MyVec3, direction: MyVec3) -> (MyVec3, f64)
+{
+    let frame = ShadingFrame::new(normal);
+    let wo    = frame.to_local(-1.0 * vec3_normalize(r.direction));
+    let wi    = frame.to_local(direction);
+    let ggx   = Ggx::new(alpha);
+
+    if wo.z <= 0.0 || wi.z <= 0.0 || ggx.is_smooth()
     {
-        let eta = if is_front {1.0 / refractive_index} else {refractive_index};
+        return (MyVec3 { x: 0.0, y: 0.0, z: 0.0 }, 0.0);
+    }
+
+    let h = vec3_normalize(wo + wi);
 
-        let mut ray_unit_vector = ray.direction;
+    let f_cos = ggx.d(h) * ggx.g2(wo, wi) / (4.0 * wo.z) * fresnel(wo.dot(h));
+    let pdf   = ggx.visible_normal_pdf(wo, h) / (4.0 * wo.dot(h));
 
-        ray_unit_vector.normalize();
+    (f_cos, pdf)
+}
+
+// Rough glass: the ray reflects from, or refracts through, a facet chosen from those visible, in proportion to the
+// Fresnel reflectance at that facet. eta is the ratio of the refractive index beyond the surface to that on the side
+// the ray arrives from
+pub fn rough_dielectric_scatter(r: Ray, normal: MyVec3, alpha: f64, eta: f64, gain: MyVec3, sampler: &mut dyn Sampler) -> Option<ScatterSample>
+{
+    let frame = ShadingFrame::new(normal);
+    let wo    = frame.to_local(-1.0 * vec3_normalize(r.direction));
 
-        let cos_incident_angle = f64::min (-1.0 * ray_unit_vector.dot(normal), 1.0);
-        let sin_incident_angle = f64::sqrt(1.0 - cos_incident_angle * cos_incident_angle);
+    if wo.z <= 0.0
+    {
+        return None;
+    }
 
-        // Check for total internal reflection, and also provide a probability of reflection based on the incidence angle (some reflections, some refractions dependent on the angle)
-        if eta * sin_incident_angle > 1.0 || schlick_approximation(cos_incident_angle, eta) > uniform_random()
+    let ggx     = Ggx::new(alpha);
+    let h       = ggx.sample_visible_normal(wo, sampler.next_2d());
+    let fresnel = microfacet::fresnel_dielectric(wo.dot(h), eta);
+
+    let wi = if sampler.next_1d() < fresnel
+    {
+        let wi = microfacet::reflect(wo, h);
+
+        if wi.z <= 0.0
         {
-            return reflect(ray, normal);
+            return None;
         }
-        else
-        {
-            let c2 = eta * (ray_unit_vector + cos_incident_angle * normal);
-            let c1 = -1.0 * f64::sqrt(f64::abs(1.0 - c2.squared_length())) * normal;
 
-            return c1 + c2
-        }
+        wi
     }
+    else
+    {
+        match microfacet::refract(wo, h, eta)
+        {
+            Some(wi) if wi.z < 0.0 => wi,
+            _                      => return None,
+        }
+    };
+
+    let (_, pdf) = ggx.dielectric(wo, wi, eta);
+    let weight   = ggx.g2(wo, wi) / ggx.g1(wo) * gain;
 
-    // Used to approximate the percentage of light (rays) reflected from a refractive surface based on the incident angle
-    fn schlick_approximation(cos_incident_angle: f64, index_of_refraction: f64) -> f64
+    Some(ScatterSample { direction: frame.to_world(wi), weight, pdf: Some(pdf) })
+}
+
+// BSDF times cosine (without the colour of the glass), and density, of rough_dielectric_scatter for light arriving
+// along the unit vector direction
+pub fn rough_dielectric_evaluate(r: Ray, normal: MyVec3, alpha: f64, eta: f64, direction: MyVec3) -> (f64, f64)
+{
+    let frame = ShadingFrame::new(normal);
+    let wo    = frame.to_local(-1.0 * vec3_normalize(r.direction));
+    let wi    = frame.to_local(direction);
+    let ggx   = Ggx::new(alpha);
+
+    if wo.z <= 0.0 || wi.z == 0.0 || ggx.is_smooth()
     {
-        let mut r0 = (1.0 - index_of_refraction) / (1.0 + index_of_refraction);
-        
-        r0 = r0 * r0;
-        
-        r0 + (1.0 - r0) * f64::powi(1.0 - cos_incident_angle, 5)
+        return (0.0, 0.0);
     }
 
-    fn reflect(ray: Ray, normal: MyVec3) -> MyVec3
+    ggx.dielectric(wo, wi, eta)
+}
+
+// The principled material, from its lobes at the intersect (see principled.rs)
+pub fn principled_scatter(r: Ray, normal: MyVec3, bsdf: &PrincipledBsdf, sampler: &mut dyn Sampler) -> Option<ScatterSample>
+{
+    let frame = ShadingFrame::horizontal(normal);
+    let wo    = frame.to_local(-1.0 * vec3_normalize(r.direction));
+
+    let (wi, weight, pdf) = bsdf.sample(wo, sampler)?;
+
+    Some(ScatterSample { direction: frame.to_world(wi), weight, pdf: Some(pdf) })
+}
+
+// BSDF times cosine, and density, of principled_scatter for light arriving along the unit vector direction
+pub fn principled_evaluate(r: Ray, normal: MyVec3, bsdf: &PrincipledBsdf, direction: MyVec3) -> (MyVec3, f64)
+{
+    let frame = ShadingFrame::horizontal(normal);
+
+    bsdf.evaluate(frame.to_local(-1.0 * vec3_normalize(r.direction)), frame.to_local(direction))
+}
+
+// Smooth glass: the ray is reflected with the probability given by the Fresnel equations for unpolarised light, and
+// otherwise refracted; eta as for rough_dielectric_scatter
+pub fn refractive_scatter(r: Ray, normal: MyVec3, eta: f64, sampler: &mut dyn Sampler) -> MyVec3
+{
+    let wo    = -1.0 * vec3_normalize(r.direction);
+    let n     = vec3_normalize(normal);
+    let cos_i = f64::clamp(wo.dot(n), 0.0, 1.0);
+
+    if sampler.next_1d() < microfacet::fresnel_dielectric(cos_i, eta)
     {
-        ray.direction - 2.0 * ray.direction.dot(normal) * normal
+        return microfacet::reflect(wo, n);
     }
+
+    // Beyond the critical angle the reflectance is one, so refraction always succeeds here
+    microfacet::refract(wo, n, eta).unwrap_or_else(|| microfacet::reflect(wo, n))
 }
+
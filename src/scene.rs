@@ -0,0 +1,838 @@
+// Scene description files
+//
+// A scene is written in TOML and describes the camera, a set of named materials, and the objects in the world.
+// Vectors are written as arrays of three numbers, angles are in degrees, and file names are relative to the
+// directory containing the scene file. For example:
+//
+//     sky = "gradient"                       # optional, gradient (default) or black
+//
+//     [fog]                                  # optional, a medium filling the scene outside medium objects (see below)
+//     density    = 0.02
+//     albedo     = [1.0, 1.0, 1.0]           # optional
+//     anisotropy = 0.0                       # optional
+//
+//     [camera]
+//     location        = [13.0, 2.0, 3.0]
+//     target          = [0.0, 0.0, 0.0]      # or direction = [...]
+//     up              = [0.0, 1.0, 0.0]      # optional
+//     vertical_fov    = 20.0
+//     aperture        = 0.1                  # optional, pinhole camera if omitted
+//     focus_distance  = 10.0                 # optional
+//     exposure_length = 1.0                  # optional
+//
+//     [textures.checker]
+//     type  = "checker"                      # solid, checker, noise or image
+//     odd   = [0.2, 0.3, 0.1]
+//     even  = [0.9, 0.9, 0.9]
+//     scale = 0.5
+//
+//     [materials.red]
+//     type    = "diffuse"                    # diffuse, metallic, refractive, principled, emissive or medium
+//     gain    = [0.8, 0.1, 0.1]
+//     texture = "checker"                    # optional, replaces gain (diffuse and metallic; principled: base_colour)
+//
+//     [[objects]]
+//     type     = "sphere"                    # sphere, moving_sphere, triangle, quad, obj, preset or instance
+//     centre   = [0.0, 1.0, 0.0]
+//     radius   = 1.0
+//     material = "red"                       # a material defined above, or a built-in material (e.g. "glass")
+//
+// Metallic and refractive materials may be rough, from roughness 0 (the default, a perfect mirror or clear glass) to 1.
+// A metal reflects its gain face-on unless given the optical constants of a real metal, either a preset conductor
+// (gold, copper, aluminium or silver) or eta and k for red, green and blue, which gain then tints. Older scenes'
+// fuzz is converted to a roughness:
+//
+//     [materials.brushed_gold]
+//     type      = "metallic"
+//     conductor = "gold"                     # optional, or eta = [...] and k = [...]
+//     roughness = 0.3                        # optional, or fuzz (not both)
+//
+//     [materials.frosted_glass]
+//     type                = "refractive"
+//     index_of_refraction = 1.5
+//     roughness           = 0.2              # optional
+//
+// Refractive objects must be closed, with their front faces outwards. Gain tints the light each time it crosses the
+// surface; coloured glass and liquids instead absorb light as it travels through them, a fraction exp(-absorption * d)
+// of each colour remaining after distance d, so that thick parts are darker than thin:
+//
+//     [materials.green_glass]
+//     type                = "refractive"
+//     index_of_refraction = 1.5
+//     absorption          = [0.8, 0.1, 0.6]  # optional, per unit distance, zero (clear) by default
+//     priority            = 2                # optional, default 0
+//
+// Dielectric objects which touch, such as a glass and the water in it, should overlap slightly rather than leave a
+// gap. Where they overlap, the object with the higher priority fills the overlap and the other's surface is ignored,
+// so the water's surface inside the glass's wall disappears and light refracts from glass into water; an air bubble
+// in water is a refractive object of index 1 with a higher priority than the water (see dielectric_stack.rs)
+//
+// A principled material combines the lobes of the others, with the parameters familiar from Disney's BRDF. Every
+// parameter is optional (defaults shown) and, except the index of refraction, between 0 and 1:
+//
+//     [materials.car_paint]
+//     type                = "principled"
+//     base_colour         = [0.8, 0.8, 0.8]
+//     texture             = "checker"        # optional, replaces base_colour
+//     metallic            = 0.0              # conductor rather than dielectric, reflecting the base colour
+//     roughness           = 0.5
+//     specular            = 0.5              # reflectance of the dielectric face-on, 0.5 being 4%
+//     transmission        = 0.0              # glass rather than diffuse, tinted by the base colour
+//     index_of_refraction = 1.5              # of the transmissive part
+//     clearcoat           = 0.0              # a clear varnish on top
+//     clearcoat_roughness = 0.03
+//     sheen               = 0.0              # cloth-like brightening at grazing angles
+//     sheen_tint          = 0.5              # how far the sheen takes on the hue of the base colour
+//     anisotropy          = 0.0              # stretches the highlights (as of brushed metal)
+//
+// Anisotropic highlights are stretched horizontally, along the circles about the world's vertical axis, whatever the
+// texture co-ordinates of the surface and however an instance of it is rotated.
+//
+// An object with a medium material is the invisible boundary of a volume of smoke, fog, or similar, and must be
+// closed (e.g. a sphere, a closed mesh, or an instance of one) with its front faces outwards. Density is the chance
+// per unit distance of light hitting a particle, albedo the fraction of that light which is scattered rather than
+// absorbed, and anisotropy (between -1 and 1, default 0 for isotropic) the Henyey-Greenstein phase function's mean
+// cosine of the angle the light is scattered through: positive scatters light onwards, negative back. The camera
+// must be outside every medium object:
+//
+//     [materials.smoke]
+//     type       = "medium"
+//     density    = 2.0
+//     albedo     = [0.8, 0.8, 0.8]           # optional
+//     anisotropy = 0.3                       # optional
+//
+// A prototype is a group of objects (any type but instance) which is stored once, with its own bounding volume
+// hierarchy, and placed in the world any number of times by instances, each with its own transform and optionally
+// its own material. Emissive prototypes light the scene, but are not sampled as lights:
+//
+//     [prototypes.tree]
+//     objects = [{ type = "obj", file = "tree.obj" }]
+//
+//     [[objects]]
+//     type      = "instance"
+//     prototype = "tree"
+//     material  = "red"                      # optional, replaces the materials of all the prototype's objects
+//     scale     = 2.0                        # optional, a number or [x, y, z]; applied first
+//     rotate    = [0.0, 45.0, 0.0]           # optional, degrees about the x, then y, then z axis
+//     translate = [4.0, 0.0, -1.0]           # optional, applied last
+//     # or instead of scale, rotate and translate, a 4x4 matrix (rows, acting on column vectors):
+//     # matrix  = [[1, 0, 0, 4], [0, 1, 0, 0], [0, 0, 1, -1], [0, 0, 0, 1]]
+//
+// An instance which moves during the exposure (motion blur) is given keyframes in place of a fixed transform, each
+// with a time (from 0 to the camera's exposure_length) and optional scale, rotate and translate as above. Between
+// keyframes the rotation turns the shorter way round, so a spin needs a keyframe at least every half turn:
+//
+//     keyframes = [{ time = 0.0, translate = [0.0, 1.0, 0.0] },
+//                  { time = 0.5, translate = [0.5, 1.2, 0.0], rotate = [0.0, 0.0, -90.0] },
+//                  { time = 1.0, translate = [1.0, 1.0, 0.0], rotate = [0.0, 0.0, -180.0] }]
+//
+// A volume is a medium whose density varies, following a grid of values which fills the cube [-0.5, 0.5]^3 and is
+// placed like an instance (scale, rotate and translate, or matrix). The grid is read from a raw file of little-endian
+// 32-bit floats, x varying fastest, then y, then z, or made from noise as a rounded puff of cloud. Density multiplies
+// the grid's values; albedo and anisotropy are as for a medium material:
+//
+//     [[objects]]
+//     type      = "volume"
+//     grid      = { type = "raw", file = "smoke.raw", resolution = [64, 64, 64] }
+//     # or grid = { type = "noise", resolution = [64, 64, 64], frequency = 4.0 }
+//     density   = 2.0                        # optional
+//     scale     = [4.0, 2.0, 4.0]            # optional
+//     translate = [0.0, 3.0, 0.0]            # optional
+//
+// The random "final scene" of Ray Tracing in One Weekend is available as the preset "final_scene", and a chart of the
+// principled material's parameters as "material_sweep", either as the whole scene (--preset) or as an object within
+// a scene file (type = "preset")
+
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}, sync::Arc};
+
+use serde::Deserialize;
+
+use crate::{my_vec3::MyVec3, my_matrix4::MyMatrix4, world_instance::Transform, animated_transform::{AnimatedTransform, Keyframe, Quaternion}, camera::Camera, material::{self, SharedMaterial, Diffuse, Metallic, Refractive, Emissive, MediumBoundary, Principled}, medium::Medium, microfacet::{self, Conductor, CONDUCTOR_PRESETS}, world_element::WorldElement, create_world::{create_world, create_material_sweep}, obj_loader, renderer::Sky,
+            common::{RandomStream, SCENE_STREAM, hash_bytes, HASH_START}, voxel_grid::VoxelGrid,
+            texture::{SharedTexture, SolidColour, CheckerTexture, NoiseTexture, ImageTexture}};
+
+pub const PRESET_NAMES: [&str; 2] = ["final_scene", "material_sweep"];
+
+type Vec3Description = [f64; 3];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDescription
+{
+    #[serde(default)]
+    sky: Sky,
+
+    fog: Option<FogDescription>,
+
+    camera: CameraDescription,
+
+    #[serde(default)]
+    textures: BTreeMap<String, TextureDescription>,
+
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialDescription>,
+
+    #[serde(default)]
+    prototypes: BTreeMap<String, PrototypeDescription>,
+
+    #[serde(default)]
+    objects: Vec<ObjectDescription>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FogDescription
+{
+    density:    f64,
+    #[serde(default = "white")]
+    albedo:     Vec3Description,
+    #[serde(default)]
+    anisotropy: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrototypeDescription
+{
+    objects: Vec<ObjectDescription>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDescription
+{
+    location:        Vec3Description,
+    direction:       Option<Vec3Description>,
+    target:          Option<Vec3Description>,
+    up:              Option<Vec3Description>,
+    vertical_fov:    f64,
+    aperture:        Option<f64>,
+    focus_distance:  Option<f64>,
+    exposure_length: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDescription
+{
+    Solid   { colour: Vec3Description },
+    Checker { odd: Vec3Description, even: Vec3Description, #[serde(default = "one")] scale: f64 },
+    Noise   { #[serde(default = "white")] colour: Vec3Description, #[serde(default = "one")] scale: f64 },
+    Image   { file: PathBuf },
+}
+
+// Diffuse and metallic materials take their colour from the named texture, if given, instead of gain (principled
+// materials instead of base_colour); parameters of a principled material which are not given take the default values
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDescription
+{
+    Diffuse    { #[serde(default = "white")] gain: Vec3Description, texture: Option<String> },
+    Metallic   { #[serde(default = "white")] gain: Vec3Description, fuzz: Option<f64>, roughness: Option<f64>, conductor: Option<String>,
+                 eta: Option<Vec3Description>, k: Option<Vec3Description>, texture: Option<String> },
+    Refractive { #[serde(default = "white")] gain: Vec3Description, index_of_refraction: f64, roughness: Option<f64>, #[serde(default)] absorption: Vec3Description,
+                 #[serde(default)] priority: u32 },
+    Emissive   { colour: Vec3Description, #[serde(default = "one")] strength: f64 },
+    Principled { base_colour: Option<Vec3Description>, texture: Option<String>, metallic: Option<f64>, roughness: Option<f64>, specular: Option<f64>,
+                 transmission: Option<f64>, index_of_refraction: Option<f64>, clearcoat: Option<f64>, clearcoat_roughness: Option<f64>,
+                 sheen: Option<f64>, sheen_tint: Option<f64>, anisotropy: Option<f64> },
+    Medium     { density: f64, #[serde(default = "white")] albedo: Vec3Description, #[serde(default)] anisotropy: f64 },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ObjectDescription
+{
+    Sphere       { centre: Vec3Description, radius: f64, material: String },
+    MovingSphere { centre: Vec3Description, radius: f64, material: String, speed: f64, direction: Vec3Description },
+    Triangle     { vertices: [Vec3Description; 3], material: String },
+    Quad         { vertices: [Vec3Description; 4], material: String },       // Planar and convex, vertices in order around the edge
+    Obj          { file: PathBuf },
+    Preset       { name: String },
+    Instance     { prototype: String, material: Option<String>, scale: Option<ScaleDescription>, rotate: Option<Vec3Description>, translate: Option<Vec3Description>,
+                   matrix: Option<[[f64; 4]; 4]>, keyframes: Option<Vec<KeyframeDescription>> },
+    Volume       { grid: GridDescription, #[serde(default = "one")] density: f64, #[serde(default = "white")] albedo: Vec3Description, #[serde(default)] anisotropy: f64,
+                   scale: Option<ScaleDescription>, rotate: Option<Vec3Description>, translate: Option<Vec3Description>, matrix: Option<[[f64; 4]; 4]> },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum GridDescription
+{
+    Raw   { file: PathBuf, resolution: [usize; 3] },
+    Noise { resolution: [usize; 3], #[serde(default = "default_noise_frequency")] frequency: f64 },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyframeDescription
+{
+    time:      f64,
+    scale:     Option<ScaleDescription>,
+    rotate:    Option<Vec3Description>,
+    translate: Option<Vec3Description>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ScaleDescription
+{
+    Uniform(f64),
+    PerAxis(Vec3Description),
+}
+
+pub struct Scene
+{
+    pub sky:           Sky,
+    pub fog:           Option<Medium>,
+    pub camera:        CameraDescription,
+    pub world_element: WorldElement,
+}
+
+impl CameraDescription
+{
+    pub fn exposure_length(&self) -> f64
+    {
+        self.exposure_length.unwrap_or(0.0)
+    }
+
+    pub fn to_camera(&self, aspect_ratio: f64) -> Result<Camera, String>
+    {
+        Camera::new(vec3(self.location),
+                    self.direction.map(vec3),
+                    self.target.map(vec3),
+                    self.up.map(vec3),
+                    self.focus_distance,
+                    self.aperture,
+                    self.exposure_length,
+                    aspect_ratio,
+                    self.vertical_fov.to_radians())
+    }
+}
+
+fn vec3(v: Vec3Description) -> MyVec3
+{
+    MyVec3 { x: v[0], y: v[1], z: v[2] }
+}
+
+fn scale_vec3(scale: &Option<ScaleDescription>) -> MyVec3
+{
+    match scale
+    {
+        Some(ScaleDescription::Uniform(s)) => MyVec3 { x: *s, y: *s, z: *s },
+        Some(ScaleDescription::PerAxis(s)) => vec3(*s),
+        None                               => MyVec3 { x: 1.0, y: 1.0, z: 1.0 },
+    }
+}
+
+// Scale, then rotate (degrees about the x, then y, then z axis), then translate; or a matrix instead of all three
+fn fixed_transform(scale: &Option<ScaleDescription>, rotate: &Option<Vec3Description>, translate: &Option<Vec3Description>, matrix: &Option<[[f64; 4]; 4]>,
+                   key: &dyn Fn(&str) -> String) -> Result<Transform, String>
+{
+    let to_world = match matrix
+    {
+        Some(_) if scale.is_some() || rotate.is_some() || translate.is_some() =>
+        {
+            return Err(format!("{}: give either a matrix or scale, rotate, and translate", key("matrix")));
+        }
+        Some(rows) => MyMatrix4 { m: *rows },
+        None =>
+        {
+            let [x, y, z] = rotate.unwrap_or([0.0, 0.0, 0.0]);
+
+            MyMatrix4::translation(vec3(translate.unwrap_or([0.0, 0.0, 0.0])))
+                * MyMatrix4::rotation(MyVec3 { x: 0.0, y: 0.0, z: 1.0 }, z.to_radians())
+                * MyMatrix4::rotation(MyVec3 { x: 0.0, y: 1.0, z: 0.0 }, y.to_radians())
+                * MyMatrix4::rotation(MyVec3 { x: 1.0, y: 0.0, z: 0.0 }, x.to_radians())
+                * MyMatrix4::scaling(scale_vec3(scale))
+        }
+    };
+
+    Transform::new(to_world).map_err(|e| format!("{}: {}", key("type"), e))
+}
+
+fn white() -> Vec3Description
+{
+    [1.0, 1.0, 1.0]
+}
+
+fn one() -> f64
+{
+    1.0
+}
+
+fn default_noise_frequency() -> f64
+{
+    4.0
+}
+
+// Materials which may be referred to by name in any scene without being defined in it
+fn built_in_material(name: &str) -> Option<SharedMaterial>
+{
+    match name
+    {
+        "glass"              => Some(Arc::new(material::GLASS)),
+        "perfect_reflection" => Some(Arc::new(material::PERFECT_REFLECTION)),
+        "yellow_tint"        => Some(Arc::new(material::YELLOW_TINT)),
+        "pure_red"           => Some(Arc::new(material::PURE_RED)),
+        "pure_green"         => Some(Arc::new(material::PURE_GREEN)),
+        "pure_blue"          => Some(Arc::new(material::PURE_BLUE)),
+        "neutral_grey"       => Some(Arc::new(material::NEUTRAL_GREY)),
+        _                    => None,
+    }
+}
+
+impl TextureDescription
+{
+    fn to_texture(&self, directory: &Path, rng: &mut RandomStream) -> Result<SharedTexture, String>
+    {
+        let solid = |colour: Vec3Description| -> SharedTexture { Arc::new(SolidColour { colour: vec3(colour) }) };
+
+        Ok(match self
+        {
+            TextureDescription::Solid   { colour }           => solid(*colour),
+            TextureDescription::Checker { odd, even, scale } => Arc::new(CheckerTexture { odd: solid(*odd), even: solid(*even), scale: *scale }),
+            TextureDescription::Noise   { colour, scale }    => Arc::new(NoiseTexture::new(vec3(*colour), *scale, rng)),
+            TextureDescription::Image   { file }             => Arc::new(ImageTexture::load(&directory.join(file))?),
+        })
+    }
+}
+
+impl MaterialDescription
+{
+    fn to_material(&self, textures: &HashMap<String, SharedTexture>, key: &str) -> Result<SharedMaterial, String>
+    {
+        let find_texture = |name: &Option<String>| -> Result<Option<SharedTexture>, String> {
+            match name
+            {
+                None       => Ok(None),
+                Some(name) => textures.get(name)
+                                      .cloned()
+                                      .map(Some)
+                                      .ok_or_else(|| format!("{}.texture: texture '{}' is not defined in [textures]", key, name)),
+            }
+        };
+
+        Ok(match self
+        {
+            MaterialDescription::Diffuse { gain, texture } =>
+                Arc::new(Diffuse { gain: vec3(*gain), texture: find_texture(texture)? }),
+            MaterialDescription::Metallic { gain, fuzz, roughness, conductor, eta, k, texture } =>
+            {
+                // The fuzz of older scenes is converted to the roughness with a similar spread of reflections
+                let roughness = match (fuzz, roughness)
+                {
+                    (Some(_), Some(_))  => return Err(format!("{}: Give either fuzz or roughness, not both", key)),
+                    (Some(fuzz), None)  => microfacet::roughness_from_fuzz(*fuzz),
+                    (None, roughness)   => roughness.unwrap_or(0.0),
+                };
+
+                let conductor = match (conductor, eta, k)
+                {
+                    (None, None, None)             => None,
+                    (Some(name), None, None)       => Some(Conductor::preset(name).ok_or_else(|| format!("{}.conductor: '{}' is not one of {}", key, name,
+                                                                                                       CONDUCTOR_PRESETS.map(|(name, _)| name).join(", ")))?),
+                    (None, Some(eta), Some(k))     => Some(Conductor { eta: vec3(*eta), k: vec3(*k) }),
+                    (Some(_), _, _)                => return Err(format!("{}: Give either conductor or eta and k, not both", key)),
+                    (None, _, _)                   => return Err(format!("{}: eta and k must be given together", key)),
+                };
+
+                Arc::new(Metallic { gain: vec3(*gain), texture: find_texture(texture)?, roughness: check_fraction(roughness, key, "roughness")?, conductor })
+            }
+            MaterialDescription::Refractive { gain, index_of_refraction, roughness, absorption, priority } =>
+            {
+                if absorption.iter().any(|&a| a < 0.0)
+                {
+                    return Err(format!("{}.absorption: The absorption must not be negative, found {:?}", key, absorption));
+                }
+
+                Arc::new(Refractive { gain: vec3(*gain), index_of_refraction: check_index_of_refraction(*index_of_refraction, key)?, roughness: check_fraction(roughness.unwrap_or(0.0), key, "roughness")?,
+                                      absorption: vec3(*absorption), priority: *priority })
+            }
+            MaterialDescription::Emissive { colour, strength } =>
+            {
+                if !colour.iter().all(|&c| c.is_finite() && c >= 0.0)
+                {
+                    return Err(format!("{}.colour: The colour must be finite and not negative, found {:?}", key, colour));
+                }
+
+                if !(strength.is_finite() && *strength >= 0.0)
+                {
+                    return Err(format!("{}.strength: The strength must be finite and not negative, not {}", key, strength));
+                }
+
+                Arc::new(Emissive { colour: vec3(*colour), strength: *strength })
+            }
+            MaterialDescription::Principled { base_colour, texture, metallic, roughness, specular, transmission, index_of_refraction, clearcoat,
+                                              clearcoat_roughness, sheen, sheen_tint, anisotropy } =>
+            {
+                let default   = Principled::default();
+                let parameter = |value: &Option<f64>, default: f64, name: &str| check_fraction(value.unwrap_or(default), key, name);
+
+                Arc::new(Principled { base_colour:         base_colour.map_or(default.base_colour, vec3),
+                                      texture:             find_texture(texture)?,
+                                      metallic:            parameter(metallic,            default.metallic,            "metallic")?,
+                                      roughness:           parameter(roughness,           default.roughness,           "roughness")?,
+                                      specular:            parameter(specular,            default.specular,            "specular")?,
+                                      transmission:        parameter(transmission,        default.transmission,        "transmission")?,
+                                      index_of_refraction: check_index_of_refraction(index_of_refraction.unwrap_or(default.index_of_refraction), key)?,
+                                      clearcoat:           parameter(clearcoat,           default.clearcoat,           "clearcoat")?,
+                                      clearcoat_roughness: parameter(clearcoat_roughness, default.clearcoat_roughness, "clearcoat_roughness")?,
+                                      sheen:               parameter(sheen,               default.sheen,               "sheen")?,
+                                      sheen_tint:          parameter(sheen_tint,          default.sheen_tint,          "sheen_tint")?,
+                                      anisotropy:          parameter(anisotropy,          default.anisotropy,          "anisotropy")? })
+            }
+            MaterialDescription::Medium { density, albedo, anisotropy } =>
+            {
+                let medium = Medium::new(*density, vec3(*albedo), *anisotropy).map_err(|e| format!("{}: {}", key, e))?;
+
+                Arc::new(MediumBoundary { medium })
+            }
+        })
+    }
+}
+
+// Check that the material parameter called name (e.g. a roughness) is between 0 and 1
+fn check_fraction(value: f64, key: &str, name: &str) -> Result<f64, String>
+{
+    if !(0.0..=1.0).contains(&value)
+    {
+        return Err(format!("{}.{}: The {} must be between 0 and 1, not {}", key, name, name, value));
+    }
+
+    Ok(value)
+}
+
+// Check that an index of refraction is positive (and finite)
+fn check_index_of_refraction(value: f64, key: &str) -> Result<f64, String>
+{
+    if !(value.is_finite() && value > 0.0)
+    {
+        return Err(format!("{}.index_of_refraction: The index of refraction must be a finite positive number, not {}", key, value));
+    }
+
+    Ok(value)
+}
+
+// Read a scene file; random choices made while building the scene (e.g. noise textures) are derived from seed
+pub fn load_scene(path: &Path, seed: u64) -> Result<Scene, String>
+{
+    let file_name = path.display().to_string();
+    let contents  = fs::read_to_string(path).map_err(|e| format!("{}: Unable to read scene file: {}", file_name, e))?;
+
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    parse_scene(&contents, directory, seed).map_err(|e| format!("{}: {}", file_name, e))
+}
+
+// The scene described by the contents of a scene file, whose other files are relative to directory
+fn parse_scene(contents: &str, directory: &Path, seed: u64) -> Result<Scene, String>
+{
+    // Syntax errors, unknown keys, and values of the wrong type are reported with the path to the offending table or
+    // key (e.g. objects[2].radius) and its line
+    let description: SceneDescription = serde_path_to_error::deserialize(toml::Deserializer::new(contents))
+                                            .map_err(|e| match e.path().to_string().as_str()
+                                            {
+                                                "."  => e.inner().to_string(),
+                                                path => format!("{}: {}", path, e.inner()),
+                                            })?;
+
+    build_scene(description, directory, seed)
+}
+
+// One of the built-in scenes
+pub fn preset_scene(name: &str, seed: u64) -> Result<Scene, String>
+{
+    // A separate stream from the rest of a scene file, so a preset is the same whatever else is in the file
+    let mut rng = RandomStream::new(seed, SCENE_STREAM, 1);
+
+    match name
+    {
+        "final_scene" =>
+        {
+            let camera = CameraDescription { location:        [13.0, 2.0, 3.0],
+                                             direction:       None,
+                                             target:          Some([0.0, 0.0, 0.0]),
+                                             up:              Some([0.0, 1.0, 0.0]),
+                                             vertical_fov:    20.0,
+                                             aperture:        Some(0.1),
+                                             focus_distance:  Some(10.0),
+                                             exposure_length: Some(1.0) };
+
+            Ok(Scene { sky: Sky::Gradient, fog: None, camera, world_element: create_world(&mut rng) })
+        }
+        "material_sweep" =>
+        {
+            let camera = CameraDescription { location:        [0.0, 3.6, 16.0],
+                                             direction:       None,
+                                             target:          Some([0.0, 3.6, 0.0]),
+                                             up:              Some([0.0, 1.0, 0.0]),
+                                             vertical_fov:    30.0,
+                                             aperture:        None,
+                                             focus_distance:  None,
+                                             exposure_length: None };
+
+            Ok(Scene { sky: Sky::Gradient, fog: None, camera, world_element: create_material_sweep() })
+        }
+        _ => Err(format!("Unknown preset '{}' (available presets: {})", name, PRESET_NAMES.join(", "))),
+    }
+}
+
+fn build_scene(description: SceneDescription, directory: &Path, seed: u64) -> Result<Scene, String>
+{
+    let mut rng = RandomStream::new(seed, SCENE_STREAM, 0);
+
+    let camera = description.camera;
+
+    // Check the camera now, so that the error refers to the scene file
+    camera.to_camera(1.0).map_err(|e| format!("camera: {}", e))?;
+
+    let fog = match description.fog
+    {
+        Some(fog) => Some(Medium::new(fog.density, vec3(fog.albedo), fog.anisotropy).map_err(|e| format!("fog: {}", e))?),
+        None      => None,
+    };
+
+    let mut textures = HashMap::new();
+
+    for (name, texture_description) in &description.textures
+    {
+        textures.insert(name.clone(), texture_description.to_texture(directory, &mut rng).map_err(|e| format!("textures.{}: {}", name, e))?);
+    }
+
+    let mut materials = HashMap::new();
+
+    for (name, material_description) in &description.materials
+    {
+        materials.insert(name.clone(), material_description.to_material(&textures, &format!("materials.{}", name))?);
+    }
+
+    let mut builder = SceneBuilder { directory, seed, materials, prototypes: HashMap::new() };
+
+    // Prototypes are complete worlds of their own, with their own bounding volume hierarchy, shared by their instances
+    for (name, prototype) in &description.prototypes
+    {
+        let mut prototype_element = WorldElement::new();
+
+        for (n, object) in prototype.objects.iter().enumerate()
+        {
+            let key = |field: &str| format!("prototypes.{}.objects[{}].{}", name, n, field);
+
+            if let ObjectDescription::Instance { .. } = object
+            {
+                return Err(format!("{}: instances cannot be used inside a prototype", key("type")));
+            }
+
+            builder.add_object(&mut prototype_element, object, &key)?;
+        }
+
+        prototype_element.build_bvh(0.0, camera.exposure_length());
+
+        builder.prototypes.insert(name.clone(), Arc::new(prototype_element));
+    }
+
+    let mut world_element = WorldElement::new();
+
+    for (n, object) in description.objects.iter().enumerate()
+    {
+        builder.add_object(&mut world_element, object, &|field: &str| format!("objects[{}].{}", n, field))?;
+    }
+
+    Ok(Scene { sky: description.sky, fog, camera, world_element })
+}
+
+struct SceneBuilder<'a>
+{
+    directory:  &'a Path,
+    seed:       u64,
+    materials:  HashMap<String, SharedMaterial>,
+    prototypes: HashMap<String, Arc<WorldElement>>,
+}
+
+impl SceneBuilder<'_>
+{
+    fn find_material(&self, key: String, name: &str) -> Result<SharedMaterial, String>
+    {
+        self.materials.get(name)
+                      .cloned()
+                      .or_else(|| built_in_material(name))
+                      .ok_or_else(|| format!("{}: material '{}' is not defined in [materials] and is not a built-in material", key, name))
+    }
+
+    // key gives the name of a field of the object for error messages
+    fn add_object(&self, world_element: &mut WorldElement, object: &ObjectDescription, key: &dyn Fn(&str) -> String) -> Result<(), String>
+    {
+        match object
+        {
+            ObjectDescription::Sphere { centre, radius, material } =>
+            {
+                let material = self.find_material(key("material"), material)?;
+
+                world_element.add_sphere(centre[0], centre[1], centre[2], *radius, material);
+            }
+            ObjectDescription::MovingSphere { centre, radius, material, speed, direction } =>
+            {
+                let material = self.find_material(key("material"), material)?;
+
+                if vec3(*direction).squared_length() == 0.0
+                {
+                    return Err(format!("{}: direction must not be zero", key("direction")));
+                }
+
+                world_element.add_moving_sphere(centre[0], centre[1], centre[2], *radius, material, *speed, vec3(*direction));
+            }
+            ObjectDescription::Triangle { vertices, material } =>
+            {
+                let material = self.find_material(key("material"), material)?;
+
+                world_element.add_triangle(vec3(vertices[0]), vec3(vertices[1]), vec3(vertices[2]), material);
+            }
+            ObjectDescription::Quad { vertices, material } =>
+            {
+                let material = self.find_material(key("material"), material)?;
+
+                world_element.add_triangle(vec3(vertices[0]), vec3(vertices[1]), vec3(vertices[2]), material.clone());
+                world_element.add_triangle(vec3(vertices[0]), vec3(vertices[2]), vec3(vertices[3]), material);
+            }
+            ObjectDescription::Obj { file } =>
+            {
+                for mesh in obj_loader::load_obj(&self.directory.join(file)).map_err(|e| format!("{}: {}", key("file"), e))?
+                {
+                    world_element.add_mesh(mesh);
+                }
+            }
+            ObjectDescription::Preset { name } =>
+            {
+                let preset = preset_scene(name, self.seed).map_err(|e| format!("{}: {}", key("name"), e))?;
+
+                for object in preset.world_element.objects
+                {
+                    world_element.add_object(object);
+                }
+
+                world_element.lights.extend(preset.world_element.lights);
+            }
+            ObjectDescription::Instance { prototype, material, scale, rotate, translate, matrix, keyframes } =>
+            {
+                let object = self.prototypes.get(prototype)
+                                            .ok_or_else(|| format!("{}: prototype '{}' is not defined in [prototypes]", key("prototype"), prototype))?;
+
+                let material = match material
+                {
+                    Some(name) => Some(self.find_material(key("material"), name)?),
+                    None       => None,
+                };
+
+                if let Some(keyframes) = keyframes
+                {
+                    if matrix.is_some() || scale.is_some() || rotate.is_some() || translate.is_some()
+                    {
+                        return Err(format!("{}: give either keyframes or a fixed transform", key("keyframes")));
+                    }
+
+                    let keyframes = keyframes.iter()
+                                             .map(|k| Keyframe { time:      k.time,
+                                                                 scale:     scale_vec3(&k.scale),
+                                                                 rotation:  Quaternion::from_euler(vec3(k.rotate.unwrap_or([0.0, 0.0, 0.0]).map(f64::to_radians))),
+                                                                 translate: vec3(k.translate.unwrap_or([0.0, 0.0, 0.0])) })
+                                             .collect();
+
+                    let motion = AnimatedTransform::new(keyframes).map_err(|e| format!("{}: {}", key("keyframes"), e))?;
+
+                    world_element.add_animated_instance(Arc::clone(object) as _, motion, material);
+
+                    return Ok(());
+                }
+
+                let transform = fixed_transform(scale, rotate, translate, matrix, key)?;
+
+                world_element.add_instance(Arc::clone(object) as _, transform, material);
+            }
+            ObjectDescription::Volume { grid, density, albedo, anisotropy, scale, rotate, translate, matrix } =>
+            {
+                let grid = match grid
+                {
+                    GridDescription::Raw { file, resolution } => VoxelGrid::load_raw(&self.directory.join(file), *resolution),
+                    GridDescription::Noise { resolution, frequency } =>
+                    {
+                        // Each noise grid has its own stream, named by the object's place in the file, so that grids
+                        // differ from one another and do not depend on the order in which textures and grids are made
+                        let stream = hash_bytes(HASH_START, key("grid").as_bytes());
+
+                        VoxelGrid::from_noise(*resolution, *frequency, &mut RandomStream::new(self.seed, SCENE_STREAM, stream))
+                    }
+                };
+
+                let grid      = Arc::new(grid.map_err(|e| format!("{}: {}", key("grid"), e))?);
+                let transform = fixed_transform(scale, rotate, translate, matrix, key)?;
+                let medium    = Medium::from_grid(grid, *density, &transform, vec3(*albedo), *anisotropy).map_err(|e| format!("{}: {}", key("type"), e))?;
+
+                let material = Arc::new(MediumBoundary { medium });
+
+                world_element.add_box(&transform, material);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const CAMERA: &str = "[camera]\nlocation = [0.0, 1.0, 5.0]\ntarget = [0.0, 1.0, 0.0]\nvertical_fov = 40.0\n";
+
+    fn parse(scene: &str) -> Result<Scene, String>
+    {
+        parse_scene(&format!("{}{}", CAMERA, scene), Path::new(""), 0)
+    }
+
+    #[test]
+    fn minimal_scene()
+    {
+        let scene = parse("[materials.red]\ntype = \"diffuse\"\ngain = [0.8, 0.1, 0.1]\n\n\
+                           [[objects]]\ntype = \"sphere\"\ncentre = [0.0, 1.0, 0.0]\nradius = 1.0\nmaterial = \"red\"\n\n\
+                           [[objects]]\ntype = \"sphere\"\ncentre = [0.0, -100.0, 0.0]\nradius = 100.0\nmaterial = \"glass\"\n").unwrap();
+
+        assert_eq!(scene.world_element.objects.len(), 2);
+        assert_eq!(scene.camera.vertical_fov, 40.0);
+        assert!(matches!(scene.sky, Sky::Gradient));
+        assert!(scene.fog.is_none());
+    }
+
+    // Errors name the table or key at fault
+    #[test]
+    fn errors_name_path()
+    {
+        let sphere = |material: &str| format!("[[objects]]\ntype = \"sphere\"\ncentre = [0.0, 1.0, 0.0]\nradius = 1.0\nmaterial = \"{}\"\n\n", material);
+
+        for (scene, message) in [(format!("{}{}{}", sphere("glass"), sphere("glass"), sphere("grey")),
+                                  "objects[2].material: material 'grey' is not defined in [materials] and is not a built-in material"),
+                                 ("[materials.old_metal]\ntype = \"metallic\"\nfuzz = 0.1\nroughness = 0.2\n".to_string(),
+                                  "materials.old_metal: Give either fuzz or roughness, not both"),
+                                 ("[materials.lamp]\ntype = \"emissive\"\ncolour = [1.0, -1.0, 1.0]\n".to_string(),
+                                  "materials.lamp.colour: The colour must be finite and not negative, found [1.0, -1.0, 1.0]"),
+                                 ("[materials.lamp]\ntype = \"emissive\"\ncolour = [1.0, 1.0, 1.0]\nstrength = -2.0\n".to_string(),
+                                  "materials.lamp.strength: The strength must be finite and not negative, not -2"),
+                                 ("[materials.glass]\ntype = \"refractive\"\nindex_of_refraction = 0.0\n".to_string(),
+                                  "materials.glass.index_of_refraction: The index of refraction must be a finite positive number, not 0")]
+        {
+            let error = parse(&scene).err().unwrap();
+
+            assert_eq!(error, message);
+        }
+    }
+
+    // Unknown keys are found while reading the file, and named in the message after the path to their table
+    #[test]
+    fn unknown_key()
+    {
+        let sphere = "[[objects]]\ntype = \"sphere\"\ncentre = [0.0, 1.0, 0.0]\nradius = 1.0\nmaterial = \"glass\"\n\n";
+        let error  = parse(&format!("{}{}{}", sphere, sphere, sphere.replace("radius", "radious"))).err().unwrap();
+
+        assert!(error.starts_with("objects[2]: ") && error.contains("unknown field `radious`"), "{}", error);
+    }
+}
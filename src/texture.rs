@@ -0,0 +1,215 @@
+// Textures give the colour (gain) of a surface as a function of the intersect: the surface co-ordinates (u, v)
+// and the point in space p. Textures are shared between materials through Arc, so e.g. a single image is loaded once
+// however many materials use it
+
+use std::{fmt::Debug, path::Path, sync::Arc};
+
+use rand::Rng;
+
+use crate::{my_vec3::MyVec3, perlin::Perlin};
+
+pub trait Texture: Debug
+{
+    fn value(&self, u: f64, v: f64, p: MyVec3) -> MyVec3;
+}
+
+pub type SharedTexture = Arc<dyn Texture + Send + Sync>;
+
+#[derive(Debug)]
+pub struct SolidColour
+{
+    pub colour: MyVec3
+}
+
+impl Texture for SolidColour
+{
+    fn value(&self, _u: f64, _v: f64, _p: MyVec3) -> MyVec3
+    {
+        self.colour
+    }
+}
+
+// Alternating 3D cells of two textures; scale is the size of each cell
+// Being solid (a function of the point rather than the surface co-ordinates) there is no distortion at the poles of spheres
+#[derive(Debug)]
+pub struct CheckerTexture
+{
+    pub odd:   SharedTexture,
+    pub even:  SharedTexture,
+    pub scale: f64
+}
+
+impl Texture for CheckerTexture
+{
+    fn value(&self, u: f64, v: f64, p: MyVec3) -> MyVec3
+    {
+        let cell = f64::floor(p.x / self.scale) as i64 + f64::floor(p.y / self.scale) as i64 + f64::floor(p.z / self.scale) as i64;
+
+        if cell.rem_euclid(2) == 0 {self.even.value(u, v, p)} else {self.odd.value(u, v, p)}
+    }
+}
+
+// Marble-like veins: a sine wave along z, phase shifted by Perlin turbulence
+#[derive(Debug)]
+pub struct NoiseTexture
+{
+    pub colour: MyVec3,
+    pub scale:  f64,
+    perlin:     Perlin
+}
+
+impl NoiseTexture
+{
+    pub fn new(colour: MyVec3, scale: f64, rng: &mut impl Rng) -> NoiseTexture
+    {
+        NoiseTexture { colour, scale, perlin: Perlin::new(rng) }
+    }
+}
+
+impl Texture for NoiseTexture
+{
+    fn value(&self, _u: f64, _v: f64, p: MyVec3) -> MyVec3
+    {
+        (0.5 * (1.0 + f64::sin(self.scale * p.z + 10.0 * self.perlin.turbulence(p, 7)))) * self.colour
+    }
+}
+
+// Image looked up by surface co-ordinates, (0, 0) being the bottom left of the image
+// Pixels are converted from sRGB to linear values when loaded, since the renderer works with linear light
+pub struct ImageTexture
+{
+    width:  u32,
+    height: u32,
+    pixels: Vec<MyVec3>
+}
+
+impl Debug for ImageTexture
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "ImageTexture {{ width: {}, height: {} }}", self.width, self.height)
+    }
+}
+
+impl ImageTexture
+{
+    pub fn load(path: &Path) -> Result<ImageTexture, String>
+    {
+        let image = image::open(path).map_err(|e| format!("{}: Unable to load image texture: {}", path.display(), e))?.to_rgb8();
+
+        let (width, height) = image.dimensions();
+
+        if width == 0 || height == 0
+        {
+            return Err(format!("{}: Image texture is empty", path.display()));
+        }
+
+        let pixels = image.pixels()
+                          .map(|p| MyVec3 { x: srgb_to_linear(p[0]), y: srgb_to_linear(p[1]), z: srgb_to_linear(p[2]) })
+                          .collect();
+
+        Ok(ImageTexture { width, height, pixels })
+    }
+}
+
+impl Texture for ImageTexture
+{
+    fn value(&self, u: f64, v: f64, _p: MyVec3) -> MyVec3
+    {
+        // Repeat outside [0, 1], and flip v since image rows run top to bottom
+        let u = u - f64::floor(u);
+        let v = 1.0 - (v - f64::floor(v));
+
+        let i = u32::min((u * self.width  as f64) as u32, self.width  - 1);
+        let j = u32::min((v * self.height as f64) as u32, self.height - 1);
+
+        self.pixels[(j * self.width + i) as usize]
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f64
+{
+    let c = value as f64 / 255.0;
+
+    if c <= 0.04045 { c / 12.92 } else { f64::powf((c + 0.055) / 1.055, 2.4) }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::common::RandomStream;
+
+    fn solid(value: f64) -> SharedTexture
+    {
+        Arc::new(SolidColour { colour: MyVec3 { x: value, y: value, z: value } })
+    }
+
+    // Neighbouring cells alternate, including across zero where floor rather than truncation matters
+    #[test]
+    fn checker_parity()
+    {
+        let checker = CheckerTexture { odd: solid(1.0), even: solid(0.0), scale: 0.5 };
+        let value   = |x: f64, y: f64, z: f64| checker.value(0.0, 0.0, MyVec3 { x, y, z }).x;
+
+        assert_eq!(value(0.25, 0.25, 0.25), 0.0);
+        assert_eq!(value(0.75, 0.25, 0.25), 1.0);
+        assert_eq!(value(0.75, 0.75, 0.25), 0.0);
+        assert_eq!(value(0.75, 0.75, 0.75), 1.0);
+        assert_eq!(value(-0.25, 0.25, 0.25), 1.0);
+        assert_eq!(value(-0.25, -0.25, 0.25), 0.0);
+        assert_eq!(value(-0.75, -0.25, -0.25), 0.0);
+    }
+
+    // 2x2 image, pixels in rows from the top: (0, 0) is the bottom left pixel and co-ordinates wrap around
+    #[test]
+    fn image_lookup()
+    {
+        let pixel   = |value: f64| MyVec3 { x: value, y: 0.0, z: 0.0 };
+        let texture = ImageTexture { width: 2, height: 2, pixels: vec![pixel(1.0), pixel(2.0), pixel(3.0), pixel(4.0)] };
+        let value   = |u: f64, v: f64| texture.value(u, v, MyVec3::default()).x;
+
+        assert_eq!(value(0.25, 0.75), 1.0);
+        assert_eq!(value(0.75, 0.75), 2.0);
+        assert_eq!(value(0.25, 0.25), 3.0);
+        assert_eq!(value(0.75, 0.25), 4.0);
+
+        assert_eq!(value(1.25, 0.25), 3.0);
+        assert_eq!(value(-0.25, 0.25), 4.0);
+        assert_eq!(value(0.25, -0.25), 1.0);
+        assert_eq!(value(0.25, 2.75), 1.0);
+
+        // The edges stay within the image
+        assert_eq!(value(0.0, 0.0), 3.0);
+        assert_eq!(value(1.0 - 1e-12, 1.0 - 1e-12), 2.0);
+    }
+
+    // Noise stays within [-1, 1], and the marble texture within [0, colour]
+    #[test]
+    fn noise_range()
+    {
+        let mut rng = RandomStream::new(7, 0, 0);
+        let perlin  = Perlin::new(&mut rng);
+        let marble  = NoiseTexture::new(MyVec3 { x: 0.5, y: 1.0, z: 2.0 }, 4.0, &mut rng);
+
+        let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+
+        for _ in 0..20000
+        {
+            let p     = MyVec3 { x: rng.gen_range(-50.0..50.0), y: rng.gen_range(-50.0..50.0), z: rng.gen_range(-50.0..50.0) };
+            let noise = perlin.noise(p);
+
+            assert!((-1.0..=1.0).contains(&noise), "{:?}: {}", p, noise);
+
+            min = f64::min(min, noise);
+            max = f64::max(max, noise);
+
+            let colour = marble.value(0.0, 0.0, p);
+
+            assert!(colour.x >= 0.0 && colour.x <= 0.5 && (colour.z - 4.0 * colour.x).abs() < 1e-12, "{:?}: {:?}", p, colour);
+        }
+
+        // Not degenerate
+        assert!(min < -0.3 && max > 0.3, "{} {}", min, max);
+    }
+}
@@ -0,0 +1,220 @@
+// Conversion of the linear (scene-referred) framebuffer to display values
+// Each pixel is scaled by the exposure and white balance, compressed into [0, 1] by the tone mapping operator, and
+// finally encoded with the sRGB transfer function. The framebuffer itself is never modified, so the same render (or a
+// saved EXR, HDR, or PFM file) can be graded any number of times
+
+use crate::my_vec3::MyVec3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ArgEnum, Default)]
+pub enum ToneMapOperator
+{
+    #[default]
+    Clamp,              // Values above 1 are clipped
+    Reinhard,           // L / (1 + L) on luminance; never reaches white
+    ExtendedReinhard,   // Reinhard with a white point, luminance at or above the white point maps to white
+    Aces,               // ACES filmic curve (RRT + ODT fit, Stephen Hill)
+    Agx                 // AgX base look (Troy Sobotka), log encoding and a sigmoid in a desaturated working space
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ToneMapping
+{
+    pub operator:      ToneMapOperator,
+    pub exposure:      f64,      // Stops (EV); each stop doubles the brightness
+    pub white_balance: MyVec3,   // Per-channel multipliers
+    pub white_point:   f64       // Extended Reinhard only, in exposed luminance
+}
+
+impl Default for ToneMapping
+{
+    fn default() -> Self
+    {
+        ToneMapping { operator: ToneMapOperator::Clamp, exposure: 0.0, white_balance: MyVec3{x: 1.0, y: 1.0, z: 1.0}, white_point: 4.0 }
+    }
+}
+
+impl ToneMapping
+{
+    // Display value of a linear pixel, each channel in the range [0, 1] and sRGB encoded
+    pub fn display(&self, linear: MyVec3) -> MyVec3
+    {
+        let exposed = f64::powf(2.0, self.exposure) * (self.white_balance * non_negative(linear));
+
+        let mapped = match self.operator
+        {
+            ToneMapOperator::Clamp            => exposed,
+            ToneMapOperator::Reinhard         => scale_luminance(exposed, |l| l / (1.0 + l)),
+            ToneMapOperator::ExtendedReinhard =>
+            {
+                let white_squared = self.white_point * self.white_point;
+
+                scale_luminance(exposed, |l| l * (1.0 + l / white_squared) / (1.0 + l))
+            }
+            ToneMapOperator::Aces             => aces_filmic(exposed),
+            ToneMapOperator::Agx              => agx(exposed),
+        };
+
+        MyVec3{x: linear_to_srgb(mapped.x), y: linear_to_srgb(mapped.y), z: linear_to_srgb(mapped.z)}
+    }
+}
+
+// sRGB opto-electronic transfer function (IEC 61966-2-1), input clamped to [0, 1]
+pub fn linear_to_srgb(value: f64) -> f64
+{
+    let c = f64::clamp(value, 0.0, 1.0);
+
+    if c <= 0.0031308 { 12.92 * c } else { 1.055 * f64::powf(c, 1.0 / 2.4) - 0.055 }
+}
+
+fn non_negative(c: MyVec3) -> MyVec3
+{
+    MyVec3{x: f64::max(c.x, 0.0), y: f64::max(c.y, 0.0), z: f64::max(c.z, 0.0)}
+}
+
+// Rec. 709 luminance
+fn luminance(c: MyVec3) -> f64
+{
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// Apply a curve to the luminance and scale the colour to match, which keeps the ratios between channels (the hue)
+fn scale_luminance<F>(c: MyVec3, curve: F) -> MyVec3 where F: Fn(f64) -> f64
+{
+    let l = luminance(c);
+
+    if l <= 0.0 { c } else { (curve(l) / l) * c }
+}
+
+// Row-major 3x3 matrix times column vector
+fn mat3_mul(m: &[[f64; 3]; 3], c: MyVec3) -> MyVec3
+{
+    MyVec3{x: m[0][0] * c.x + m[0][1] * c.y + m[0][2] * c.z,
+           y: m[1][0] * c.x + m[1][1] * c.y + m[1][2] * c.z,
+           z: m[2][0] * c.x + m[2][1] * c.y + m[2][2] * c.z}
+}
+
+fn map_channels<F>(c: MyVec3, f: F) -> MyVec3 where F: Fn(f64) -> f64
+{
+    MyVec3{x: f(c.x), y: f(c.y), z: f(c.z)}
+}
+
+// sRGB => ACES AP1 (with the RRT saturation adjustment folded in), then the fitted RRT + ODT curve, then back to sRGB
+fn aces_filmic(c: MyVec3) -> MyVec3
+{
+    const INPUT:  [[f64; 3]; 3] = [[ 0.59719,  0.35458,  0.04823],
+                                   [ 0.07600,  0.90834,  0.01566],
+                                   [ 0.02840,  0.13383,  0.83777]];
+    const OUTPUT: [[f64; 3]; 3] = [[ 1.60475, -0.53108, -0.07367],
+                                   [-0.10208,  1.10813, -0.00605],
+                                   [-0.00327, -0.07276,  1.07602]];
+
+    let fitted = map_channels(mat3_mul(&INPUT, c), |v| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.4329510) + 0.238081));
+
+    map_channels(mat3_mul(&OUTPUT, fitted), |v| f64::clamp(v, 0.0, 1.0))
+}
+
+// AgX: the colour is pulled towards the achromatic axis (inset), encoded as log2 over a fixed range of stops, passed
+// through the default contrast sigmoid (a polynomial fit), and the inset undone. The sigmoid output is display encoded
+// with a 2.2 gamma, which is removed here so the sRGB transfer function can be applied like the other operators
+fn agx(c: MyVec3) -> MyVec3
+{
+    const INSET:  [[f64; 3]; 3] = [[ 0.842479062253094,  0.0784335999999992,  0.0792237451477643],
+                                   [ 0.0423282422610123, 0.878468636469772,   0.0791661274605434],
+                                   [ 0.0423756549057051, 0.0784336,           0.879142973793104 ]];
+    const OUTSET: [[f64; 3]; 3] = [[ 1.19687900512017,  -0.0980208811401368, -0.0990297440797205],
+                                   [-0.0528968517574562, 1.15190312990417,   -0.0989611768448433],
+                                   [-0.0529716355144438,-0.0980434501171241,  1.15107367264116  ]];
+    const MIN_EV: f64 = -12.47393;
+    const MAX_EV: f64 =   4.026069;
+
+    let encoded = map_channels(mat3_mul(&INSET, c), |v|
+    {
+        let x  = (f64::clamp(f64::log2(f64::max(v, 1e-10)), MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    });
+
+    map_channels(mat3_mul(&OUTSET, encoded), |v| f64::powf(f64::clamp(v, 0.0, 1.0), 2.2))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn grey(value: f64) -> MyVec3
+    {
+        MyVec3{x: value, y: value, z: value}
+    }
+
+    fn mapping(operator: ToneMapOperator) -> ToneMapping
+    {
+        ToneMapping { operator, ..ToneMapping::default() }
+    }
+
+    #[test]
+    fn srgb_transfer_function()
+    {
+        assert_eq!(linear_to_srgb(0.0), 0.0);
+        assert!((linear_to_srgb(0.5) - 0.735357).abs() < 1e-6);
+        assert!((linear_to_srgb(1.0) - 1.0).abs() < 1e-12);
+
+        // The linear segment and the power curve meet at the knee
+        let knee = 0.0031308;
+
+        assert!((linear_to_srgb(knee) - linear_to_srgb(knee + 1e-12)).abs() < 1e-6);
+        assert!((12.92 * knee - (1.055 * f64::powf(knee, 1.0 / 2.4) - 0.055)).abs() < 1e-6);
+    }
+
+    // Each stop of exposure doubles the linear value before it is encoded
+    #[test]
+    fn exposure_doubles()
+    {
+        let exposed = ToneMapping { exposure: 1.0, ..ToneMapping::default() };
+
+        for value in [0.001, 0.1, 0.3]
+        {
+            assert!((exposed.display(grey(value)).x - ToneMapping::default().display(grey(2.0 * value)).x).abs() < 1e-12);
+        }
+    }
+
+    // Reinhard approaches white without reaching it, extended Reinhard reaches it at the white point
+    #[test]
+    fn reinhard_white_point()
+    {
+        let reinhard = mapping(ToneMapOperator::Reinhard).display(grey(1e6)).x;
+
+        assert!(reinhard < 1.0 && reinhard > 0.9999, "{}", reinhard);
+
+        for white_point in [1.0, 4.0, 11.2]
+        {
+            let extended = ToneMapping { white_point, ..mapping(ToneMapOperator::ExtendedReinhard) };
+
+            assert!((extended.display(grey(white_point)).x - 1.0).abs() < 1e-12);
+            assert!(extended.display(grey(0.9 * white_point)).x < 1.0);
+        }
+    }
+
+    // The filmic curves get brighter as the light does, and stay within the display range
+    #[test]
+    fn filmic_curves_monotonic()
+    {
+        for operator in [ToneMapOperator::Aces, ToneMapOperator::Agx]
+        {
+            let mut previous = 0.0;
+
+            for n in 0..=400
+            {
+                let value   = f64::powf(2.0, -12.0 + 0.05 * n as f64);
+                let display = mapping(operator).display(grey(value)).x;
+
+                assert!((0.0..=1.0).contains(&display), "{:?}: {} maps to {}", operator, value, display);
+                assert!(display >= previous, "{:?}: {} maps to {} below {}", operator, value, display, previous);
+
+                previous = display;
+            }
+        }
+    }
+}
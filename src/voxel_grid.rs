@@ -0,0 +1,160 @@
+// Density grids for heterogeneous media (smoke, clouds)
+//
+// A grid of nx x ny x nz values fills the cube [-0.5, 0.5]^3 in its own co-ordinates, each value at the centre of
+// its cell, and is placed in the world by a transform. Between the cell centres the density is interpolated
+// trilinearly, and outside the cube it is zero.
+
+use std::{fmt::Debug, fs, path::Path};
+
+use crate::{my_vec3::MyVec3, perlin::Perlin, common::RandomStream};
+
+pub struct VoxelGrid
+{
+    resolution: [usize; 3],
+    values:     Vec<f32>,       // x varies fastest, then y, then z
+    max_value:  f64
+}
+
+impl Debug for VoxelGrid
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "VoxelGrid {{ resolution: {:?}, max_value: {} }}", self.resolution, self.max_value)
+    }
+}
+
+impl VoxelGrid
+{
+    pub fn new(resolution: [usize; 3], values: Vec<f32>) -> Result<VoxelGrid, String>
+    {
+        if resolution.contains(&0)
+        {
+            return Err("The grid resolution must be at least 1 in each direction".to_string());
+        }
+
+        if values.len() != resolution[0] * resolution[1] * resolution[2]
+        {
+            return Err(format!("A {}x{}x{} grid needs {} values, not {}", resolution[0], resolution[1], resolution[2], resolution[0] * resolution[1] * resolution[2], values.len()));
+        }
+
+        if let Some(value) = values.iter().find(|v| !(v.is_finite() && **v >= 0.0))
+        {
+            return Err(format!("Grid values must be finite and not negative, not {}", value));
+        }
+
+        let max_value = values.iter().fold(0.0, |max, &v| f64::max(max, v as f64));
+
+        Ok(VoxelGrid { resolution, values, max_value })
+    }
+
+    // Raw file of little-endian 32-bit floats, x varying fastest, then y, then z, with no header
+    pub fn load_raw(path: &Path, resolution: [usize; 3]) -> Result<VoxelGrid, String>
+    {
+        let bytes = fs::read(path).map_err(|e| format!("{}: Unable to read grid file: {}", path.display(), e))?;
+
+        if bytes.len() % 4 != 0
+        {
+            return Err(format!("{}: The file is not a whole number of 32-bit floats", path.display()));
+        }
+
+        let values = bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+
+        VoxelGrid::new(resolution, values).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // A rounded puff of cloud, densest in the middle and thinning out to nothing before the faces of the cube, with its
+    // edge broken up by turbulence; frequency is the number of noise features across the cube
+    pub fn from_noise(resolution: [usize; 3], frequency: f64, rng: &mut RandomStream) -> Result<VoxelGrid, String>
+    {
+        let perlin = Perlin::new(rng);
+        let mut values = Vec::with_capacity(resolution[0] * resolution[1] * resolution[2]);
+
+        for k in 0..resolution[2]
+        {
+            for j in 0..resolution[1]
+            {
+                for i in 0..resolution[0]
+                {
+                    let p = MyVec3{x: (i as f64 + 0.5) / resolution[0] as f64 - 0.5,
+                                   y: (j as f64 + 0.5) / resolution[1] as f64 - 0.5,
+                                   z: (k as f64 + 0.5) / resolution[2] as f64 - 0.5};
+
+                    let falloff = 1.0 - 2.2 * p.length();
+                    let detail  = perlin.turbulence(frequency * p, 5);
+
+                    values.push((f64::clamp(2.5 * falloff - 2.0 * detail + 0.5, 0.0, 1.0) * f64::clamp(4.0 * falloff, 0.0, 1.0)) as f32);
+                }
+            }
+        }
+
+        VoxelGrid::new(resolution, values)
+    }
+
+    pub fn max_value(&self) -> f64
+    {
+        self.max_value
+    }
+
+    fn value(&self, i: usize, j: usize, k: usize) -> f64
+    {
+        self.values[(k * self.resolution[1] + j) * self.resolution[0] + i] as f64
+    }
+
+    // Trilinearly interpolated density at p in the grid's own co-ordinates
+    pub fn density_at(&self, p: MyVec3) -> f64
+    {
+        if !(-0.5..=0.5).contains(&p.x) || !(-0.5..=0.5).contains(&p.y) || !(-0.5..=0.5).contains(&p.z)
+        {
+            return 0.0;
+        }
+
+        // Cell index and fraction of the way to the next cell centre along one axis; beyond the outermost cell
+        // centres the density is that of the outermost cells
+        let axis = |x: f64, n: usize| -> (usize, usize, f64)
+        {
+            let g  = f64::clamp((x + 0.5) * n as f64 - 0.5, 0.0, (n - 1) as f64);
+            let i0 = usize::min(g as usize, n - 1);
+
+            (i0, usize::min(i0 + 1, n - 1), g - i0 as f64)
+        };
+
+        let (i0, i1, fx) = axis(p.x, self.resolution[0]);
+        let (j0, j1, fy) = axis(p.y, self.resolution[1]);
+        let (k0, k1, fz) = axis(p.z, self.resolution[2]);
+
+        let lerp = |a: f64, b: f64, t: f64| a + t * (b - a);
+
+        lerp(lerp(lerp(self.value(i0, j0, k0), self.value(i1, j0, k0), fx), lerp(self.value(i0, j1, k0), self.value(i1, j1, k0), fx), fy),
+             lerp(lerp(self.value(i0, j0, k1), self.value(i1, j0, k1), fx), lerp(self.value(i0, j1, k1), self.value(i1, j1, k1), fx), fy),
+             fz)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Trilinear interpolation reproduces a density which is linear in each co-ordinate between the cell centres
+    #[test]
+    fn interpolation_is_trilinear()
+    {
+        let f = |p: MyVec3| 4.0 + p.x + 2.0 * p.y + 3.0 * p.z;
+
+        let resolution = [4, 3, 5];
+        let values     = (0..resolution[2]).flat_map(|k| (0..resolution[1]).flat_map(move |j| (0..resolution[0]).map(move |i|
+                         {
+                             f(MyVec3{x: (i as f64 + 0.5) / 4.0 - 0.5, y: (j as f64 + 0.5) / 3.0 - 0.5, z: (k as f64 + 0.5) / 5.0 - 0.5}) as f32
+                         }))).collect();
+
+        let grid = VoxelGrid::new(resolution, values).unwrap();
+
+        for p in [MyVec3{x: 0.1, y: -0.2, z: 0.3}, MyVec3{x: -0.3, y: 0.15, z: -0.35}, MyVec3{x: 0.0, y: 0.0, z: 0.0}]
+        {
+            assert!((grid.density_at(p) - f(p)).abs() < 1e-5, "{:?}: {} != {}", p, grid.density_at(p), f(p));
+        }
+
+        assert_eq!(grid.density_at(MyVec3{x: 0.6, y: 0.0, z: 0.0}), 0.0);
+        assert!(VoxelGrid::new([2, 2, 2], vec![1.0; 7]).is_err());
+    }
+}
@@ -1,17 +1,50 @@
-use crate::{my_vec3::MyVec3, rayinfo::RayInfo, ray::Ray, material::Material, world_sphere::{WESphere, WEMovingSphere}};
+use crate::{my_vec3::MyVec3, rayinfo::RayInfo, ray::Ray, material::SharedMaterial, world_sphere::{WESphere, WEMovingSphere}, bounding_box::WEBoundingBox, bvh::WEBvh,
+            world_triangle::WETriangle, world_mesh::{WETriangleMesh, WEMeshTriangle}, light::{LightSample, SharedLight, SphereLight, TriangleLight},
+            world_instance::{WEInstance, WEAnimatedInstance, Transform}, animated_transform::AnimatedTransform};
+use std::sync::Arc;
 
 pub trait Intersect {
-    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo);
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo<'_>);
+
+    // Axis-aligned box enclosing the object at every time in the interval [time0, time1]
+    fn bounding_box(&self, time0: f64, time1: f64) -> WEBoundingBox;
 }
 
+#[derive(Default)]
 pub struct WorldElement {
-    pub objects: Vec<Box<dyn Intersect + Send + Sync>>
+    pub objects: Vec<Box<dyn Intersect + Send + Sync>>,
+
+    // Emissive objects which may be sampled directly; these are also present in objects
+    pub lights: Vec<SharedLight>,
+
+    // Acceleration structure over objects, must be rebuilt (build_bvh) after objects are added
+    bvh: Option<WEBvh>
 }
 
 impl WorldElement 
 {
-    pub fn intersect_all(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo)
+    pub fn new() -> WorldElement
+    {
+        WorldElement { objects: vec![], lights: vec![], bvh: None }
+    }
+
+    // Build the bounding volume hierarchy over all objects, valid for rays cast in the time interval [time0, time1]
+    pub fn build_bvh(&mut self, time0: f64, time1: f64)
     {
+        let bounds: Vec<WEBoundingBox> = self.objects.iter().map(|object| object.bounding_box(time0, time1)).collect();
+
+        self.bvh = Some(WEBvh::build(&bounds));
+    }
+
+    #[allow(clippy::needless_return)]
+    pub fn intersect_all(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo<'_>)
+    {
+        if let Some(bvh) = &self.bvh
+        {
+            return bvh.intersect(ray, min_scale, max_scale, |index, min_scale, max_scale| self.objects[index].intersect(ray, min_scale, max_scale, cast_time));
+        }
+
+        // No acceleration structure, test every object
         let mut ray_scale_closest = max_scale;
         let mut f_any_intersect   = false;
         let mut info              = RayInfo::default();
@@ -34,15 +67,206 @@ impl WorldElement
         return (f_any_intersect, info);
     }
 
-    pub fn add_sphere(&mut self, x: f64, y: f64, z:f64, r: f64, material: Material)
+    // Choose one of the lights at random (u0) and sample a direction towards it (u1, u2)
+    // The density of the sample includes the probability of choosing that light
+    pub fn sample_light(&self, origin: MyVec3, u0: f64, u1: f64, u2: f64) -> Option<LightSample>
+    {
+        if self.lights.is_empty()
+        {
+            return None;
+        }
+
+        let number_of_lights = self.lights.len();
+        let index            = usize::min((u0 * number_of_lights as f64) as usize, number_of_lights - 1);
+
+        let mut light_sample = self.lights[index].sample(origin, u1, u2)?;
+
+        light_sample.pdf /= number_of_lights as f64;
+
+        Some(light_sample)
+    }
+
+    // The density with which sample_light would choose the direction from origin to the intersect of ray_info, found on
+    // an emissive surface: that of the light which is the object hit (zero if none) times the chance of choosing it
+    pub fn light_pdf(&self, origin: MyVec3, ray_info: &RayInfo) -> f64
+    {
+        match ray_info.light
+        {
+            Some(light) => light.pdf(origin, ray_info.intersect) / self.lights.len() as f64,
+            None        => 0.0,
+        }
+    }
+
+    // Register a light to be sampled, returning it for the object which is the light to hold
+    fn add_light(&mut self, light: SharedLight) -> SharedLight
+    {
+        self.lights.push(Arc::clone(&light));
+
+        light
+    }
+
+    pub fn add_sphere(&mut self, x: f64, y: f64, z:f64, r: f64, material: SharedMaterial)
+    {
+        let light = material.emission().map(|radiance| self.add_light(Arc::new(SphereLight{c: MyVec3 {x, y, z}, r, radiance})));
+
+        self.add_object(Box::new(WESphere{c: MyVec3 {x, y, z}, r, material, light}));
+    }
+
+    // Same arguments as add_sphere, plus the motion
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_moving_sphere(&mut self, x: f64, y: f64, z:f64, r: f64, material: SharedMaterial, speed: f64, direction: MyVec3)
+    {
+        let moving_sphere = WEMovingSphere::new(WESphere{c: MyVec3 {x, y, z}, r, material, light: None}, speed, direction); 
+
+        self.add_object(Box::new(moving_sphere));
+    }
+
+    pub fn add_triangle(&mut self, p0: MyVec3, p1: MyVec3, p2: MyVec3, material: SharedMaterial)
+    {
+        let light = material.emission().map(|radiance| self.add_light(Arc::new(TriangleLight{p0, p1, p2, radiance})));
+
+        self.add_object(Box::new(WETriangle{p0, p1, p2, material, light}));
+    }
+
+    // Each triangle of the mesh is added as a separate object (sharing the mesh's vertex data) so that the
+    // bounding volume hierarchy can separate them
+    pub fn add_mesh(&mut self, mesh: WETriangleMesh)
     {
-        self.objects.push(Box::new(WESphere{c: MyVec3 {x, y, z}, r, material}));
+        let radiance = mesh.material.emission();
+        let mesh     = Arc::new(mesh);
+
+        for index in 0..mesh.number_of_triangles()
+        {
+            let [i0, i1, i2] = mesh.indices[index];
+
+            let light = radiance.map(|radiance| self.add_light(Arc::new(TriangleLight{p0: mesh.positions[i0], p1: mesh.positions[i1], p2: mesh.positions[i2], radiance})));
+
+            self.add_object(Box::new(WEMeshTriangle::new(Arc::clone(&mesh), index, light)));
+        }
     }
 
-    pub fn add_moving_sphere(&mut self, x: f64, y: f64, z:f64, r: f64, material: Material, speed: f64, direction: MyVec3)
+    // Place a shared object (e.g. a WorldElement with its own bounding volume hierarchy) in the world, optionally with
+    // a material replacing the object's own. Emissive surfaces of instances light the scene only when scattered rays
+    // hit them, they are not added to the lights
+    pub fn add_instance(&mut self, object: Arc<dyn Intersect + Send + Sync>, transform: Transform, material: Option<SharedMaterial>)
     {
-        let moving_sphere = WEMovingSphere::new(WESphere{c: MyVec3 {x, y, z}, r, material}, speed, direction); 
+        self.add_object(Box::new(WEInstance { object, transform, material }));
+    }
+
+    // The cube [-0.5, 0.5]^3 placed in the world by transform, as twelve triangles facing outwards (e.g. the boundary
+    // of a medium filling the box)
+    pub fn add_box(&mut self, transform: &Transform, material: SharedMaterial)
+    {
+        // Corner i is at +0.5 in x if bit 0 of i is set, y bit 1, z bit 2; faces anticlockwise seen from outside
+        const FACES: [[usize; 4]; 6] = [[0, 4, 6, 2], [1, 3, 7, 5], [0, 1, 5, 4], [2, 6, 7, 3], [0, 2, 3, 1], [4, 5, 7, 6]];
+
+        let corners = WEBoundingBox::new(MyVec3{x: -0.5, y: -0.5, z: -0.5}, MyVec3{x: 0.5, y: 0.5, z: 0.5}).corners()
+                                                                                                             .map(|p| transform.to_world.transform_point(p));
+
+        // A reflection turns the faces inside out
+        let to_world    = transform.to_world;
+        let determinant = to_world.transform_vector(MyVec3{x: 1.0, y: 0.0, z: 0.0}).cross(to_world.transform_vector(MyVec3{x: 0.0, y: 1.0, z: 0.0}))
+                                  .dot(to_world.transform_vector(MyVec3{x: 0.0, y: 0.0, z: 1.0}));
+
+        for face in FACES
+        {
+            let [a, b, c, d] = if determinant > 0.0 {face} else {[face[0], face[3], face[2], face[1]]}.map(|i| corners[i]);
+
+            self.add_triangle(a, b, c, material.clone());
+            self.add_triangle(a, c, d, material.clone());
+        }
+    }
+
+    // As add_instance, with a transform which changes during the exposure
+    pub fn add_animated_instance(&mut self, object: Arc<dyn Intersect + Send + Sync>, motion: AnimatedTransform, material: Option<SharedMaterial>)
+    {
+        self.add_object(Box::new(WEAnimatedInstance { object, motion, material }));
+    }
+
+    // Adding an object invalidates the bounding volume hierarchy
+    pub fn add_object(&mut self, object: Box<dyn Intersect + Send + Sync>)
+    {
+        self.objects.push(object);
+        self.bvh = None;
+    }
+}
+
+// A world element may itself be an object of another world, e.g. a group of objects placed many times by instances
+// (build_bvh must have been called, or every object is tested)
+impl Intersect for WorldElement {
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo<'_>)
+    {
+        self.intersect_all(ray, min_scale, max_scale, cast_time)
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64) -> WEBoundingBox
+    {
+        self.objects.iter().fold(WEBoundingBox::empty(), |bounds, object| bounds.union(&object.bounding_box(time0, time1)))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{material::{Diffuse, Emissive}, my_matrix4::MyMatrix4, common::RandomStream};
+    use rand::Rng;
+
+    // The light found on the object hit gives the same density as searching every light for the point, and objects
+    // which are not among the world's lights (including the lights of an instance) give zero
+    #[test]
+    fn light_pdf_from_object_hit()
+    {
+        let light   = || -> SharedMaterial { Arc::new(Emissive { colour: MyVec3 { x: 1.0, y: 1.0, z: 1.0 }, strength: 5.0 }) };
+        let diffuse = Arc::new(Diffuse { gain: MyVec3 { x: 0.5, y: 0.5, z: 0.5 }, texture: None });
+
+        let mut lamp = WorldElement::new();
+        lamp.add_sphere(0.0, 0.0, 0.0, 0.5, light());
+        lamp.build_bvh(0.0, 0.0);
+
+        let mut world_element = WorldElement::new();
+        world_element.add_sphere(-3.0, 0.0, 0.0, 1.0, light());
+        world_element.add_sphere(0.0, -1001.0, 0.0, 1000.0, diffuse);
+        world_element.add_triangle(MyVec3 { x: 2.0, y: 0.0, z: -1.0 }, MyVec3 { x: 4.0, y: 0.0, z: -1.0 }, MyVec3 { x: 3.0, y: 2.0, z: -1.0 }, light());
+        world_element.add_mesh(WETriangleMesh::new(vec![MyVec3 { x: -1.0, y: 2.0, z: -2.0 }, MyVec3 { x: 1.0, y: 2.0, z: -2.0 }, MyVec3 { x: 1.0, y: 3.0, z: -2.0 }, MyVec3 { x: -1.0, y: 3.0, z: -2.0 }],
+                                                   None, None, vec![[0, 1, 2], [0, 2, 3]], light()).unwrap());
+        world_element.add_instance(Arc::new(lamp), Transform::new(MyMatrix4::translation(MyVec3 { x: 0.0, y: 0.5, z: 2.0 })).unwrap(), None);
+        world_element.build_bvh(0.0, 0.0);
+
+        assert_eq!(world_element.lights.len(), 4);
+
+        let origin       = MyVec3 { x: 0.0, y: 0.5, z: 5.0 };
+        let mut rng      = RandomStream::new(0, 0, 0);
+        let mut lit      = 0;
+        let mut instance = 0;
+
+        for _ in 0..2000
+        {
+            let direction = MyVec3 { x: rng.gen_range(-1.0..1.0), y: rng.gen_range(-0.5..0.5), z: -1.0 };
+
+            let (hit, ray_info) = world_element.intersect_all(&Ray { p: origin, direction, cast_time: 0.0 }, 0.001, f64::INFINITY, 0.0);
+
+            if !hit
+            {
+                continue;
+            }
+
+            let searched = world_element.lights.iter().map(|light| light.pdf(origin, ray_info.intersect)).sum::<f64>() / 4.0;
+            let pdf      = world_element.light_pdf(origin, &ray_info);
+
+            if ray_info.material.emission().is_some() && searched == 0.0
+            {
+                instance += 1;
+            }
+
+            if searched > 0.0
+            {
+                lit += 1;
+            }
+
+            assert!((pdf - searched).abs() <= 1e-9 * searched, "{:?}: {} not {}", ray_info.intersect, pdf, searched);
+        }
 
-        self.objects.push(Box::new(moving_sphere));
+        assert!(lit > 50 && instance > 50, "{} rays hit the world's lights and {} the instance", lit, instance);
     }
 }
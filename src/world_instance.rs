@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use crate::{my_vec3::{MyVec3, vec3_normalize}, my_matrix4::MyMatrix4, rayinfo::RayInfo, ray::Ray, world_element::Intersect, material::SharedMaterial,
+            bounding_box::WEBoundingBox, animated_transform::AnimatedTransform};
+
+// Affine transform from an object's own co-ordinates to the world, with the matrices needed to go back the other way
+// computed once rather than for every ray
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    pub to_world:  MyMatrix4,
+    pub to_object: MyMatrix4,   // Inverse of to_world
+    normal_matrix: MyMatrix4    // Transpose of to_object; normals are not transformed like directions unless the scaling is uniform
+}
+
+impl Transform {
+    pub fn new(to_world: MyMatrix4) -> Result<Transform, String>
+    {
+        if !to_world.is_affine()
+        {
+            return Err("The bottom row of a transform matrix must be [0, 0, 0, 1]".to_string());
+        }
+
+        let to_object = to_world.inverse().ok_or_else(|| "The transform cannot be inverted (is a scale zero?)".to_string())?;
+
+        Ok(Transform::from_matrices(to_world, to_object))
+    }
+
+    // to_object must be the inverse of to_world
+    pub fn from_matrices(to_world: MyMatrix4, to_object: MyMatrix4) -> Transform
+    {
+        Transform { to_world, to_object, normal_matrix: to_object.transpose() }
+    }
+
+    pub fn normal_to_world(&self, n: MyVec3) -> MyVec3
+    {
+        vec3_normalize(self.normal_matrix.transform_vector(n))
+    }
+}
+
+// An object placed in the world by a transform. The object is shared, so one object (e.g. a mesh with its own
+// bounding volume hierarchy) can be placed any number of times while being stored once, each instance optionally
+// with its own material in place of the object's materials
+//
+// Rays are transformed into the object's co-ordinates instead of transforming the object. The direction is not
+// normalised afterwards, so a distance along the ray in object space is the same distance along the world ray
+pub struct WEInstance {
+    pub object:    Arc<dyn Intersect + Send + Sync>,
+    pub transform: Transform,
+    pub material:  Option<SharedMaterial>
+}
+
+impl Intersect for WEInstance {
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo<'_>)
+    {
+        intersect_transformed(self.object.as_ref(), &self.transform, self.material.as_ref(), ray, min_scale, max_scale, cast_time)
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64) -> WEBoundingBox
+    {
+        transform_bounds(&self.transform, self.object.bounding_box(time0, time1))
+    }
+}
+
+// An instance whose transform changes during the exposure (motion blur); the transform is found for the cast time of
+// each ray
+pub struct WEAnimatedInstance {
+    pub object:    Arc<dyn Intersect + Send + Sync>,
+    pub motion:    AnimatedTransform,
+    pub material:  Option<SharedMaterial>
+}
+
+impl Intersect for WEAnimatedInstance {
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo<'_>)
+    {
+        intersect_transformed(self.object.as_ref(), &self.motion.at(cast_time), self.material.as_ref(), ray, min_scale, max_scale, cast_time)
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64) -> WEBoundingBox
+    {
+        self.motion.swept_bounds(self.object.bounding_box(time0, time1), time0, time1)
+    }
+}
+
+fn intersect_transformed<'a>(object: &'a (dyn Intersect + Send + Sync), transform: &Transform, material: Option<&'a SharedMaterial>, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo<'a>)
+{
+    let object_ray = Ray { p: transform.to_object.transform_point(ray.p), direction: transform.to_object.transform_vector(ray.direction), cast_time: ray.cast_time };
+
+    let (f_intersect, object_info) = object.intersect(&object_ray, min_scale, max_scale, cast_time);
+
+    if !f_intersect
+    {
+        return (false, RayInfo::default());
+    }
+
+    let info = RayInfo { intersect: ray.at(object_info.ds),
+                         normal:    transform.normal_to_world(object_info.normal),
+                         material:  material.map_or(object_info.material, |material| material.as_ref()),
+                         light:     None,       // The object's lights (if any) are not among the world's lights
+                         ..object_info };
+
+    (true, info)
+}
+
+// Box around the eight corners of the object's box, transformed
+fn transform_bounds(transform: &Transform, object_box: WEBoundingBox) -> WEBoundingBox
+{
+    if object_box.is_empty()
+    {
+        return object_box;
+    }
+
+    object_box.corners().iter().fold(WEBoundingBox::empty(), |bounds, &p| bounds.union_point(transform.to_world.transform_point(p)))
+}
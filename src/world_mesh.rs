@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use crate::{my_vec3::{MyVec3, vec3_normalize}, rayinfo::RayInfo, ray::Ray, world_element::Intersect, material::SharedMaterial, bounding_box::WEBoundingBox,
+            world_triangle::{moller_trumbore, triangle_ray_info}, light::{Light, SharedLight}};
+
+// Indexed triangle mesh
+// Vertex data is stored once and shared between all the triangles which use it; each triangle is three indices
+// into the vertex arrays. Normals and texture co-ordinates are optional but, where present, there must be one
+// per position (i.e. they use the same index as the position)
+pub struct WETriangleMesh {
+    pub positions: Vec<MyVec3>,
+    pub normals:   Option<Vec<MyVec3>>,
+    pub uvs:       Option<Vec<(f64, f64)>>,
+    pub indices:   Vec<[usize; 3]>,
+    pub material:  SharedMaterial
+}
+
+impl WETriangleMesh {
+    pub fn new(positions: Vec<MyVec3>,
+               normals: Option<Vec<MyVec3>>,
+               uvs: Option<Vec<(f64, f64)>>,
+               indices: Vec<[usize; 3]>,
+               material: SharedMaterial)
+               -> Result<WETriangleMesh, String>
+    {
+        if let Some(normals) = &normals
+        {
+            if normals.len() != positions.len()
+            {
+                return Err(format!("WETriangleMesh fn new: {} normals given for {} positions. There must be one normal per position", normals.len(), positions.len()));
+            }
+        }
+
+        if let Some(uvs) = &uvs
+        {
+            if uvs.len() != positions.len()
+            {
+                return Err(format!("WETriangleMesh fn new: {} texture co-ordinates given for {} positions. There must be one texture co-ordinate per position", uvs.len(), positions.len()));
+            }
+        }
+
+        if let Some(index) = indices.iter().flatten().find(|&&index| index >= positions.len())
+        {
+            return Err(format!("WETriangleMesh fn new: Vertex index {} is out of range (mesh has {} positions)", index, positions.len()));
+        }
+
+        Ok(WETriangleMesh { positions, normals, uvs, indices, material })
+    }
+
+    pub fn number_of_triangles(&self) -> usize
+    {
+        self.indices.len()
+    }
+}
+
+// One triangle of a mesh. Each triangle is a separate object so that the bounding volume hierarchy is built over
+// the individual triangles rather than over the mesh as a whole
+pub struct WEMeshTriangle {
+    mesh:  Arc<WETriangleMesh>,
+    index: usize,
+    light: Option<SharedLight>      // Where the mesh is emissive and the triangle one of the world's lights
+}
+
+impl WEMeshTriangle {
+    pub fn new(mesh: Arc<WETriangleMesh>, index: usize, light: Option<SharedLight>) -> WEMeshTriangle
+    {
+        WEMeshTriangle { mesh, index, light }
+    }
+
+    fn vertices(&self) -> (MyVec3, MyVec3, MyVec3)
+    {
+        let [i0, i1, i2] = self.mesh.indices[self.index];
+
+        (self.mesh.positions[i0], self.mesh.positions[i1], self.mesh.positions[i2])
+    }
+}
+
+impl Intersect for WEMeshTriangle {
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, _cast_time: f64) -> (bool, RayInfo<'_>)
+    {
+        let (p0, p1, p2) = self.vertices();
+
+        match moller_trumbore(ray, p0, p1, p2, min_scale, max_scale)
+        {
+            None => (false, RayInfo::default()),
+
+            Some((ds, b1, b2)) =>
+            {
+                let [i0, i1, i2]     = self.mesh.indices[self.index];
+                let b0               = 1.0 - b1 - b2;
+                let geometric_normal = vec3_normalize((p1 - p0).cross(p2 - p0));
+
+                // Smooth shading: interpolate vertex normals across the face using the barycentric co-ordinates
+                let shading_normal = match &self.mesh.normals
+                {
+                    Some(normals) =>
+                    {
+                        let n = b0 * normals[i0] + b1 * normals[i1] + b2 * normals[i2];
+
+                        if n.squared_length() > 1e-12 {vec3_normalize(n)} else {geometric_normal}
+                    }
+                    None => geometric_normal,
+                };
+
+                let (u, v) = match &self.mesh.uvs
+                {
+                    Some(uvs) => (b0 * uvs[i0].0 + b1 * uvs[i1].0 + b2 * uvs[i2].0, b0 * uvs[i0].1 + b1 * uvs[i1].1 + b2 * uvs[i2].1),
+                    None      => (b1, b2),
+                };
+
+                let ray_info = triangle_ray_info(ray, ds, geometric_normal, shading_normal, u, v, self.mesh.material.as_ref());
+
+                (true, RayInfo { light: self.light.as_deref().map(|light| light as &dyn Light), ..ray_info })
+            }
+        }
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> WEBoundingBox
+    {
+        let (p0, p1, p2) = self.vertices();
+
+        WEBoundingBox::new(p0, p1).union_point(p2)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::material::YELLOW_TINT;
+
+    // One triangle with a different normal and texture co-ordinate at each corner, hit at known barycentric
+    // co-ordinates from either side
+    #[test]
+    fn interpolates_normals_and_uvs()
+    {
+        let positions = vec![MyVec3 { x: 0.0, y: 0.0, z: 0.0 }, MyVec3 { x: 1.0, y: 0.0, z: 0.0 }, MyVec3 { x: 0.0, y: 1.0, z: 0.0 }];
+        let normals   = vec![MyVec3 { x: 0.0, y: 0.0, z: 1.0 }, MyVec3 { x: 1.0, y: 0.0, z: 1.0 }, MyVec3 { x: 0.0, y: 1.0, z: 1.0 }];
+        let uvs       = vec![(0.2, 0.1), (0.8, 0.3), (0.4, 0.9)];
+
+        let mesh     = WETriangleMesh::new(positions, Some(normals.clone()), Some(uvs.clone()), vec![[0, 1, 2]], Arc::new(YELLOW_TINT)).unwrap();
+        let triangle = WEMeshTriangle::new(Arc::new(mesh), 0, None);
+
+        let (b0, b1, b2) = (0.25, 0.25, 0.5);
+
+        let expected_normal = vec3_normalize(b0 * normals[0] + b1 * normals[1] + b2 * normals[2]);
+        let expected_u      = b0 * uvs[0].0 + b1 * uvs[1].0 + b2 * uvs[2].0;
+        let expected_v      = b0 * uvs[0].1 + b1 * uvs[1].1 + b2 * uvs[2].1;
+
+        for side in [1.0, -1.0]
+        {
+            let ray = Ray { p: MyVec3 { x: b1, y: b2, z: 2.0 * side }, direction: MyVec3 { x: 0.0, y: 0.0, z: -side }, cast_time: 0.0 };
+
+            let (hit, ray_info) = triangle.intersect(&ray, 0.001, f64::INFINITY, 0.0);
+
+            assert!(hit);
+            assert_eq!(ray_info.is_front, side > 0.0);
+            assert!((ray_info.ds - 2.0).abs() < 1e-12);
+            assert!((ray_info.intersect - MyVec3 { x: b1, y: b2, z: 0.0 }).length() < 1e-12);
+
+            // The normal faces the ray
+            assert!((ray_info.normal - side * expected_normal).length() < 1e-12, "{:?} not {:?}", ray_info.normal, side * expected_normal);
+            assert!((ray_info.u - expected_u).abs() < 1e-12 && (ray_info.v - expected_v).abs() < 1e-12, "({}, {}) not ({}, {})", ray_info.u, ray_info.v, expected_u, expected_v);
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use crate::{my_vec3::{MyVec3, vec3_normalize}, rayinfo::RayInfo, ray::Ray, world_element::{Intersect}, material::Material};
+use crate::{my_vec3::{MyVec3, vec3_normalize}, rayinfo::RayInfo, ray::Ray, world_element::{Intersect}, material::{Material, SharedMaterial}, bounding_box::WEBoundingBox,
+            light::{Light, SharedLight}};
 
 // This sphere can only move in a straight line, and does not stop
 // Elements not declared pub in order to force the use of new to instantiate (thereby normalizing direction at the time of creation)
@@ -13,18 +14,27 @@ impl WEMovingSphere {
     {
         WEMovingSphere { sphere_zero, speed, direction: vec3_normalize(direction) }
     }
+
+    fn centre_at(&self, time: f64) -> MyVec3
+    {
+        self.sphere_zero.c + (time * self.speed) * self.direction
+    }
 }
 
 impl Intersect for WEMovingSphere {
-    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo)
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, cast_time: f64) -> (bool, RayInfo<'_>)
     {
         // Determine the position of the sphere at the cast time of the ray, and intersect
-        let c = self.sphere_zero.c + (cast_time * self.speed) * self.direction;
+        let c = self.centre_at(cast_time);
         let r = self.sphere_zero.r;
 
-        let sphere_t = WESphere{ c, r, material: self.sphere_zero.material };
+        intersect_sphere(c, r, self.sphere_zero.material.as_ref(), ray, min_scale, max_scale)
+    }
 
-        return sphere_t.intersect(ray, min_scale, max_scale, cast_time);
+    // The sphere sweeps along a straight line, so its bounds over the interval are those of the spheres at either end
+    fn bounding_box(&self, time0: f64, time1: f64) -> WEBoundingBox
+    {
+        sphere_bounding_box(self.centre_at(time0), self.sphere_zero.r).union(&sphere_bounding_box(self.centre_at(time1), self.sphere_zero.r))
     }
 }
 
@@ -32,48 +42,124 @@ impl Intersect for WEMovingSphere {
 pub struct WESphere {
     pub c:        MyVec3,
     pub r:        f64,
-    pub material: Material
+    pub material: SharedMaterial,
+    pub light:    Option<SharedLight>     // Where the sphere is emissive and one of the world's lights
 }
 
 impl Intersect for WESphere {
-    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, _cast_time: f64) -> (bool, RayInfo)
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, _cast_time: f64) -> (bool, RayInfo<'_>)
     {
-        let sqrt = f64::sqrt; 
+        let (f_intersect, ray_info) = intersect_sphere(self.c, self.r, self.material.as_ref(), ray, min_scale, max_scale);
 
-        let a = ray.direction.squared_length();
-        let h = ray.direction.dot(ray.p - self.c);   	// b = 2h
-        let c = (ray.p - self.c).squared_length() - 1.0 * self.r * self.r;
+        (f_intersect, RayInfo { light: self.light.as_deref().map(|light| light as &dyn Light), ..ray_info })
+    }
 
-        let s = h * h - a * c;
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> WEBoundingBox
+    {
+        sphere_bounding_box(self.c, self.r)
+    }
+}
 
-        if s >= 0.0
-        {
-            let mut ds = (-h - sqrt(s)) / a;
+// Shared by static and moving spheres (a moving sphere is a static sphere at the cast time of the ray)
+fn intersect_sphere<'a>(centre: MyVec3, radius: f64, material: &'a dyn Material, ray: &Ray, min_scale: f64, max_scale: f64) -> (bool, RayInfo<'a>)
+{
+    let sqrt = f64::sqrt; 
 
+    let a = ray.direction.squared_length();
+    let h = ray.direction.dot(ray.p - centre);   	// b = 2h
+    let c = (ray.p - centre).squared_length() - 1.0 * radius * radius;
+
+    let s = h * h - a * c;
+
+    if s >= 0.0
+    {
+        let mut ds = (-h - sqrt(s)) / a;
+
+        if ds < min_scale || ds > max_scale
+        {
+            ds = (-h + sqrt(s)) / a;
             if ds < min_scale || ds > max_scale
             {
-                ds = (-h + sqrt(s)) / a;
-                if ds < min_scale || ds > max_scale
-                {
-                    return (false, RayInfo::default());
-                }
+                return (false, RayInfo::default());
             }
+        }
 
-            let intersect = ray.at(ds);
+        let intersect = ray.at(ds);
 
-            let mut normal = (intersect - self.c) / self.r;		// This vector is already normalized to length = 1, so no explicit normalization step is necessary
-            //let mut normal = intersect - self.c;
-            //normal.normalize();
+        let mut normal = (intersect - centre) / radius;		// This vector is already normalized to length = 1, so no explicit normalization step is necessary
+        //let mut normal = intersect - centre;
+        //normal.normalize();
 
-            let is_front  = normal.dot(ray.direction) < 0.0;
-            normal        = if is_front {normal} else {-1.0 * normal};
+        // Surface co-ordinates from the outward normal: u is the angle around the y axis (from -x), v the angle up from -y
+        let (u, v)    = sphere_uv(normal);
 
-            let ray_info  = RayInfo{intersect, normal, ds, is_front, material: self.material};
+        let is_front  = normal.dot(ray.direction) < 0.0;
+        normal        = if is_front {normal} else {-1.0 * normal};
 
-            return (true, ray_info);
-        }
+        let ray_info  = RayInfo{intersect, normal, ds, is_front, u, v, material, relative_index: None, light: None};
+
+        return (true, ray_info);
+    }
+
+    (false, RayInfo::default())
+}
+
+fn sphere_bounding_box(centre: MyVec3, radius: f64) -> WEBoundingBox
+{
+    let r = MyVec3 { x: f64::abs(radius), y: f64::abs(radius), z: f64::abs(radius) };
+
+    WEBoundingBox::new(centre - r, centre + r)
+}
+
+fn sphere_uv(outward_normal: MyVec3) -> (f64, f64)
+{
+    use std::f64::consts::PI;
+
+    let theta = f64::acos(f64::clamp(-outward_normal.y, -1.0, 1.0));
+    let phi   = f64::atan2(-outward_normal.z, outward_normal.x) + PI;
+
+    (phi / (2.0 * PI), theta / PI)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn assert_uv(normal: MyVec3, u: f64, v: f64)
+    {
+        let (actual_u, actual_v) = sphere_uv(normal);
+
+        assert!((actual_u - u).abs() < 1e-9 && (actual_v - v).abs() < 1e-9, "{:?}: ({}, {}) not ({}, {})", normal, actual_u, actual_v, u, v);
+    }
+
+    // v runs from the south pole (-y) to the north pole (+y), u once around the equator starting from -x
+    #[test]
+    fn uv_poles_and_equator()
+    {
+        assert_uv(MyVec3 { x: 0.0, y: -1.0, z: 0.0 }, 0.5, 0.0);
+        assert_uv(MyVec3 { x: 0.0, y: 1.0, z: 0.0 }, 0.5, 1.0);
 
-        return(false, RayInfo::default());
+        assert_uv(MyVec3 { x: 1.0, y: 0.0, z: 0.0 }, 0.5, 0.5);
+        assert_uv(MyVec3 { x: 0.0, y: 0.0, z: 1.0 }, 0.25, 0.5);
+        assert_uv(MyVec3 { x: 0.0, y: 0.0, z: -1.0 }, 0.75, 0.5);
+
+        // Normals a little outside the unit sphere (rounding) still give v within [0, 1]
+        let (_, v) = sphere_uv(MyVec3 { x: 0.0, y: 1.0 + 1e-12, z: 0.0 });
+
+        assert_eq!(v, 1.0);
     }
 
+    // Either side of the seam at -x, u is close to 0 and 1 respectively and never outside [0, 1]
+    #[test]
+    fn uv_seam()
+    {
+        let (below, _) = sphere_uv(vec3_normalize(MyVec3 { x: -1.0, y: 0.0, z: -1e-9 }));
+        let (above, _) = sphere_uv(vec3_normalize(MyVec3 { x: -1.0, y: 0.0, z: 1e-9 }));
+        let (on, _)    = sphere_uv(MyVec3 { x: -1.0, y: 0.0, z: 0.0 });
+
+        assert!(below > 1.0 - 1e-6 && below <= 1.0, "{}", below);
+        assert!((0.0..1e-6).contains(&above), "{}", above);
+        assert!(on == 0.0 || on == 1.0, "{}", on);
+    }
 }
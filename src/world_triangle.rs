@@ -0,0 +1,124 @@
+use crate::{my_vec3::{MyVec3, vec3_normalize}, rayinfo::RayInfo, ray::Ray, world_element::Intersect, material::{Material, SharedMaterial}, bounding_box::WEBoundingBox,
+            light::{Light, SharedLight}};
+
+// Single triangle with its own vertices; the normal is that of the plane of the triangle
+// For triangles which share vertices (and optionally normals and texture co-ordinates) use WETriangleMesh
+pub struct WETriangle {
+    pub p0:       MyVec3,
+    pub p1:       MyVec3,
+    pub p2:       MyVec3,
+    pub material: SharedMaterial,
+    pub light:    Option<SharedLight>     // Where the triangle is emissive and one of the world's lights
+}
+
+impl Intersect for WETriangle {
+    fn intersect(&self, ray: &Ray, min_scale: f64, max_scale: f64, _cast_time: f64) -> (bool, RayInfo<'_>)
+    {
+        match moller_trumbore(ray, self.p0, self.p1, self.p2, min_scale, max_scale)
+        {
+            None => (false, RayInfo::default()),
+
+            Some((ds, b1, b2)) =>
+            {
+                let geometric_normal = vec3_normalize((self.p1 - self.p0).cross(self.p2 - self.p0));
+
+                // Barycentric co-ordinates are used as the surface co-ordinates
+                let ray_info = triangle_ray_info(ray, ds, geometric_normal, geometric_normal, b1, b2, self.material.as_ref());
+
+                (true, RayInfo { light: self.light.as_deref().map(|light| light as &dyn Light), ..ray_info })
+            }
+        }
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> WEBoundingBox
+    {
+        WEBoundingBox::new(self.p0, self.p1).union_point(self.p2)
+    }
+}
+
+// Möller–Trumbore ray/triangle intersection
+// The intersect is written in barycentric co-ordinates as p0 + b1 * (p1 - p0) + b2 * (p2 - p0), and equated with
+// the ray p + ds * direction; the resulting 3x3 linear system is solved for (ds, b1, b2) by Cramer's rule
+// Returns (ds, b1, b2) if the ray hits the triangle within [min_scale, max_scale]
+pub fn moller_trumbore(ray: &Ray, p0: MyVec3, p1: MyVec3, p2: MyVec3, min_scale: f64, max_scale: f64) -> Option<(f64, f64, f64)>
+{
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+
+    let p_vec = ray.direction.cross(edge2);
+    let det   = edge1.dot(p_vec);
+
+    // Ray parallel to the plane of the triangle (or degenerate triangle); det scales with the lengths of the edges and
+    // of the direction, so the cutoff does too, for the same behaviour with tiny and huge models
+    if f64::abs(det) < 1e-12 * edge1.length() * edge2.length() * ray.direction.length()
+    {
+        return None;
+    }
+
+    let recip_det = 1.0 / det;
+
+    let t_vec = ray.p - p0;
+    let b1    = t_vec.dot(p_vec) * recip_det;
+
+    if !(0.0..=1.0).contains(&b1)
+    {
+        return None;
+    }
+
+    let q_vec = t_vec.cross(edge1);
+    let b2    = ray.direction.dot(q_vec) * recip_det;
+
+    if b2 < 0.0 || b1 + b2 > 1.0
+    {
+        return None;
+    }
+
+    let ds = edge2.dot(q_vec) * recip_det;
+
+    if ds < min_scale || ds > max_scale
+    {
+        return None;
+    }
+
+    Some((ds, b1, b2))
+}
+
+// Common to all triangles; the geometric normal decides which side of the surface the ray hit, the shading normal
+// (which may be interpolated from vertex normals) is returned on that same side
+pub fn triangle_ray_info<'a>(ray: &Ray, ds: f64, geometric_normal: MyVec3, shading_normal: MyVec3, u: f64, v: f64, material: &'a dyn Material) -> RayInfo<'a>
+{
+    let intersect = ray.at(ds);
+    let is_front  = geometric_normal.dot(ray.direction) < 0.0;
+
+    let oriented_geometric = if is_front {geometric_normal} else {-1.0 * geometric_normal};
+
+    // Interpolated normals are not guaranteed to lie on the same side of the surface as the geometric normal
+    let normal = if shading_normal.dot(oriented_geometric) >= 0.0 {shading_normal} else {-1.0 * shading_normal};
+
+    RayInfo { intersect, normal, ds, is_front, u, v, material, relative_index: None, light: None }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // The same triangle, viewed the same way, is hit whatever the scale of the model
+    #[test]
+    fn hit_at_any_scale()
+    {
+        for scale in [1e-7, 1.0, 1e7]
+        {
+            let p0 = scale * MyVec3 { x: -1.0, y: -1.0, z: 0.0 };
+            let p1 = scale * MyVec3 { x: 1.0, y: -1.0, z: 0.0 };
+            let p2 = scale * MyVec3 { x: 0.0, y: 1.0, z: 0.0 };
+
+            let ray = Ray { p: scale * MyVec3 { x: 0.1, y: 0.0, z: 2.0 }, direction: scale * MyVec3 { x: 0.0, y: 0.0, z: -1.0 }, cast_time: 0.0 };
+
+            let (ds, b1, b2) = moller_trumbore(&ray, p0, p1, p2, 0.0, f64::INFINITY).expect("missed the triangle");
+
+            assert!((ds - 2.0).abs() < 1e-9, "scale {}: ds {}", scale, ds);
+            assert!((b1 - 0.3).abs() < 1e-9 && (b2 - 0.5).abs() < 1e-9, "scale {}: {} {}", scale, b1, b2);
+        }
+    }
+}